@@ -18,8 +18,7 @@ async function main() {
 
     let obridge = new Program(idl as Idl, obridgeProgramId);
 
-    const feeRecepientSecret = JSON.parse(process.env.FEE_RECEPIENT_PRIVATE_KEY) as number[];
-    const feeRecepient = Keypair.fromSecretKey(Uint8Array.from(feeRecepientSecret));
+    const feeRecepient = new PublicKey(process.env.FEE_RECEPIENT);
 
     const adminSecret = JSON.parse(process.env.ADMIN_PRIVATE_KEY) as number[];
     const admin = Keypair.fromSecretKey(Uint8Array.from(adminSecret));
@@ -28,10 +27,10 @@ async function main() {
 
     let tx;
 
-    // set admin
+    // set admin and cap fee rate at 10%
     let [adminSettingsPubKey] = PublicKey.findProgramAddressSync([Buffer.from("settings")], obridge.programId);
     tx = await obridge.methods
-        .initialize(admin.publicKey)
+        .initialize(admin.publicKey, 1000)
         .accounts({
             payer: payer.publicKey,
             adminSettings: adminSettingsPubKey,
@@ -45,11 +44,11 @@ async function main() {
     tx = await obridge.methods
         .setFeeRecepient()
         .accounts({
-            admin: admin.publicKey,
-            feeRecepient: feeRecepient.publicKey,
+            authority: admin.publicKey,
+            feeRecepient: feeRecepient,
             adminSettings: adminSettingsPubKey,
         })
-        .signers([admin, feeRecepient])
+        .signers([admin])
         .rpc();
 
     console.log(`successfully set fee recepient tx: ${tx}`);
@@ -58,7 +57,7 @@ async function main() {
     tx = await obridge.methods
         .setFeeRate(1000)
         .accounts({
-            admin: admin.publicKey,
+            authority: admin.publicKey,
             adminSettings: adminSettingsPubKey,
         })
         .signers([admin])
@@ -1,47 +1,98 @@
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{self, CloseAccount, Mint, Token, TokenAccount};
-use solana_program::keccak;
-use std::mem::size_of;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee, transfer_hook, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token_interface::{self, CloseAccount, Mint, TokenAccount, TokenInterface};
+use solana_program::{hash, keccak};
+use std::ops::{Deref, DerefMut};
 
 declare_id!("FAqaHQHgBFFX8fJB6fQUqNdc8zABV5pGVRdCt7fLLYVo");
 
 const ADMIN_SETTINGS_SEED: &[u8] = b"settings";
 const TOKEN_SETTINGS_SEED_PREFIX: &[u8] = b"token";
+const FEE_DISCOUNT_SEED_PREFIX: &[u8] = b"discount";
+const PREIMAGE_RECORD_SEED_PREFIX: &[u8] = b"preimage";
+const PENDING_CHANGE_SEED_PREFIX: &[u8] = b"pending";
+const REFERRAL_VAULT_SEED_PREFIX: &[u8] = b"referral";
+const DEFAULT_PREIMAGE_RETENTION_PERIOD: i64 = 7 * 24 * 60 * 60;
+const MAX_PREIMAGE_LEN: usize = 64;
+const MAX_FEE_TIERS: usize = 8;
+const MAX_ADMIN_SIGNERS: usize = 10;
+/// Written to the `version` of config and escrow accounts, older accounts are upgraded by the
+/// `migrate_*` instructions. New fields take their space from the zeroed `reserved` tail.
+const ACCOUNT_VERSION: u8 = 1;
 
 mod helpers {
     use super::*;
     use anchor_lang::system_program;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn handle_token_transfer<'info>(
-        token_program: &Program<'info, Token>,
+        token_program: &Interface<'info, TokenInterface>,
         from: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
         to: &AccountInfo<'info>,
         authority: &AccountInfo<'info>,
         amount: u64,
+        hook_accounts: &[AccountInfo<'info>],
         seeds: Option<&[&[&[u8]]]>,
     ) -> Result<()> {
-        let transfer_ctx = CpiContext::new(
-            token_program.to_account_info(),
-            token::Transfer {
-                from: from.to_account_info(),
-                to: to.to_account_info(),
-                authority: authority.to_account_info(),
-            },
-        );
+        let mut ix = spl_token_2022::instruction::transfer_checked(
+            token_program.key,
+            from.key,
+            &mint.key(),
+            to.key,
+            authority.key,
+            &[],
+            amount,
+            mint.decimals,
+        )?;
+        let mut account_infos = vec![
+            from.to_account_info(),
+            mint.to_account_info(),
+            to.to_account_info(),
+            authority.to_account_info(),
+        ];
+
+        // Token-2022 picks the transfer hook's extra accounts out of whatever follows the transfer
+        // accounts, transfers of mints without a hook get none of them
+        if has_transfer_hook(mint)? {
+            for account in hook_accounts {
+                ix.accounts.push(AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: false,
+                    is_writable: account.is_writable,
+                });
+                account_infos.push(account.to_account_info());
+            }
+        }
 
-        if let Some(seeds) = seeds {
-            token::transfer(
-                CpiContext::new_with_signer(
-                    token_program.to_account_info(),
-                    transfer_ctx.accounts,
-                    seeds,
-                ),
-                amount,
-            )
-        } else {
-            token::transfer(transfer_ctx, amount)
+        solana_program::program::invoke_signed(&ix, &account_infos, seeds.unwrap_or_default())?;
+        Ok(())
+    }
+
+    pub fn has_transfer_hook(mint: &InterfaceAccount<Mint>) -> Result<bool> {
+        let mint = mint.to_account_info();
+        if *mint.owner != spl_token_2022::ID {
+            return Ok(false);
+        }
+        let data = mint.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        Ok(transfer_hook::get_program_id(&state).is_some())
+    }
+
+    pub fn has_transfer_fee(mint: &InterfaceAccount<Mint>) -> Result<bool> {
+        let mint = mint.to_account_info();
+        if *mint.owner != spl_token_2022::ID {
+            return Ok(false);
         }
+        let data = mint.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        Ok(state
+            .get_extension::<transfer_fee::TransferFeeConfig>()
+            .is_ok())
     }
 
     pub fn handle_sol_transfer<'info>(
@@ -62,49 +113,140 @@ mod helpers {
         )
     }
 
+    /// Reallocs `account` to the current layout of `T` and writes `value`, `payer` covers the extra rent.
+    pub fn write_migrated_account<'info, T: AccountSerialize + Space>(
+        account: &AccountInfo<'info>,
+        payer: &AccountInfo<'info>,
+        system_program: &AccountInfo<'info>,
+        value: &T,
+    ) -> Result<()> {
+        let new_len = 8 + T::INIT_SPACE;
+        let rent = Rent::get()?;
+        let extra_rent = rent
+            .minimum_balance(new_len)
+            .saturating_sub(rent.minimum_balance(account.data_len()));
+        if extra_rent > 0 {
+            handle_sol_transfer(payer, account, system_program, extra_rent)?;
+        }
+        account.realloc(new_len, true)?;
+        value.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])
+    }
+
     pub fn verify_token_settings(
-        token: Option<&Account<Mint>>,
-        token_settings: Option<&Account<TokenSettings>>,
+        token: Option<&InterfaceAccount<Mint>>,
+        token_settings: &Account<TokenSettings>,
         program_id: &Pubkey,
     ) -> Result<()> {
-        if let Some(settings) = token_settings {
-            let (expected_settings, _) = if let Some(token) = token {
-                Pubkey::find_program_address(
-                    &[
-                        TOKEN_SETTINGS_SEED_PREFIX,
-                        &token.to_account_info().key().to_bytes(),
-                    ],
-                    program_id,
-                )
-            } else {
-                Pubkey::find_program_address(&[TOKEN_SETTINGS_SEED_PREFIX, &[0u8; 32]], program_id)
-            };
+        let (expected_settings, _) = if let Some(token) = token {
+            Pubkey::find_program_address(
+                &[
+                    TOKEN_SETTINGS_SEED_PREFIX,
+                    &token.to_account_info().key().to_bytes(),
+                ],
+                program_id,
+            )
+        } else {
+            Pubkey::find_program_address(&[TOKEN_SETTINGS_SEED_PREFIX, &[0u8; 32]], program_id)
+        };
+        require!(
+            token_settings.key() == expected_settings,
+            Errors::InvalidTokenSettings
+        );
+        require!(token_settings.enabled, Errors::TokenNotEnabled);
+        Ok(())
+    }
+
+    pub fn calculate_fee(
+        amount: u64,
+        admin_settings: &AdminSettings,
+        token_settings: &TokenSettings,
+        is_out: bool,
+    ) -> Result<u64> {
+        let fee_rate_bp = token_settings
+            .fee_rate_bp_for(amount, is_out)
+            .unwrap_or_else(|| admin_settings.fee_rate_bp_for(is_out));
+        let mut fee = mul_bp(amount, fee_rate_bp, admin_settings.fee_rounding)?;
+        if fee < token_settings.min_fee {
+            fee = token_settings.min_fee;
+        }
+        if token_settings.max_fee > 0 && fee > token_settings.max_fee {
+            fee = token_settings.max_fee;
+        }
+        Ok(fee.min(mul_bp(
+            amount,
+            admin_settings.fee_rate_cap_bp,
+            admin_settings.fee_rounding,
+        )?))
+    }
+
+    pub fn check_fee_tiers(fee_tiers: &[FeeTier], fee_rate_cap_bp: u16) -> Result<()> {
+        require!(fee_tiers.len() <= MAX_FEE_TIERS, Errors::InvalidFeeTiers);
+        for (i, tier) in fee_tiers.iter().enumerate() {
+            require!(tier.fee_rate_bp < 10000, Errors::InvalidFeeRate);
+            require!(tier.fee_rate_bp <= fee_rate_cap_bp, Errors::FeeRateAboveCap);
             require!(
-                settings.key() == expected_settings,
-                Errors::InvalidTokenSettings
+                i == 0 || tier.min_amount > fee_tiers[i - 1].min_amount,
+                Errors::InvalidFeeTiers
             );
         }
         Ok(())
     }
 
-    pub fn calculate_fee(amount: u64, fee_rate_bp: u16, max_fee: Option<u64>) -> u64 {
-        let mut fee = amount * fee_rate_bp as u64 / 10000;
-        if let Some(max) = max_fee {
-            if max > 0 && fee > max {
-                fee = max;
-            }
+    pub fn apply_fee_discount(
+        fee: u64,
+        fee_discount: Option<&Account<FeeDiscount>>,
+    ) -> Result<u64> {
+        let discount_bp = fee_discount.map_or(0, |d| d.discount_bp);
+        Ok(fee - mul_bp(fee, discount_bp, FeeRounding::Floor)?)
+    }
+
+    /// `amount * bp / 10000` without overflow, the result never exceeds `amount` as `bp > 10000` is rejected.
+    pub fn mul_bp(amount: u64, bp: u16, rounding: FeeRounding) -> Result<u64> {
+        require!(bp <= 10000, Errors::InvalidFeeRate);
+        let product = amount as u128 * bp as u128;
+        let result = match rounding {
+            FeeRounding::Floor => product / 10000,
+            FeeRounding::Ceil => product.div_ceil(10000),
+        };
+        Ok(u64::try_from(result).map_err(|_| Errors::InvalidFeeRate)?)
+    }
+
+    pub fn harvest_withheld_fees<'info>(
+        token_program: &Interface<'info, TokenInterface>,
+        account: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
+    ) -> Result<()> {
+        // Token-2022 transfer fees are withheld in the receiving account and block closing it
+        let withheld_amount = {
+            let data = account.try_borrow_data()?;
+            let state = StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)?;
+            state
+                .get_extension::<transfer_fee::TransferFeeAmount>()
+                .map_or(0, |fee_amount| u64::from(fee_amount.withheld_amount))
+        };
+        if withheld_amount == 0 {
+            return Ok(());
         }
-        fee
+
+        let ix = transfer_fee::instruction::harvest_withheld_tokens_to_mint(
+            &token_program.key(),
+            &mint.key(),
+            &[account.key],
+        )?;
+        solana_program::program::invoke(&ix, &[mint.to_account_info(), account.clone()])?;
+        Ok(())
     }
 
     pub fn close_token_account<'info>(
-        token_program: &Program<'info, Token>,
+        token_program: &Interface<'info, TokenInterface>,
         account: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
         destination: &AccountInfo<'info>,
         authority: &AccountInfo<'info>,
         seeds: &[&[&[u8]]],
     ) -> Result<()> {
-        token::close_account(CpiContext::new_with_signer(
+        harvest_withheld_fees(token_program, account, mint)?;
+        token_interface::close_account(CpiContext::new_with_signer(
             token_program.to_account_info(),
             CloseAccount {
                 account: account.to_account_info(),
@@ -122,50 +264,486 @@ pub mod obridge {
     use anchor_lang::system_program;
     use helpers::*;
 
-    pub fn initialize(ctx: Context<Initialize>, admin: Pubkey) -> Result<()> {
-        ctx.accounts.admin_settings.admin = admin;
+    pub fn initialize(ctx: Context<Initialize>, admin: Pubkey, fee_rate_cap_bp: u16) -> Result<()> {
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        admin_settings.version = ACCOUNT_VERSION;
+        admin_settings.admin = admin;
+        admin_settings.fee_rate_cap_bp = fee_rate_cap_bp;
+        admin_settings.preimage_retention_period = DEFAULT_PREIMAGE_RETENTION_PERIOD;
         Ok(())
     }
 
     pub fn change_admin(ctx: Context<ChangeAdmin>) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.admin = ctx.accounts.new_admin.key();
         Ok(())
     }
 
+    pub fn set_admin_signers(
+        ctx: Context<SetAdminSigners>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        require!(
+            signers.len() <= MAX_ADMIN_SIGNERS && threshold as usize <= signers.len(),
+            Errors::InvalidAdminSigners
+        );
+        for (i, signer) in signers.iter().enumerate() {
+            require!(!signers[..i].contains(signer), Errors::InvalidAdminSigners);
+        }
+
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        admin_settings.admin_signers = [Pubkey::default(); MAX_ADMIN_SIGNERS];
+        admin_settings.admin_signers[..signers.len()].copy_from_slice(&signers);
+        admin_settings.admin_signer_count = signers.len() as u8;
+        admin_settings.admin_threshold = threshold;
+        Ok(())
+    }
+
+    pub fn set_role(ctx: Context<SetRole>, role: Role, key: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        match role {
+            Role::FeeManager => admin_settings.fee_manager = key,
+            Role::TokenManager => admin_settings.token_manager = key,
+            Role::Pauser => admin_settings.pauser = key,
+        }
+        Ok(())
+    }
+
     pub fn set_fee_recepient(ctx: Context<SetFeeRecepient>) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.fee_recepient = ctx.accounts.fee_recepient.key();
         Ok(())
     }
 
     pub fn set_fee_rate(ctx: Context<SetFeeRate>, fee_rate_bp: u16) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.fee_rate_bp = fee_rate_bp;
         Ok(())
     }
 
+    pub fn set_direction_fee_rate(
+        ctx: Context<SetDirectionFeeRate>,
+        fee_rate_bp_out: Option<u16>,
+        fee_rate_bp_in: Option<u16>,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        admin_settings.fee_rate_bp_out = fee_rate_bp_out;
+        admin_settings.fee_rate_bp_in = fee_rate_bp_in;
+        Ok(())
+    }
+
+    pub fn set_fee_rounding(ctx: Context<SetFeeRounding>, fee_rounding: FeeRounding) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.fee_rounding = fee_rounding;
+        Ok(())
+    }
+
+    pub fn set_max_referral_share(
+        ctx: Context<SetMaxReferralShare>,
+        max_referral_share_bp: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.max_referral_share_bp = max_referral_share_bp;
+        Ok(())
+    }
+
     pub fn set_max_fee_for_token(
         ctx: Context<SetMaxFeeForToken>,
         _mint: Pubkey,
         max_fee: u64,
     ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.version = ACCOUNT_VERSION;
         ctx.accounts.token_settings.max_fee = max_fee;
         Ok(())
     }
 
-    pub fn prepare(
-        ctx: Context<Prepare>,
-        _uuid: [u8; 32],
+    pub fn set_fee_rate_for_token(
+        ctx: Context<SetFeeRateForToken>,
+        _mint: Pubkey,
+        fee_rate_bp: Option<u16>,
+        min_fee: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.fee_rate_bp = fee_rate_bp;
+        token_settings.min_fee = min_fee;
+        Ok(())
+    }
+
+    pub fn set_direction_fee_rate_for_token(
+        ctx: Context<SetDirectionFeeRateForToken>,
+        _mint: Pubkey,
+        fee_rate_bp_out: Option<u16>,
+        fee_rate_bp_in: Option<u16>,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.fee_rate_bp_out = fee_rate_bp_out;
+        token_settings.fee_rate_bp_in = fee_rate_bp_in;
+        Ok(())
+    }
+
+    pub fn set_fee_tiers_for_token(
+        ctx: Context<SetFeeTiersForToken>,
+        _mint: Pubkey,
+        fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        check_fee_tiers(&fee_tiers, ctx.accounts.admin_settings.fee_rate_cap_bp)?;
+
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        token_settings.fee_tiers[..fee_tiers.len()].copy_from_slice(&fee_tiers);
+        token_settings.fee_tier_count = fee_tiers.len() as u8;
+        Ok(())
+    }
+
+    pub fn set_fee_discount(
+        ctx: Context<SetFeeDiscount>,
+        _user: Pubkey,
+        discount_bp: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.fee_discount.discount_bp = discount_bp;
+        Ok(())
+    }
+
+    pub fn remove_fee_discount(ctx: Context<RemoveFeeDiscount>, _user: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        Ok(())
+    }
+
+    pub fn set_amount_limits_for_token(
+        ctx: Context<SetAmountLimitsForToken>,
+        _mint: Pubkey,
+        min_amount: u64,
+        max_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.min_amount = min_amount;
+        token_settings.max_amount = max_amount;
+        Ok(())
+    }
+
+    pub fn register_token(ctx: Context<RegisterToken>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.version = ACCOUNT_VERSION;
+        ctx.accounts.token_settings.enabled = true;
+        Ok(())
+    }
+
+    pub fn deregister_token(ctx: Context<DeregisterToken>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.enabled = false;
+        Ok(())
+    }
+
+    pub fn set_preimage_retention(
+        ctx: Context<SetPreimageRetention>,
+        retention_period: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.preimage_retention_period = retention_period;
+        Ok(())
+    }
+
+    pub fn set_timelock_delay(ctx: Context<SetTimelockDelay>, delay: i64) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.timelock_delay = delay;
+        Ok(())
+    }
+
+    pub fn queue_admin_change(
+        ctx: Context<QueueAdminChange>,
+        _id: u64,
+        change: AdminChange,
+        effective_time: i64,
+    ) -> Result<()> {
+        let admin_settings = &ctx.accounts.admin_settings;
+        admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        change.validate(admin_settings.fee_rate_cap_bp)?;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            effective_time >= timestamp.saturating_add(admin_settings.timelock_delay),
+            Errors::InvalidEffectiveTime
+        );
+
+        let pending_change = &mut ctx.accounts.pending_change;
+        pending_change.change = change;
+        pending_change.effective_time = effective_time;
+        pending_change.payer = ctx.accounts.payer.key();
+        Ok(())
+    }
+
+    pub fn execute_admin_change(ctx: Context<ExecuteAdminChange>, _id: u64) -> Result<()> {
+        let timestamp = Clock::get()?.unix_timestamp;
+        let pending_change = &ctx.accounts.pending_change;
+        require!(
+            timestamp >= pending_change.effective_time,
+            Errors::TimelockNotElapsed
+        );
+
+        let token_settings = match pending_change.change.mint() {
+            Some(mint) => {
+                let token_settings = ctx
+                    .accounts
+                    .token_settings
+                    .as_mut()
+                    .ok_or(Errors::InvalidTokenSettings)?;
+                let (expected_settings, _) = Pubkey::find_program_address(
+                    &[TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+                    ctx.program_id,
+                );
+                require!(
+                    token_settings.key() == expected_settings,
+                    Errors::InvalidTokenSettings
+                );
+                Some(&mut **token_settings)
+            }
+            None => None,
+        };
+        let fee_discount = match pending_change.change.discount_user() {
+            Some(user) => {
+                let fee_discount = ctx
+                    .accounts
+                    .fee_discount
+                    .as_mut()
+                    .ok_or(Errors::InvalidFeeDiscount)?;
+                let (expected_discount, _) = Pubkey::find_program_address(
+                    &[FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()],
+                    ctx.program_id,
+                );
+                require!(
+                    fee_discount.key() == expected_discount,
+                    Errors::InvalidFeeDiscount
+                );
+                Some(&mut **fee_discount)
+            }
+            None => None,
+        };
+        pending_change.change.apply(
+            &mut ctx.accounts.admin_settings,
+            token_settings,
+            fee_discount,
+        );
+        Ok(())
+    }
+
+    pub fn cancel_admin_change(ctx: Context<CancelAdminChange>, _id: u64) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        Ok(())
+    }
+
+    pub fn migrate_admin_settings(
+        ctx: Context<MigrateAdminSettings>,
+        fee_rate_cap_bp: u16,
+    ) -> Result<()> {
+        let account = ctx.accounts.admin_settings.to_account_info();
+        let admin_settings =
+            legacy::read::<legacy::AdminSettings, AdminSettings>(&account.try_borrow_data()?)?
+                .upgrade(fee_rate_cap_bp)?;
+        require_keys_eq!(
+            admin_settings.admin,
+            ctx.accounts.admin.key(),
+            Errors::AccountMismatch
+        );
+        admin_settings.check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &admin_settings,
+        )
+    }
+
+    pub fn migrate_token_settings(ctx: Context<MigrateTokenSettings>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        let account = ctx.accounts.token_settings.to_account_info();
+        let token_settings: TokenSettings =
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&account.try_borrow_data()?)?
+                .into();
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &token_settings,
+        )
+    }
+
+    /// Open to anyone, the upgrade keeps the parties, amounts and lock of the escrow as they are.
+    pub fn migrate_escrow(ctx: Context<MigrateEscrow>, _uuid: [u8; 32]) -> Result<()> {
+        let account = ctx.accounts.escrow.to_account_info();
+        let escrow: Escrow =
+            legacy::read::<legacy::Escrow, Escrow>(&account.try_borrow_data()?)?.into();
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &escrow,
+        )
+    }
+
+    /// Pausing is the emergency brake, so the pauser or the admin can pull it alone. Lifting any
+    /// pause reopens new escrows and needs the admin approvals.
+    pub fn set_pause(
+        ctx: Context<SetPause>,
+        paused: bool,
+        paused_out: bool,
+        paused_in: bool,
+    ) -> Result<()> {
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        let unpausing = (admin_settings.paused && !paused)
+            || (admin_settings.paused_out && !paused_out)
+            || (admin_settings.paused_in && !paused_in);
+        if unpausing {
+            admin_settings
+                .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        }
+        admin_settings.paused = paused;
+        admin_settings.paused_out = paused_out;
+        admin_settings.paused_in = paused_in;
+        Ok(())
+    }
+
+    /// Remaining accounts hold `co_signer_count` admin co-signers, then for each mint its
+    /// (mint, fee_vault, destination, token_program) followed by `hook_account_counts[i]`
+    /// transfer hook accounts. Token fees go to whatever destination the approving admins
+    /// picked, SOL fees always go to `fee_recepient`.
+    pub fn withdraw_fees<'info>(
+        ctx: Context<'_, '_, 'info, 'info, WithdrawFees<'info>>,
+        co_signer_count: u8,
+        hook_account_counts: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            ctx.remaining_accounts.len() >= co_signer_count as usize,
+            Errors::InvalidAccount
+        );
+        let (co_signers, mut accounts) = ctx.remaining_accounts.split_at(co_signer_count as usize);
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), co_signers)?;
+        let admin_settings = ctx.accounts.admin_settings.to_account_info();
+        let seeds: &[&[&[u8]]] = &[&[ADMIN_SETTINGS_SEED, &[ctx.bumps.admin_settings]]];
+
+        for hook_account_count in hook_account_counts {
+            let mint_accounts_len = 4 + hook_account_count as usize;
+            require!(accounts.len() >= mint_accounts_len, Errors::InvalidAccount);
+            let (mint_accounts, rest) = accounts.split_at(mint_accounts_len);
+            accounts = rest;
+
+            let mint = InterfaceAccount::<Mint>::try_from(&mint_accounts[0])?;
+            let fee_vault = InterfaceAccount::<TokenAccount>::try_from(&mint_accounts[1])?;
+            let destination = InterfaceAccount::<TokenAccount>::try_from(&mint_accounts[2])?;
+            let token_program = Interface::<TokenInterface>::try_from(&mint_accounts[3])?;
+            require!(
+                fee_vault.owner == admin_settings.key()
+                    && fee_vault.mint == mint.key()
+                    && destination.mint == mint.key()
+                    && *mint.to_account_info().owner == token_program.key(),
+                Errors::AccountMismatch
+            );
+
+            if fee_vault.amount > 0 {
+                handle_token_transfer(
+                    &token_program,
+                    &mint_accounts[1],
+                    &mint,
+                    &mint_accounts[2],
+                    &admin_settings,
+                    fee_vault.amount,
+                    &mint_accounts[4..],
+                    Some(seeds),
+                )?;
+            }
+        }
+
+        // sweep SOL fees, keeping the admin settings account rent exempt
+        let rent_exempt = Rent::get()?.minimum_balance(admin_settings.data_len());
+        let sol_fee = admin_settings.lamports().saturating_sub(rent_exempt);
+        if sol_fee > 0 {
+            ctx.accounts.admin_settings.sub_lamports(sol_fee)?;
+            ctx.accounts.fee_recepient.add_lamports(sol_fee)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn prepare<'info>(
+        ctx: Context<'_, '_, '_, 'info, Prepare<'info>>,
+        uuid: [u8; 32],
         to: Pubkey,
         sol_amount: u64,
         token_amount: u64,
         lock: Lock,
         is_out: bool,
         _memo: Vec<u8>,
+        fee_mode: FeeMode,
+        referral: Option<Referral>,
     ) -> Result<()> {
         require!(
             ctx.accounts.payer.key() == ctx.accounts.from.key(),
             Errors::InvalidSender
         );
         require!(sol_amount > 0 || token_amount > 0, Errors::InvalidAmount);
+        ctx.accounts.admin_settings.check_not_paused(is_out)?;
+        ctx.accounts.admin_settings.check_referral(
+            referral.as_ref(),
+            ctx.accounts.referrer.as_ref().map(|r| r.as_ref()),
+        )?;
 
         let timestamp = Clock::get()?.unix_timestamp;
 
@@ -177,32 +755,46 @@ pub mod obridge {
         require!(timestamp <= timelock, Errors::DeadlineExceeded);
 
         lock.check_refund_time()?;
+        lock.check_max_preimage_len()?;
 
         verify_token_settings(
             ctx.accounts.mint.as_ref(),
-            ctx.accounts.token_settings.as_ref(),
+            &ctx.accounts.token_settings,
             &ctx.program_id,
         )?;
+        // the settings belong to the mint when one is escrowed, to native SOL otherwise
+        if ctx.accounts.mint.is_some() {
+            ctx.accounts.token_settings.check_amount(token_amount)?;
+        }
+        // the SOL leg always follows the native SOL settings
+        let sol_settings = match ctx.accounts.mint {
+            Some(_) => ctx.accounts.sol_settings.as_ref(),
+            None => Some(&ctx.accounts.token_settings),
+        };
 
-        let fee_rate_bp = ctx.accounts.admin_settings.fee_rate_bp;
+        let fee_for = |amount: u64, token_settings: &TokenSettings| {
+            apply_fee_discount(
+                calculate_fee(amount, &ctx.accounts.admin_settings, token_settings, is_out)?,
+                ctx.accounts.fee_discount.as_ref(),
+            )
+        };
 
         let mut sol_fee: u64 = 0;
         if sol_amount > 0 {
+            let sol_settings = sol_settings.ok_or(Errors::InvalidTokenSettings)?;
+            verify_token_settings(None, sol_settings, ctx.program_id)?;
+            sol_settings.check_amount(sol_amount)?;
+            sol_fee = fee_for(sol_amount, sol_settings)?;
             handle_sol_transfer(
                 &ctx.accounts.from.to_account_info(),
                 &ctx.accounts.escrow.to_account_info(),
                 &ctx.accounts.system_program.to_account_info(),
-                sol_amount,
+                fee_mode.escrowed_amount(sol_amount, sol_fee),
             )?;
-
-            sol_fee = calculate_fee(
-                sol_amount,
-                fee_rate_bp,
-                ctx.accounts.token_settings.as_ref().map(|s| s.max_fee),
-            );
         }
 
         let mut token_fee: u64 = 0;
+        let mut token_received: u64 = 0;
         if token_amount > 0 {
             require!(
                 ctx.accounts.mint.is_some()
@@ -211,20 +803,43 @@ pub mod obridge {
                     && ctx.accounts.token_program.is_some(),
                 Errors::InvalidAccount
             );
+            // the mint would withhold part of the pull and leave `to` short of token_amount
+            require!(
+                fee_mode == FeeMode::Deducted
+                    || !has_transfer_fee(ctx.accounts.mint.as_ref().unwrap())?,
+                Errors::OnTopFeeWithTransferFee
+            );
+            // on top fees are quoted on token_amount and pulled along with it
+            let on_top_fee = match fee_mode {
+                FeeMode::Deducted => 0,
+                FeeMode::OnTop => fee_for(token_amount, &ctx.accounts.token_settings)?,
+            };
+
+            let escrow_ata = ctx.accounts.escrow_ata.as_mut().unwrap();
+            let balance_before = escrow_ata.amount;
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.source.as_ref().unwrap().to_account_info(),
-                &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
+                &escrow_ata.to_account_info(),
                 &ctx.accounts.from.to_account_info(),
-                token_amount,
+                token_amount + on_top_fee,
+                ctx.remaining_accounts,
                 None,
             )?;
 
-            token_fee = calculate_fee(
-                token_amount,
-                fee_rate_bp,
-                ctx.accounts.token_settings.as_ref().map(|s| s.max_fee),
-            );
+            // transfer-fee mints deliver less than token_amount, account for what actually arrived
+            escrow_ata.reload()?;
+            token_received = escrow_ata.amount - balance_before;
+            require!(token_received > on_top_fee, Errors::InvalidAmount);
+
+            token_fee = match fee_mode {
+                FeeMode::Deducted => fee_for(token_received, &ctx.accounts.token_settings)?,
+                FeeMode::OnTop => {
+                    token_received -= on_top_fee;
+                    on_top_fee
+                }
+            };
         }
 
         // Add this constant at the top of the file with other constants
@@ -232,6 +847,7 @@ pub mod obridge {
 
         // In the prepare function, replace the escrow assignment block with:
         let escrow = &mut ctx.accounts.escrow;
+        escrow.version = ACCOUNT_VERSION;
         escrow.from = ctx.accounts.from.key();
         escrow.to = to;
         escrow.token_program = ctx
@@ -251,25 +867,55 @@ pub mod obridge {
             .as_ref()
             .map_or(ZERO_PUBKEY, |ata| ata.key());
         escrow.sol_amount = sol_amount;
-        escrow.token_amount = token_amount;
+        escrow.token_amount = token_received;
         escrow.sol_fee = sol_fee;
         escrow.token_fee = token_fee;
         escrow.lock = lock;
         escrow.is_out = is_out;
+        escrow.fee_mode = fee_mode;
+        if let Some(referral) = referral {
+            escrow.referrer = referral.referrer;
+            escrow.referral_share_bp = referral.share_bp;
+        }
+
+        emit_cpi!(Prepared {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            mint: escrow.mint,
+            sol_amount: escrow.sol_amount,
+            token_amount: escrow.token_amount,
+            sol_fee: escrow.sol_fee,
+            token_fee: escrow.token_fee,
+            lock: escrow.lock.clone(),
+            is_out: escrow.is_out,
+            fee_mode: escrow.fee_mode,
+        });
+
         Ok(())
     }
 
-    pub fn confirm(
-        ctx: Context<Confirm>,
+    pub fn confirm<'info>(
+        ctx: Context<'_, '_, '_, 'info, Confirm<'info>>,
         uuid: [u8; 32],
         preimage: [u8; 32],
         is_out: bool,
+    ) -> Result<()> {
+        confirm_with_preimage(ctx, uuid, preimage.to_vec(), is_out)
+    }
+
+    pub fn confirm_with_preimage<'info>(
+        ctx: Context<'_, '_, '_, 'info, Confirm<'info>>,
+        uuid: [u8; 32],
+        preimage: Vec<u8>,
+        is_out: bool,
     ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
-        let hash = keccak::hash(&preimage).0;
         let timestamp = Clock::get()?.unix_timestamp;
 
-        escrow.lock.check_hashlock(&hash)?;
+        require!(escrow.is_out == is_out, Errors::InvalidDirection);
+        escrow.lock.check_preimage_len(&preimage)?;
+        escrow.lock.check_hashlock(&preimage)?;
 
         if is_out {
             // payer is the from account
@@ -312,31 +958,97 @@ pub mod obridge {
             }
         }
 
+        // keep the revealed preimage readable after the escrow is closed
+        if let Some(record) = ctx.accounts.preimage_record.as_mut() {
+            if record.payer == Pubkey::default() {
+                record.payer = ctx.accounts.payer.key();
+            }
+            record.hash = escrow.lock.hash;
+            record.preimage = [0u8; MAX_PREIMAGE_LEN];
+            record.preimage[..preimage.len()].copy_from_slice(&preimage);
+            record.preimage_len = preimage.len() as u8;
+            record.recorded_time = timestamp;
+        }
+
+        let sol_referral_fee = escrow.referral_fee(escrow.sol_fee)?;
+        let token_referral_fee = escrow.referral_fee(escrow.token_fee)?;
+
+        let event = Confirmed {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            mint: escrow.mint,
+            sol_amount: escrow.sol_amount,
+            token_amount: escrow.token_amount,
+            sol_fee: escrow.sol_fee,
+            token_fee: escrow.token_fee,
+            lock: escrow.lock.clone(),
+            is_out: escrow.is_out,
+            preimage,
+            fee_mode: escrow.fee_mode,
+            referrer: escrow.referrer,
+            sol_referral_fee,
+            token_referral_fee,
+        };
+
         let seeds: &[&[&[u8]]] = &[&[&uuid, &[Pubkey::find_program_address(&[&uuid], &id()).1]]];
 
+        // the referral vault is only opened for escrows that carry a referrer
+        if escrow.has_referrer() {
+            require!(
+                ctx.accounts.referral_vault.is_some(),
+                Errors::InvalidAccount
+            );
+            ctx.accounts.referral_vault.as_mut().unwrap().referrer = escrow.referrer;
+        }
+
         // Handle token transfers if applicable
         if escrow.token_amount > 0 {
-            // Transfer fee to fee recipient
+            // Transfer fee to fee vault
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
-                &ctx.accounts
-                    .fee_destination
-                    .as_ref()
-                    .unwrap()
-                    .to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
+                &ctx.accounts.fee_vault.as_ref().unwrap().to_account_info(),
                 &escrow.to_account_info(),
-                escrow.token_fee,
+                escrow.token_fee - token_referral_fee,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
+            // Accrue referral share of the fee until the referrer claims it
+            if token_referral_fee > 0 {
+                require!(
+                    ctx.accounts.referral_vault_ata.is_some(),
+                    Errors::InvalidAccount
+                );
+                handle_token_transfer(
+                    ctx.accounts.token_program.as_ref().unwrap(),
+                    &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                    ctx.accounts.mint.as_ref().unwrap(),
+                    &ctx.accounts
+                        .referral_vault_ata
+                        .as_ref()
+                        .unwrap()
+                        .to_account_info(),
+                    &escrow.to_account_info(),
+                    token_referral_fee,
+                    ctx.remaining_accounts,
+                    Some(seeds),
+                )?;
+            }
+
             // Transfer remaining amount to destination
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
                 &ctx.accounts.destination.as_ref().unwrap().to_account_info(),
                 &escrow.to_account_info(),
-                escrow.token_amount - escrow.token_fee,
+                escrow
+                    .fee_mode
+                    .payout_amount(escrow.token_amount, escrow.token_fee),
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
@@ -344,6 +1056,7 @@ pub mod obridge {
             close_token_account(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
                 &ctx.accounts.from.to_account_info(),
                 &escrow.to_account_info(),
                 seeds,
@@ -354,13 +1067,32 @@ pub mod obridge {
         let escrow_lamports = escrow.to_account_info().lamports();
         if escrow.sol_amount > 0 {
             // Handle SOL transfers if applicable
-            ctx.accounts.fee_recepient.add_lamports(escrow.sol_fee)?;
-            ctx.accounts
-                .to
-                .add_lamports(escrow.sol_amount - escrow.sol_fee)?;
+            if sol_referral_fee > 0 {
+                require!(
+                    ctx.accounts.referral_vault.is_some(),
+                    Errors::InvalidAccount
+                );
+                ctx.accounts
+                    .referral_vault
+                    .as_ref()
+                    .unwrap()
+                    .add_lamports(sol_referral_fee)?;
+            }
+            // SOL fees are kept on the admin settings account until withdrawn
             ctx.accounts
-                .from
-                .add_lamports(escrow_lamports - escrow.sol_amount)?;
+                .admin_settings
+                .add_lamports(escrow.sol_fee - sol_referral_fee)?;
+            ctx.accounts.to.add_lamports(
+                escrow
+                    .fee_mode
+                    .payout_amount(escrow.sol_amount, escrow.sol_fee),
+            )?;
+            ctx.accounts.from.add_lamports(
+                escrow_lamports
+                    - escrow
+                        .fee_mode
+                        .escrowed_amount(escrow.sol_amount, escrow.sol_fee),
+            )?;
         } else {
             ctx.accounts.from.add_lamports(escrow_lamports)?;
         }
@@ -368,10 +1100,16 @@ pub mod obridge {
         escrow.to_account_info().assign(&system_program::ID);
         escrow.to_account_info().realloc(0, false)?;
 
+        emit_cpi!(event);
+
         Ok(())
     }
 
-    pub fn refund(ctx: Context<Refund>, uuid: [u8; 32], _is_out: bool) -> Result<()> {
+    pub fn refund<'info>(
+        ctx: Context<'_, '_, '_, 'info, Refund<'info>>,
+        uuid: [u8; 32],
+        _is_out: bool,
+    ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         let timestamp = Clock::get()?.unix_timestamp;
 
@@ -380,15 +1118,34 @@ pub mod obridge {
             Errors::NotRefundable
         );
 
+        let event = Refunded {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            mint: escrow.mint,
+            sol_amount: escrow.sol_amount,
+            token_amount: escrow.token_amount,
+            sol_fee: escrow.sol_fee,
+            token_fee: escrow.token_fee,
+            lock: escrow.lock.clone(),
+            is_out: escrow.is_out,
+            fee_mode: escrow.fee_mode,
+            referrer: escrow.referrer,
+        };
+
         let seeds: &[&[&[u8]]] = &[&[&uuid, &[Pubkey::find_program_address(&[&uuid], &id()).1]]];
 
         if escrow.token_amount > 0 {
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
                 &ctx.accounts.source.as_ref().unwrap().to_account_info(),
                 &escrow.to_account_info(),
-                escrow.token_amount,
+                escrow
+                    .fee_mode
+                    .escrowed_amount(escrow.token_amount, escrow.token_fee),
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
@@ -396,6 +1153,7 @@ pub mod obridge {
             close_token_account(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.mint.as_ref().unwrap(),
                 &ctx.accounts.from.to_account_info(),
                 &escrow.to_account_info(),
                 seeds,
@@ -410,6 +1168,63 @@ pub mod obridge {
         escrow.to_account_info().assign(&system_program::ID);
         escrow.to_account_info().realloc(0, false)?;
 
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn claim_referral_fees<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimReferralFees<'info>>,
+    ) -> Result<()> {
+        let referrer = ctx.accounts.referrer.key();
+        let seeds: &[&[&[u8]]] = &[&[
+            REFERRAL_VAULT_SEED_PREFIX,
+            referrer.as_ref(),
+            &[ctx.bumps.referral_vault],
+        ]];
+
+        if let Some(referral_vault_ata) = ctx.accounts.referral_vault_ata.as_ref() {
+            require!(
+                ctx.accounts.mint.is_some()
+                    && ctx.accounts.destination.is_some()
+                    && ctx.accounts.token_program.is_some(),
+                Errors::InvalidAccount
+            );
+            if referral_vault_ata.amount > 0 {
+                handle_token_transfer(
+                    ctx.accounts.token_program.as_ref().unwrap(),
+                    &referral_vault_ata.to_account_info(),
+                    ctx.accounts.mint.as_ref().unwrap(),
+                    &ctx.accounts.destination.as_ref().unwrap().to_account_info(),
+                    &ctx.accounts.referral_vault.to_account_info(),
+                    referral_vault_ata.amount,
+                    ctx.remaining_accounts,
+                    Some(seeds),
+                )?;
+            }
+        }
+
+        // sweep SOL shares, keeping the vault rent exempt
+        let referral_vault = ctx.accounts.referral_vault.to_account_info();
+        let rent_exempt = Rent::get()?.minimum_balance(referral_vault.data_len());
+        let sol_fee = referral_vault.lamports().saturating_sub(rent_exempt);
+        if sol_fee > 0 {
+            ctx.accounts.referral_vault.sub_lamports(sol_fee)?;
+            ctx.accounts.referrer.add_lamports(sol_fee)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn close_preimage_record(ctx: Context<ClosePreimageRecord>, _hash: [u8; 32]) -> Result<()> {
+        let timestamp = Clock::get()?.unix_timestamp;
+        let closable_time = ctx
+            .accounts
+            .preimage_record
+            .recorded_time
+            .checked_add(ctx.accounts.admin_settings.preimage_retention_period)
+            .ok_or(Errors::InvalidRetentionPeriod)?;
+        require!(timestamp >= closable_time, Errors::RetentionNotElapsed);
         Ok(())
     }
 }
@@ -440,20 +1255,358 @@ pub enum Errors {
     InvalidDirection,
     #[msg("invalid token settings")]
     InvalidTokenSettings,
+    #[msg("invalid retention period")]
+    InvalidRetentionPeriod,
+    #[msg("retention period not elapsed")]
+    RetentionNotElapsed,
+    #[msg("invalid preimage length")]
+    InvalidPreimageLength,
+    #[msg("paused")]
+    Paused,
+    #[msg("token not enabled")]
+    TokenNotEnabled,
+    #[msg("invalid amount limits")]
+    InvalidAmountLimits,
+    #[msg("amount out of range")]
+    AmountOutOfRange,
+    #[msg("invalid fee tiers")]
+    InvalidFeeTiers,
+    #[msg("invalid fee discount")]
+    InvalidFeeDiscount,
+    #[msg("invalid referral share")]
+    InvalidReferralShare,
+    #[msg("invalid admin signers")]
+    InvalidAdminSigners,
+    #[msg("not enough admin signers")]
+    NotEnoughAdminSigners,
+    #[msg("change must be queued")]
+    TimelockRequired,
+    #[msg("invalid effective time")]
+    InvalidEffectiveTime,
+    #[msg("timelock not elapsed")]
+    TimelockNotElapsed,
+    #[msg("fee rate above protocol cap")]
+    FeeRateAboveCap,
+    #[msg("account already migrated")]
+    AlreadyMigrated,
+    #[msg("invalid referrer")]
+    InvalidReferrer,
+    #[msg("fee on top not supported for transfer fee mints")]
+    OnTopFeeWithTransferFee,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Lock {
-    pub hash: [u8; 32],
-    pub agreement_reached_time: i64,
-    pub expected_single_step_time: i64,
-    pub tolerant_single_step_time: i64,
-    pub earliest_refund_time: i64,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum HashAlgorithm {
+    Keccak256,
+    Sha256,
+    DoubleSha256,
 }
 
-impl Lock {
-    fn check_refund_time(&self) -> Result<()> {
-        require!(
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum FeeMode {
+    /// `to` receives the escrowed amount minus the fee.
+    Deducted,
+    /// `from` pays the fee on top and `to` receives the full amount. Not available for Token-2022
+    /// mints with a transfer fee.
+    OnTop,
+}
+
+impl FeeMode {
+    fn escrowed_amount(&self, amount: u64, fee: u64) -> u64 {
+        match self {
+            FeeMode::Deducted => amount,
+            FeeMode::OnTop => amount + fee,
+        }
+    }
+
+    fn payout_amount(&self, amount: u64, fee: u64) -> u64 {
+        match self {
+            FeeMode::Deducted => amount - fee,
+            FeeMode::OnTop => amount,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    FeeManager,
+    TokenManager,
+    Pauser,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum FeeRounding {
+    Floor,
+    Ceil,
+}
+
+/// Fee parameter updates that go through `queue_admin_change` once a timelock delay is set.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub enum AdminChange {
+    FeeRate {
+        fee_rate_bp: u16,
+    },
+    DirectionFeeRate {
+        fee_rate_bp_out: Option<u16>,
+        fee_rate_bp_in: Option<u16>,
+    },
+    FeeRounding {
+        fee_rounding: FeeRounding,
+    },
+    TimelockDelay {
+        delay: i64,
+    },
+    TokenMaxFee {
+        mint: Pubkey,
+        max_fee: u64,
+    },
+    TokenFeeRate {
+        mint: Pubkey,
+        fee_rate_bp: Option<u16>,
+        min_fee: u64,
+    },
+    TokenDirectionFeeRate {
+        mint: Pubkey,
+        fee_rate_bp_out: Option<u16>,
+        fee_rate_bp_in: Option<u16>,
+    },
+    TokenFeeTiers {
+        mint: Pubkey,
+        fee_tiers: [FeeTier; MAX_FEE_TIERS],
+        fee_tier_count: u8,
+    },
+    FeeRecepient {
+        fee_recepient: Pubkey,
+    },
+    MaxReferralShare {
+        max_referral_share_bp: u16,
+    },
+    FeeDiscount {
+        user: Pubkey,
+        discount_bp: u16,
+    },
+    TokenAmountLimits {
+        mint: Pubkey,
+        min_amount: u64,
+        max_amount: u64,
+    },
+}
+
+impl AdminChange {
+    /// `None` restricts the change to the super-admin.
+    fn role(&self) -> Option<Role> {
+        match self {
+            AdminChange::FeeRate { .. }
+            | AdminChange::DirectionFeeRate { .. }
+            | AdminChange::FeeRounding { .. }
+            | AdminChange::FeeRecepient { .. }
+            | AdminChange::MaxReferralShare { .. }
+            | AdminChange::FeeDiscount { .. } => Some(Role::FeeManager),
+            AdminChange::TimelockDelay { .. } => None,
+            AdminChange::TokenMaxFee { .. }
+            | AdminChange::TokenFeeRate { .. }
+            | AdminChange::TokenDirectionFeeRate { .. }
+            | AdminChange::TokenFeeTiers { .. }
+            | AdminChange::TokenAmountLimits { .. } => Some(Role::TokenManager),
+        }
+    }
+
+    fn mint(&self) -> Option<Pubkey> {
+        match self {
+            AdminChange::TokenMaxFee { mint, .. }
+            | AdminChange::TokenFeeRate { mint, .. }
+            | AdminChange::TokenDirectionFeeRate { mint, .. }
+            | AdminChange::TokenFeeTiers { mint, .. }
+            | AdminChange::TokenAmountLimits { mint, .. } => Some(*mint),
+            _ => None,
+        }
+    }
+
+    fn discount_user(&self) -> Option<Pubkey> {
+        match self {
+            AdminChange::FeeDiscount { user, .. } => Some(*user),
+            _ => None,
+        }
+    }
+
+    fn validate(&self, fee_rate_cap_bp: u16) -> Result<()> {
+        let check_rate = |fee_rate_bp: Option<u16>| -> Result<()> {
+            let fee_rate_bp = fee_rate_bp.unwrap_or(0);
+            require!(fee_rate_bp < 10000, Errors::InvalidFeeRate);
+            require!(fee_rate_bp <= fee_rate_cap_bp, Errors::FeeRateAboveCap);
+            Ok(())
+        };
+        match self {
+            AdminChange::FeeRate { fee_rate_bp } => check_rate(Some(*fee_rate_bp)),
+            AdminChange::DirectionFeeRate {
+                fee_rate_bp_out,
+                fee_rate_bp_in,
+            }
+            | AdminChange::TokenDirectionFeeRate {
+                fee_rate_bp_out,
+                fee_rate_bp_in,
+                ..
+            } => {
+                check_rate(*fee_rate_bp_out)?;
+                check_rate(*fee_rate_bp_in)
+            }
+            AdminChange::TimelockDelay { delay } => {
+                require!(*delay >= 0, Errors::InvalidEffectiveTime);
+                Ok(())
+            }
+            AdminChange::TokenFeeRate { fee_rate_bp, .. } => check_rate(*fee_rate_bp),
+            AdminChange::TokenFeeTiers {
+                fee_tiers,
+                fee_tier_count,
+                ..
+            } => {
+                require!(
+                    *fee_tier_count as usize <= MAX_FEE_TIERS,
+                    Errors::InvalidFeeTiers
+                );
+                helpers::check_fee_tiers(&fee_tiers[..*fee_tier_count as usize], fee_rate_cap_bp)
+            }
+            AdminChange::MaxReferralShare {
+                max_referral_share_bp,
+            } => {
+                require!(
+                    *max_referral_share_bp <= 10000,
+                    Errors::InvalidReferralShare
+                );
+                Ok(())
+            }
+            AdminChange::FeeDiscount { discount_bp, .. } => {
+                require!(*discount_bp <= 10000, Errors::InvalidFeeDiscount);
+                Ok(())
+            }
+            AdminChange::TokenAmountLimits {
+                min_amount,
+                max_amount,
+                ..
+            } => {
+                require!(
+                    *max_amount == 0 || min_amount <= max_amount,
+                    Errors::InvalidAmountLimits
+                );
+                Ok(())
+            }
+            AdminChange::FeeRounding { .. }
+            | AdminChange::TokenMaxFee { .. }
+            | AdminChange::FeeRecepient { .. } => Ok(()),
+        }
+    }
+
+    /// `token_settings` must be the settings of `mint()` for token changes and `fee_discount` the
+    /// discount account of `discount_user()` for discount changes.
+    fn apply(
+        &self,
+        admin_settings: &mut AdminSettings,
+        token_settings: Option<&mut TokenSettings>,
+        fee_discount: Option<&mut FeeDiscount>,
+    ) {
+        match (*self, token_settings) {
+            (AdminChange::FeeRate { fee_rate_bp }, _) => admin_settings.fee_rate_bp = fee_rate_bp,
+            (
+                AdminChange::DirectionFeeRate {
+                    fee_rate_bp_out,
+                    fee_rate_bp_in,
+                },
+                _,
+            ) => {
+                admin_settings.fee_rate_bp_out = fee_rate_bp_out;
+                admin_settings.fee_rate_bp_in = fee_rate_bp_in;
+            }
+            (AdminChange::FeeRounding { fee_rounding }, _) => {
+                admin_settings.fee_rounding = fee_rounding
+            }
+            (AdminChange::TimelockDelay { delay }, _) => admin_settings.timelock_delay = delay,
+            (AdminChange::TokenMaxFee { max_fee, .. }, Some(token_settings)) => {
+                token_settings.max_fee = max_fee
+            }
+            (
+                AdminChange::TokenFeeRate {
+                    fee_rate_bp,
+                    min_fee,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.fee_rate_bp = fee_rate_bp;
+                token_settings.min_fee = min_fee;
+            }
+            (
+                AdminChange::TokenDirectionFeeRate {
+                    fee_rate_bp_out,
+                    fee_rate_bp_in,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.fee_rate_bp_out = fee_rate_bp_out;
+                token_settings.fee_rate_bp_in = fee_rate_bp_in;
+            }
+            (
+                AdminChange::TokenFeeTiers {
+                    fee_tiers,
+                    fee_tier_count,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.fee_tiers = fee_tiers;
+                token_settings.fee_tier_count = fee_tier_count;
+            }
+            (AdminChange::FeeRecepient { fee_recepient }, _) => {
+                admin_settings.fee_recepient = fee_recepient
+            }
+            (
+                AdminChange::MaxReferralShare {
+                    max_referral_share_bp,
+                },
+                _,
+            ) => admin_settings.max_referral_share_bp = max_referral_share_bp,
+            (AdminChange::FeeDiscount { discount_bp, .. }, _) => {
+                if let Some(fee_discount) = fee_discount {
+                    fee_discount.discount_bp = discount_bp;
+                }
+            }
+            (
+                AdminChange::TokenAmountLimits {
+                    min_amount,
+                    max_amount,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.min_amount = min_amount;
+                token_settings.max_amount = max_amount;
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Referral {
+    pub referrer: Pubkey,
+    pub share_bp: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Lock {
+    pub hash: [u8; 32],
+    pub agreement_reached_time: i64,
+    pub expected_single_step_time: i64,
+    pub tolerant_single_step_time: i64,
+    pub earliest_refund_time: i64,
+    pub hash_algorithm: HashAlgorithm,
+    /// Longest preimage accepted by `confirm_with_preimage`, 0 means a fixed 32-byte preimage.
+    pub max_preimage_len: u8,
+}
+
+impl Lock {
+    fn check_refund_time(&self) -> Result<()> {
+        require!(
             self.earliest_refund_time
                 > self.agreement_reached_time
                     + 3 * self.expected_single_step_time
@@ -463,18 +1616,51 @@ impl Lock {
         Ok(())
     }
 
-    fn check_hashlock(&self, hash: &[u8; 32]) -> Result<()> {
+    fn check_max_preimage_len(&self) -> Result<()> {
+        require!(
+            self.max_preimage_len as usize <= MAX_PREIMAGE_LEN,
+            Errors::InvalidPreimageLength
+        );
+        Ok(())
+    }
+
+    fn check_preimage_len(&self, preimage: &[u8]) -> Result<()> {
+        if self.max_preimage_len == 0 {
+            require!(preimage.len() == 32, Errors::InvalidPreimageLength);
+        } else {
+            require!(
+                !preimage.is_empty() && preimage.len() <= self.max_preimage_len as usize,
+                Errors::InvalidPreimageLength
+            );
+        }
+        Ok(())
+    }
+
+    fn check_hashlock(&self, preimage: &[u8]) -> Result<()> {
+        let hash = match self.hash_algorithm {
+            HashAlgorithm::Keccak256 => keccak::hash(preimage).0,
+            HashAlgorithm::Sha256 => hash::hash(preimage).to_bytes(),
+            HashAlgorithm::DoubleSha256 => hash::hash(&hash::hash(preimage).to_bytes()).to_bytes(),
+        };
         require!(hash.eq(&self.hash), Errors::PreimageMismatch);
         Ok(())
     }
 }
 
 #[derive(Accounts)]
+#[instruction(admin: Pubkey, fee_rate_cap_bp: u16)]
 pub struct Initialize<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
 
-    #[account(init, payer = payer, space = size_of::<AdminSettings>() + 8, seeds = [ADMIN_SETTINGS_SEED], bump)]
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + AdminSettings::INIT_SPACE,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = fee_rate_cap_bp <= 10000 @ Errors::InvalidFeeRate,
+    )]
     pub admin_settings: Account<'info, AdminSettings>,
 
     pub system_program: Program<'info, System>,
@@ -490,25 +1676,99 @@ pub struct ChangeAdmin<'info> {
 }
 
 #[derive(Accounts)]
-pub struct SetFeeRecepient<'info> {
+pub struct SetAdminSigners<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetRole<'info> {
     pub admin: Signer<'info>,
-    pub fee_recepient: Signer<'info>,
 
     #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
     pub admin_settings: Account<'info, AdminSettings>,
 }
 
+#[derive(Accounts)]
+pub struct SetFeeRecepient<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: fee recepient
+    pub fee_recepient: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
 #[derive(Accounts)]
 #[instruction(value: u16)]
 pub struct SetFeeRate<'info> {
-    pub admin: Signer<'info>,
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
         seeds = [ADMIN_SETTINGS_SEED],
         bump,
-        has_one = admin @ Errors::AccountMismatch,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
         constraint = value < 10000 @ Errors::InvalidFeeRate,
+        constraint = value <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(fee_rate_bp_out: Option<u16>, fee_rate_bp_in: Option<u16>)]
+pub struct SetDirectionFeeRate<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+        constraint = fee_rate_bp_out.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp_in.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp_out.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+        constraint = fee_rate_bp_in.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeRounding<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(value: u16)]
+pub struct SetMaxReferralShare<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+        constraint = value <= 10000 @ Errors::InvalidReferralShare,
     )]
     pub admin_settings: Account<'info, AdminSettings>,
 }
@@ -518,14 +1778,19 @@ pub struct SetFeeRate<'info> {
 pub struct SetMaxFeeForToken<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    pub admin: Signer<'info>,
+    pub authority: Signer<'info>,
 
-    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
     pub admin_settings: Account<'info, AdminSettings>,
     #[account(
         init_if_needed,
         payer = payer,
-        space = size_of::<TokenSettings>() + 8,
+        space = 8 + TokenSettings::INIT_SPACE,
         seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
         bump,
     )]
@@ -535,109 +1800,840 @@ pub struct SetMaxFeeForToken<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(uuid: [u8; 32])]
-pub struct Prepare<'info> {
+#[instruction(mint: Pubkey, fee_rate_bp: Option<u16>)]
+pub struct SetFeeRateForToken<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut)]
-    pub from: Signer<'info>,
-
-    pub mint: Option<Account<'info, Mint>>,
-    #[account(mut)]
-    pub source: Option<Account<'info, TokenAccount>>,
-
-    #[account(init, payer = payer, space = size_of::<Escrow>() + 8, seeds = [&uuid], bump)]
-    pub escrow: Account<'info, Escrow>,
-    #[account(init, payer = payer, associated_token::mint = mint, associated_token::authority = escrow)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    pub authority: Signer<'info>,
 
-    #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
     pub admin_settings: Account<'info, AdminSettings>,
-    pub token_settings: Option<Account<'info, TokenSettings>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        constraint = fee_rate_bp.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
 
-    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
 }
 
 #[derive(Accounts)]
-#[instruction(uuid: [u8; 32], preimage: [u8; 32], is_out: bool)]
-pub struct Confirm<'info> {
+#[instruction(mint: Pubkey, fee_rate_bp_out: Option<u16>, fee_rate_bp_in: Option<u16>)]
+pub struct SetDirectionFeeRateForToken<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut)]
-    pub from: SystemAccount<'info>,
-    /// CHECK: value recepient
-    #[account(mut)]
-    pub to: UncheckedAccount<'info>,
-    #[account(mut, associated_token::mint = escrow.mint, associated_token::authority = escrow.to)]
-    pub destination: Option<Account<'info, TokenAccount>>,
+    pub authority: Signer<'info>,
 
     #[account(
-        mut,
-        seeds=[&uuid],
+        seeds = [ADMIN_SETTINGS_SEED],
         bump,
-        has_one = from @ Errors::AccountMismatch,
-        has_one = to @ Errors::AccountMismatch,
-        constraint = escrow.is_out == is_out @ Errors::InvalidDirection,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        constraint = fee_rate_bp_out.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp_in.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp_out.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+        constraint = fee_rate_bp_in.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct SetFeeTiersForToken<'info> {
     #[account(mut)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
 
-    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = fee_recepient)]
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
     pub admin_settings: Account<'info, AdminSettings>,
-    /// CHECK: fee recepient
-    #[account(mut)]
-    pub fee_recepient: UncheckedAccount<'info>,
-    #[account(mut, associated_token::mint = escrow.mint, associated_token::authority = admin_settings.fee_recepient)]
-    pub fee_destination: Option<Account<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
 }
 
 #[derive(Accounts)]
-#[instruction(uuid: [u8; 32], is_out: bool)]
-pub struct Refund<'info> {
-    #[account(mut)]
-    pub from: SystemAccount<'info>,
+#[instruction(user: Pubkey, discount_bp: u16)]
+pub struct SetFeeDiscount<'info> {
     #[account(mut)]
-    pub source: Option<Account<'info, TokenAccount>>,
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
 
     #[account(
-        mut,
-        seeds=[&uuid],
+        seeds = [ADMIN_SETTINGS_SEED],
         bump,
-        has_one = from @ Errors::AccountMismatch,
-        constraint = escrow.is_out == is_out @ Errors::InvalidDirection,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
     )]
-    pub escrow: Account<'info, Escrow>,
-    #[account(mut)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + FeeDiscount::INIT_SPACE,
+        seeds = [FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()],
+        bump,
+        constraint = discount_bp <= 10000 @ Errors::InvalidFeeDiscount,
+    )]
+    pub fee_discount: Account<'info, FeeDiscount>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
 }
 
-#[account]
-pub struct AdminSettings {
-    pub admin: Pubkey,
-    pub fee_recepient: Pubkey,
-    pub fee_rate_bp: u16,
-}
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct RemoveFeeDiscount<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
 
-#[account]
-pub struct TokenSettings {
-    pub max_fee: u64,
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(mut, close = authority, seeds = [FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()], bump)]
+    pub fee_discount: Account<'info, FeeDiscount>,
 }
 
-#[account]
-pub struct Escrow {
-    pub from: Pubkey,
-    pub to: Pubkey,
-    pub token_program: Pubkey,
-    pub mint: Pubkey,
+#[derive(Accounts)]
+#[instruction(mint: Pubkey, min_amount: u64, max_amount: u64)]
+pub struct SetAmountLimitsForToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        constraint = max_amount == 0 || min_amount <= max_amount @ Errors::InvalidAmountLimits,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct RegisterToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct DeregisterToken<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(mut, seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()], bump)]
+    pub token_settings: Account<'info, TokenSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(value: i64)]
+pub struct SetPreimageRetention<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        has_one = admin @ Errors::AccountMismatch,
+        constraint = value > 0 @ Errors::InvalidRetentionPeriod,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(delay: i64)]
+pub struct SetTimelockDelay<'info> {
+    pub admin: Signer<'info>,
+
+    /// Shortening the delay has to wait out the current one through `queue_admin_change`.
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        has_one = admin @ Errors::AccountMismatch,
+        constraint = delay >= admin_settings.timelock_delay @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64, change: AdminChange)]
+pub struct QueueAdminChange<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.can_change(&change, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PendingChange::INIT_SPACE,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    /// Opened empty for `FeeDiscount` changes, the permissionless execute cannot pay for it.
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + FeeDiscount::INIT_SPACE,
+        seeds = [FEE_DISCOUNT_SEED_PREFIX, &change.discount_user().unwrap_or_default().to_bytes()],
+        bump,
+    )]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct ExecuteAdminChange<'info> {
+    /// CHECK: rent receiver, checked against `pending_change.payer`
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        mut,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+        has_one = payer @ Errors::AccountMismatch,
+        close = payer,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    #[account(mut)]
+    pub token_settings: Option<Account<'info, TokenSettings>>,
+    #[account(mut)]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct CancelAdminChange<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: rent receiver, checked against `pending_change.payer`
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        mut,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+        has_one = payer @ Errors::AccountMismatch,
+        constraint = admin_settings.can_change(&pending_change.change, &authority.key()) @ Errors::AccountMismatch,
+        close = payer,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAdminSettings<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub admin: Signer<'info>,
+
+    /// CHECK: legacy layout, checked and upgraded by `migrate_admin_settings`
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, owner = crate::ID)]
+    pub admin_settings: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct MigrateTokenSettings<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    /// CHECK: legacy layout, checked and upgraded by `migrate_token_settings`
+    #[account(
+        mut,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        owner = crate::ID,
+    )]
+    pub token_settings: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(uuid: [u8; 32])]
+pub struct MigrateEscrow<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: legacy layout, checked and upgraded by `migrate_escrow`
+    #[account(mut, seeds = [&uuid], bump, owner = crate::ID)]
+    pub escrow: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetPause<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::Pauser, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        has_one = fee_recepient @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    /// CHECK: fee recepient
+    #[account(mut)]
+    pub fee_recepient: UncheckedAccount<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(uuid: [u8; 32])]
+pub struct Prepare<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub from: Signer<'info>,
+
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub source: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(init, payer = payer, space = 8 + Escrow::INIT_SPACE, seeds = [&uuid], bump)]
+    pub escrow: Account<'info, Escrow>,
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = escrow,
+        associated_token::token_program = token_program,
+    )]
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    pub token_settings: Account<'info, TokenSettings>,
+    pub sol_settings: Option<Account<'info, TokenSettings>>,
+    #[account(seeds = [FEE_DISCOUNT_SEED_PREFIX, from.key().as_ref()], bump)]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+    /// CHECK: referrer of the escrow, only checked to be able to claim
+    pub referrer: Option<UncheckedAccount<'info>>,
+
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(uuid: [u8; 32])]
+pub struct Confirm<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(mut)]
+    pub from: SystemAccount<'info>,
+    /// CHECK: value recepient
+    #[account(mut)]
+    pub to: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        associated_token::mint = escrow.mint,
+        associated_token::authority = escrow.to,
+        associated_token::token_program = token_program,
+    )]
+    pub destination: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds=[&uuid],
+        bump,
+        has_one = from @ Errors::AccountMismatch,
+        has_one = to @ Errors::AccountMismatch,
+    )]
+    pub escrow: Account<'info, AnyVersionEscrow>,
+    #[account(mut)]
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = escrow.mint @ Errors::AccountMismatch)]
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = admin_settings,
+        associated_token::token_program = token_program,
+    )]
+    pub fee_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + ReferralVault::INIT_SPACE,
+        seeds = [REFERRAL_VAULT_SEED_PREFIX, escrow.referrer.as_ref()],
+        bump,
+        constraint = escrow.has_referrer() @ Errors::InvalidAccount,
+    )]
+    pub referral_vault: Option<Account<'info, ReferralVault>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = referral_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub referral_vault_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + PreimageRecord::INIT_SPACE,
+        seeds = [PREIMAGE_RECORD_SEED_PREFIX, &escrow.lock.hash],
+        bump,
+    )]
+    pub preimage_record: Option<Account<'info, PreimageRecord>>,
+
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub system_program: Program<'info, System>,
+    #[account(address = escrow.token_program @ Errors::AccountMismatch)]
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(uuid: [u8; 32], is_out: bool)]
+pub struct Refund<'info> {
+    #[account(mut)]
+    pub from: SystemAccount<'info>,
+    #[account(
+        mut,
+        constraint = source.owner == escrow.from && source.mint == escrow.mint @ Errors::AccountMismatch,
+    )]
+    pub source: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds=[&uuid],
+        bump,
+        has_one = from @ Errors::AccountMismatch,
+        constraint = escrow.is_out == is_out @ Errors::InvalidDirection,
+    )]
+    pub escrow: Account<'info, AnyVersionEscrow>,
+    #[account(mut)]
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = escrow.mint @ Errors::AccountMismatch)]
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+
+    pub system_program: Program<'info, System>,
+    #[account(address = escrow.token_program @ Errors::AccountMismatch)]
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRAL_VAULT_SEED_PREFIX, referrer.key().as_ref()],
+        bump,
+        has_one = referrer @ Errors::AccountMismatch,
+    )]
+    pub referral_vault: Account<'info, ReferralVault>,
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = referral_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub referral_vault_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[derive(Accounts)]
+#[instruction(hash: [u8; 32])]
+pub struct ClosePreimageRecord<'info> {
+    /// CHECK: rent payer of the record, receives the lamports back
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [PREIMAGE_RECORD_SEED_PREFIX, &hash],
+        bump,
+        has_one = payer @ Errors::AccountMismatch,
+        close = payer,
+    )]
+    pub preimage_record: Account<'info, PreimageRecord>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct AdminSettings {
+    pub version: u8,
+    pub admin: Pubkey,
+    pub fee_recepient: Pubkey,
+    pub fee_rate_bp: u16,
+    pub preimage_retention_period: i64,
+    /// Blocks new escrows in both directions, confirm and refund stay available.
+    pub paused: bool,
+    pub paused_out: bool,
+    pub paused_in: bool,
+    /// Direction specific rates, falling back to `fee_rate_bp` when unset.
+    pub fee_rate_bp_out: Option<u16>,
+    pub fee_rate_bp_in: Option<u16>,
+    /// Upper bound for the fee share a referrer can be given on new escrows.
+    pub max_referral_share_bp: u16,
+    /// Rounding applied to fee rates, `Ceil` keeps split orders from paying less.
+    pub fee_rounding: FeeRounding,
+    /// Admin instructions also need `admin_threshold` of these keys to sign, 0 disables the check.
+    pub admin_signers: [Pubkey; MAX_ADMIN_SIGNERS],
+    pub admin_signer_count: u8,
+    pub admin_threshold: u8,
+    /// Role keys, `admin` acts as super-admin and holds every role.
+    pub fee_manager: Pubkey,
+    pub token_manager: Pubkey,
+    pub pauser: Pubkey,
+    /// Minimum wait for queued fee changes, direct fee setters are disabled while non-zero.
+    pub timelock_delay: i64,
+    /// Set at initialize and never updated, no fee can exceed this share of the amount.
+    pub fee_rate_cap_bp: u16,
+    pub reserved: [u8; 256],
+}
+
+impl Default for AdminSettings {
+    /// An account as `initialize` leaves it before the admin and fee cap are set.
+    fn default() -> Self {
+        Self {
+            version: ACCOUNT_VERSION,
+            admin: Pubkey::default(),
+            fee_recepient: Pubkey::default(),
+            fee_rate_bp: 0,
+            preimage_retention_period: DEFAULT_PREIMAGE_RETENTION_PERIOD,
+            paused: false,
+            paused_out: false,
+            paused_in: false,
+            fee_rate_bp_out: None,
+            fee_rate_bp_in: None,
+            max_referral_share_bp: 0,
+            fee_rounding: FeeRounding::Floor,
+            admin_signers: [Pubkey::default(); MAX_ADMIN_SIGNERS],
+            admin_signer_count: 0,
+            admin_threshold: 0,
+            fee_manager: Pubkey::default(),
+            token_manager: Pubkey::default(),
+            pauser: Pubkey::default(),
+            timelock_delay: 0,
+            fee_rate_cap_bp: 0,
+            reserved: [0; 256],
+        }
+    }
+}
+
+impl AdminSettings {
+    fn has_role(&self, role: Role, key: &Pubkey) -> bool {
+        let role_key = match role {
+            Role::FeeManager => self.fee_manager,
+            Role::TokenManager => self.token_manager,
+            Role::Pauser => self.pauser,
+        };
+        *key == self.admin || *key == role_key
+    }
+
+    fn can_change(&self, change: &AdminChange, key: &Pubkey) -> bool {
+        match change.role() {
+            Some(role) => self.has_role(role, key),
+            None => *key == self.admin,
+        }
+    }
+
+    /// Counts `admin` and the signing remaining accounts that belong to the signer set.
+    fn check_admin_approvals(&self, admin: &Pubkey, co_signers: &[AccountInfo]) -> Result<()> {
+        let members = &self.admin_signers[..self.admin_signer_count as usize];
+        let signers = co_signers
+            .iter()
+            .filter(|account| account.is_signer)
+            .map(|account| account.key);
+        let mut approvals: Vec<&Pubkey> = Vec::new();
+        for signer in std::iter::once(admin).chain(signers) {
+            if members.contains(signer) && !approvals.contains(&signer) {
+                approvals.push(signer);
+            }
+        }
+        require!(
+            approvals.len() >= self.admin_threshold as usize,
+            Errors::NotEnoughAdminSigners
+        );
+        Ok(())
+    }
+
+    /// The referrer claims its shares later, so it has to be a key that can sign.
+    fn check_referral(
+        &self,
+        referral: Option<&Referral>,
+        referrer: Option<&AccountInfo>,
+    ) -> Result<()> {
+        if let Some(referral) = referral {
+            require!(
+                referral.share_bp <= self.max_referral_share_bp,
+                Errors::InvalidReferralShare
+            );
+            if referral.share_bp > 0 {
+                let referrer = referrer.ok_or(Errors::InvalidReferrer)?;
+                require!(
+                    referral.referrer != Pubkey::default()
+                        && referrer.key() == referral.referrer
+                        && !referrer.executable,
+                    Errors::InvalidReferrer
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn fee_rate_bp_for(&self, is_out: bool) -> u16 {
+        let direction_rate = if is_out {
+            self.fee_rate_bp_out
+        } else {
+            self.fee_rate_bp_in
+        };
+        direction_rate.unwrap_or(self.fee_rate_bp)
+    }
+
+    fn check_not_paused(&self, is_out: bool) -> Result<()> {
+        let direction_paused = if is_out {
+            self.paused_out
+        } else {
+            self.paused_in
+        };
+        require!(!self.paused && !direction_paused, Errors::Paused);
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct FeeTier {
+    pub min_amount: u64,
+    pub fee_rate_bp: u16,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct TokenSettings {
+    pub version: u8,
+    pub max_fee: u64,
+    /// Overrides the rates in `AdminSettings` when set and neither a fee tier nor a direction
+    /// override applies.
+    pub fee_rate_bp: Option<u16>,
+    pub min_fee: u64,
+    /// Only registered tokens, or native SOL under the zero key, can be escrowed.
+    pub enabled: bool,
+    pub min_amount: u64,
+    /// 0 means no upper limit.
+    pub max_amount: u64,
+    /// Direction specific overrides, taking precedence over `fee_rate_bp` but not over a matching
+    /// fee tier.
+    pub fee_rate_bp_out: Option<u16>,
+    pub fee_rate_bp_in: Option<u16>,
+    /// Volume pricing, `fee_tier_count` tiers sorted by ascending `min_amount`.
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    pub fee_tier_count: u8,
+    pub reserved: [u8; 128],
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            version: ACCOUNT_VERSION,
+            max_fee: 0,
+            fee_rate_bp: None,
+            min_fee: 0,
+            enabled: false,
+            min_amount: 0,
+            max_amount: 0,
+            fee_rate_bp_out: None,
+            fee_rate_bp_in: None,
+            fee_tiers: [FeeTier::default(); MAX_FEE_TIERS],
+            fee_tier_count: 0,
+            reserved: [0; 128],
+        }
+    }
+}
+
+impl TokenSettings {
+    fn tier_fee_rate_bp(&self, amount: u64) -> Option<u16> {
+        self.fee_tiers[..self.fee_tier_count as usize]
+            .iter()
+            .rev()
+            .find(|tier| amount >= tier.min_amount)
+            .map(|tier| tier.fee_rate_bp)
+    }
+
+    /// The matching fee tier wins, then the direction override, then `fee_rate_bp`. `None` falls
+    /// back to the rates in `AdminSettings`.
+    fn fee_rate_bp_for(&self, amount: u64, is_out: bool) -> Option<u16> {
+        let direction_rate = if is_out {
+            self.fee_rate_bp_out
+        } else {
+            self.fee_rate_bp_in
+        };
+        self.tier_fee_rate_bp(amount)
+            .or(direction_rate)
+            .or(self.fee_rate_bp)
+    }
+
+    fn check_amount(&self, amount: u64) -> Result<()> {
+        require!(
+            amount >= self.min_amount && (self.max_amount == 0 || amount <= self.max_amount),
+            Errors::AmountOutOfRange
+        );
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct FeeDiscount {
+    /// Share of the fee waived for the user, 10000 means fee exempt.
+    pub discount_bp: u16,
+}
+
+/// Holds the referral shares of `referrer`, SOL as lamports and tokens in its associated token accounts.
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralVault {
+    pub referrer: Pubkey,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct PreimageRecord {
+    pub hash: [u8; 32],
+    pub preimage: [u8; MAX_PREIMAGE_LEN],
+    pub preimage_len: u8,
+    pub payer: Pubkey,
+    pub recorded_time: i64,
+}
+
+/// Escrows snapshot their fees at prepare, so the change only affects later ones.
+#[account]
+#[derive(InitSpace)]
+pub struct PendingChange {
+    pub change: AdminChange,
+    pub effective_time: i64,
+    /// Gets the rent back once the change is executed or cancelled.
+    pub payer: Pubkey,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Escrow {
+    pub version: u8,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub token_program: Pubkey,
+    pub mint: Pubkey,
     pub source: Pubkey,
     pub escrow_ata: Pubkey,
     pub sol_amount: u64,
@@ -646,4 +2642,649 @@ pub struct Escrow {
     pub token_fee: u64,
     pub lock: Lock,
     pub is_out: bool,
+    /// Receives `referral_share_bp` of the fees on confirm, zero when there is no referrer.
+    pub referrer: Pubkey,
+    pub referral_share_bp: u16,
+    pub fee_mode: FeeMode,
+    pub reserved: [u8; 64],
+}
+
+impl Escrow {
+    fn has_referrer(&self) -> bool {
+        self.referral_share_bp > 0 && self.referrer != Pubkey::default()
+    }
+
+    fn referral_fee(&self, fee: u64) -> Result<u64> {
+        helpers::mul_bp(fee, self.referral_share_bp, FeeRounding::Floor)
+    }
+}
+
+/// An `Escrow` of the current or the legacy layout, legacy ones upgraded in memory. Confirm and
+/// refund close the escrow, so a legacy escrow settles without being migrated first and the
+/// upgraded value is never written back.
+#[derive(Clone)]
+pub struct AnyVersionEscrow(Escrow);
+
+impl AccountDeserialize for AnyVersionEscrow {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if buf.len() == <legacy::Escrow as legacy::LegacyAccount>::LEN {
+            return Ok(Self(legacy::read::<legacy::Escrow, Escrow>(buf)?.into()));
+        }
+        Escrow::try_deserialize(buf).map(Self)
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        Escrow::try_deserialize_unchecked(buf).map(Self)
+    }
+}
+
+impl AccountSerialize for AnyVersionEscrow {
+    fn try_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        self.0.try_serialize(writer)
+    }
+}
+
+impl Owner for AnyVersionEscrow {
+    fn owner() -> Pubkey {
+        Escrow::owner()
+    }
+}
+
+impl Deref for AnyVersionEscrow {
+    type Target = Escrow;
+
+    fn deref(&self) -> &Escrow {
+        &self.0
+    }
+}
+
+impl DerefMut for AnyVersionEscrow {
+    fn deref_mut(&mut self) -> &mut Escrow {
+        &mut self.0
+    }
+}
+
+#[event]
+pub struct Prepared {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub mint: Pubkey,
+    pub sol_amount: u64,
+    pub token_amount: u64,
+    pub sol_fee: u64,
+    pub token_fee: u64,
+    pub lock: Lock,
+    pub is_out: bool,
+    pub fee_mode: FeeMode,
+}
+
+#[event]
+pub struct Confirmed {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub mint: Pubkey,
+    pub sol_amount: u64,
+    pub token_amount: u64,
+    pub sol_fee: u64,
+    pub token_fee: u64,
+    pub lock: Lock,
+    pub is_out: bool,
+    pub preimage: Vec<u8>,
+    pub fee_mode: FeeMode,
+    pub referrer: Pubkey,
+    /// Shares of `sol_fee` and `token_fee` accrued to the referral vault of `referrer`.
+    pub sol_referral_fee: u64,
+    pub token_referral_fee: u64,
+}
+
+#[event]
+pub struct Refunded {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub mint: Pubkey,
+    pub sol_amount: u64,
+    pub token_amount: u64,
+    pub sol_fee: u64,
+    pub token_fee: u64,
+    pub lock: Lock,
+    pub is_out: bool,
+    pub fee_mode: FeeMode,
+    pub referrer: Pubkey,
+}
+
+/// Layouts of the first, unversioned release. Their accounts count as version 0.
+mod legacy {
+    use super::*;
+
+    #[derive(AnchorDeserialize)]
+    pub struct AdminSettings {
+        pub admin: Pubkey,
+        pub fee_recepient: Pubkey,
+        pub fee_rate_bp: u16,
+    }
+
+    #[derive(AnchorDeserialize)]
+    pub struct TokenSettings {
+        pub max_fee: u64,
+    }
+
+    #[derive(AnchorDeserialize)]
+    pub struct Lock {
+        pub hash: [u8; 32],
+        pub agreement_reached_time: i64,
+        pub expected_single_step_time: i64,
+        pub tolerant_single_step_time: i64,
+        pub earliest_refund_time: i64,
+    }
+
+    #[derive(AnchorDeserialize)]
+    pub struct Escrow {
+        pub from: Pubkey,
+        pub to: Pubkey,
+        pub token_program: Pubkey,
+        pub mint: Pubkey,
+        pub source: Pubkey,
+        pub escrow_ata: Pubkey,
+        pub sol_amount: u64,
+        pub token_amount: u64,
+        pub sol_fee: u64,
+        pub token_fee: u64,
+        pub lock: Lock,
+        pub is_out: bool,
+    }
+
+    impl AdminSettings {
+        /// The legacy program had no fee cap, so the migrating admin picks one that covers the current rate.
+        pub fn upgrade(self, fee_rate_cap_bp: u16) -> Result<super::AdminSettings> {
+            require!(
+                fee_rate_cap_bp >= self.fee_rate_bp && fee_rate_cap_bp <= 10000,
+                Errors::InvalidFeeRate
+            );
+            Ok(super::AdminSettings {
+                admin: self.admin,
+                fee_recepient: self.fee_recepient,
+                fee_rate_bp: self.fee_rate_bp,
+                fee_rate_cap_bp,
+                ..Default::default()
+            })
+        }
+    }
+
+    impl From<TokenSettings> for super::TokenSettings {
+        fn from(legacy: TokenSettings) -> Self {
+            Self {
+                max_fee: legacy.max_fee,
+                // the legacy program accepted every mint it had settings for
+                enabled: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<Escrow> for super::Escrow {
+        fn from(legacy: Escrow) -> Self {
+            Self {
+                version: ACCOUNT_VERSION,
+                from: legacy.from,
+                to: legacy.to,
+                token_program: legacy.token_program,
+                mint: legacy.mint,
+                source: legacy.source,
+                escrow_ata: legacy.escrow_ata,
+                sol_amount: legacy.sol_amount,
+                token_amount: legacy.token_amount,
+                sol_fee: legacy.sol_fee,
+                token_fee: legacy.token_fee,
+                lock: super::Lock {
+                    hash: legacy.lock.hash,
+                    agreement_reached_time: legacy.lock.agreement_reached_time,
+                    expected_single_step_time: legacy.lock.expected_single_step_time,
+                    tolerant_single_step_time: legacy.lock.tolerant_single_step_time,
+                    earliest_refund_time: legacy.lock.earliest_refund_time,
+                    hash_algorithm: HashAlgorithm::Keccak256,
+                    max_preimage_len: 0,
+                },
+                is_out: legacy.is_out,
+                referrer: Pubkey::default(),
+                referral_share_bp: 0,
+                fee_mode: FeeMode::Deducted,
+                reserved: [0; 64],
+            }
+        }
+    }
+
+    /// A layout of the first release and the length its accounts were allocated with,
+    /// discriminator included.
+    pub trait LegacyAccount: AnchorDeserialize {
+        const LEN: usize;
+    }
+
+    impl LegacyAccount for AdminSettings {
+        const LEN: usize = 74;
+    }
+
+    impl LegacyAccount for TokenSettings {
+        const LEN: usize = 16;
+    }
+
+    impl LegacyAccount for Escrow {
+        const LEN: usize = 304;
+    }
+
+    /// Reads `data` as a legacy `L` account of the current type `T`.
+    ///
+    /// Accounts of exactly `L::LEN` bytes are legacy, accounts of any other size carry their
+    /// layout version right after the discriminator.
+    pub fn read<L, T>(data: &[u8]) -> Result<L>
+    where
+        L: LegacyAccount,
+        T: anchor_lang::Discriminator,
+    {
+        require!(
+            data.starts_with(&T::DISCRIMINATOR),
+            anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+        );
+        let version = if data.len() == L::LEN {
+            0
+        } else {
+            data.get(8).copied().unwrap_or_default()
+        };
+        require!(version < ACCOUNT_VERSION, Errors::AlreadyMigrated);
+        Ok(L::deserialize(&mut &data[8..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::Discriminator;
+    use helpers::{calculate_fee, has_transfer_fee, has_transfer_hook, mul_bp};
+    use proptest::prelude::*;
+
+    fn admin_settings(fee_rate_bp: u16, fee_rounding: FeeRounding) -> AdminSettings {
+        AdminSettings {
+            fee_rate_bp,
+            fee_rounding,
+            fee_rate_cap_bp: 10000,
+            ..Default::default()
+        }
+    }
+
+    fn token_settings(min_fee: u64, max_fee: u64) -> TokenSettings {
+        TokenSettings {
+            max_fee,
+            min_fee,
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn fee_rounding() -> impl Strategy<Value = FeeRounding> {
+        prop_oneof![Just(FeeRounding::Floor), Just(FeeRounding::Ceil)]
+    }
+
+    #[test]
+    fn fee_on_max_amount_does_not_overflow() {
+        let expected_floor = (u64::MAX as u128 * 9999 / 10000) as u64;
+        let token_settings = token_settings(0, 0);
+        let floor = calculate_fee(
+            u64::MAX,
+            &admin_settings(9999, FeeRounding::Floor),
+            &token_settings,
+            true,
+        )
+        .unwrap();
+        let ceil = calculate_fee(
+            u64::MAX,
+            &admin_settings(9999, FeeRounding::Ceil),
+            &token_settings,
+            true,
+        )
+        .unwrap();
+        assert_eq!(floor, expected_floor);
+        assert_eq!(ceil, expected_floor + 1);
+    }
+
+    #[test]
+    fn mul_bp_rejects_rates_above_one_hundred_percent() {
+        assert!(mul_bp(u64::MAX, 10001, FeeRounding::Floor).is_err());
+        assert!(mul_bp(1, u16::MAX, FeeRounding::Ceil).is_err());
+        assert_eq!(
+            mul_bp(u64::MAX, 10000, FeeRounding::Ceil).unwrap(),
+            u64::MAX
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn mul_bp_rounds_to_nearest_bound(amount: u64, bp in 0u16..=10000) {
+            let exact = amount as u128 * bp as u128;
+            let floor = mul_bp(amount, bp, FeeRounding::Floor).unwrap();
+            let ceil = mul_bp(amount, bp, FeeRounding::Ceil).unwrap();
+            prop_assert!(floor as u128 * 10000 <= exact);
+            prop_assert!(ceil as u128 * 10000 >= exact);
+            prop_assert!(ceil - floor <= 1);
+            prop_assert_eq!(ceil == floor, floor as u128 * 10000 == exact);
+            prop_assert!(ceil <= amount);
+        }
+
+        #[test]
+        fn fee_stays_within_bounds(
+            amount: u64,
+            fee_rate_bp in 0u16..10000,
+            min_fee: u64,
+            max_fee: u64,
+            is_out: bool,
+            fee_rounding in fee_rounding(),
+        ) {
+            let fee = calculate_fee(
+                amount,
+                &admin_settings(fee_rate_bp, fee_rounding),
+                &token_settings(min_fee, max_fee),
+                is_out,
+            ).unwrap();
+            prop_assert!(fee <= amount);
+            if max_fee > 0 {
+                prop_assert!(fee <= max_fee);
+            }
+            let floor = if max_fee > 0 { min_fee.min(max_fee) } else { min_fee };
+            prop_assert!(fee >= floor.min(amount));
+        }
+
+        #[test]
+        fn fee_never_exceeds_protocol_cap(
+            amount: u64,
+            fee_rate_bp in 0u16..10000,
+            fee_rate_cap_bp in 0u16..=10000,
+            min_fee: u64,
+            max_fee: u64,
+            is_out: bool,
+            fee_rounding in fee_rounding(),
+        ) {
+            let mut admin_settings = admin_settings(fee_rate_bp, fee_rounding);
+            admin_settings.fee_rate_cap_bp = fee_rate_cap_bp;
+            let fee = calculate_fee(
+                amount,
+                &admin_settings,
+                &token_settings(min_fee, max_fee),
+                is_out,
+            ).unwrap();
+            prop_assert!(fee <= mul_bp(amount, fee_rate_cap_bp, fee_rounding).unwrap());
+        }
+
+        #[test]
+        fn ceil_fee_is_at_most_one_above_floor(
+            amount: u64,
+            fee_rate_bp in 0u16..10000,
+            is_out: bool,
+        ) {
+            let token_settings = token_settings(0, 0);
+            let floor_settings = admin_settings(fee_rate_bp, FeeRounding::Floor);
+            let ceil_settings = admin_settings(fee_rate_bp, FeeRounding::Ceil);
+            let floor = calculate_fee(amount, &floor_settings, &token_settings, is_out).unwrap();
+            let ceil = calculate_fee(amount, &ceil_settings, &token_settings, is_out).unwrap();
+            prop_assert!(floor <= ceil && ceil - floor <= 1);
+        }
+
+        #[test]
+        fn ceil_fee_cannot_be_reduced_by_splitting(
+            first in 0u64..u64::MAX / 2,
+            second in 0u64..u64::MAX / 2,
+            fee_rate_bp in 0u16..10000,
+            is_out: bool,
+        ) {
+            let admin_settings = admin_settings(fee_rate_bp, FeeRounding::Ceil);
+            let token_settings = token_settings(0, 0);
+            let split = calculate_fee(first, &admin_settings, &token_settings, is_out).unwrap()
+                + calculate_fee(second, &admin_settings, &token_settings, is_out).unwrap();
+            let whole = calculate_fee(first + second, &admin_settings, &token_settings, is_out).unwrap();
+            prop_assert!(split >= whole);
+        }
+
+        #[test]
+        fn tiers_apply_before_direction_overrides(
+            amount: u64,
+            tier_min_amount: u64,
+            tier_fee_rate_bp in 0u16..10000,
+            out_fee_rate_bp in 0u16..10000,
+            is_out: bool,
+            fee_rounding in fee_rounding(),
+        ) {
+            let admin_settings = admin_settings(0, fee_rounding);
+            let mut token_settings = token_settings(0, 0);
+            token_settings.fee_tiers[0] = FeeTier {
+                min_amount: tier_min_amount,
+                fee_rate_bp: tier_fee_rate_bp,
+            };
+            token_settings.fee_tier_count = 1;
+            token_settings.fee_rate_bp_out = Some(out_fee_rate_bp);
+
+            let expected_rate_bp = if amount >= tier_min_amount {
+                tier_fee_rate_bp
+            } else if is_out {
+                out_fee_rate_bp
+            } else {
+                0
+            };
+            let fee = calculate_fee(amount, &admin_settings, &token_settings, is_out).unwrap();
+            prop_assert_eq!(fee, mul_bp(amount, expected_rate_bp, fee_rounding).unwrap());
+        }
+    }
+
+    /// Account data as the first release allocated and wrote it: `len` bytes holding the
+    /// discriminator and the Borsh encoded `fields`, zero padded.
+    fn baseline_account_data(discriminator: [u8; 8], len: usize, fields: &[&[u8]]) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        fields
+            .iter()
+            .for_each(|field| data.extend_from_slice(field));
+        assert!(data.len() <= len);
+        data.resize(len, 0);
+        data
+    }
+
+    /// Packs an initialized Token-2022 mint, with a transfer hook when `hook_program` is set.
+    fn token_2022_mint_data(hook_program: Option<Pubkey>, transfer_fee_bp: Option<u16>) -> Vec<u8> {
+        use spl_token_2022::extension::{ExtensionType, StateWithExtensionsMut};
+        let mut extensions = vec![];
+        if hook_program.is_some() {
+            extensions.push(ExtensionType::TransferHook);
+        }
+        if transfer_fee_bp.is_some() {
+            extensions.push(ExtensionType::TransferFeeConfig);
+        }
+        let len =
+            ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&extensions)
+                .unwrap();
+        let mut data = vec![0; len];
+        let mut state =
+            StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data)
+                .unwrap();
+        if let Some(program_id) = hook_program {
+            let hook = state
+                .init_extension::<transfer_hook::TransferHook>(true)
+                .unwrap();
+            hook.program_id = Some(program_id).try_into().unwrap();
+        }
+        if let Some(fee_bp) = transfer_fee_bp {
+            let config = state
+                .init_extension::<transfer_fee::TransferFeeConfig>(true)
+                .unwrap();
+            config.newer_transfer_fee.transfer_fee_basis_points = fee_bp.into();
+        }
+        state.base = spl_token_2022::state::Mint {
+            decimals: 6,
+            is_initialized: true,
+            ..Default::default()
+        };
+        state.pack_base();
+        state.init_account_type().unwrap();
+        data
+    }
+
+    /// Writes `value` into an account of the current size and reads it back.
+    fn reload<T: AccountSerialize + AccountDeserialize + Space>(value: &T) -> (Vec<u8>, T) {
+        let mut data = vec![0; 8 + T::INIT_SPACE];
+        value.try_serialize(&mut &mut data[..]).unwrap();
+        let reloaded = T::try_deserialize(&mut &data[..]).unwrap();
+        (data, reloaded)
+    }
+
+    #[test]
+    fn migrate_baseline_admin_settings() {
+        let admin = Pubkey::new_from_array([1; 32]);
+        let fee_recepient = Pubkey::new_from_array([2; 32]);
+        let data = baseline_account_data(
+            AdminSettings::DISCRIMINATOR,
+            74,
+            &[admin.as_ref(), fee_recepient.as_ref(), &30u16.to_le_bytes()],
+        );
+        let read = || legacy::read::<legacy::AdminSettings, AdminSettings>(&data).unwrap();
+
+        // the cap has to cover the rate the account already charges
+        assert_eq!(
+            read().upgrade(29).err(),
+            Some(Errors::InvalidFeeRate.into())
+        );
+        assert_eq!(
+            read().upgrade(10001).err(),
+            Some(Errors::InvalidFeeRate.into())
+        );
+        let (migrated, admin_settings) = reload(&read().upgrade(100).unwrap());
+
+        assert!(migrated.len() > data.len());
+        assert_eq!(admin_settings.version, ACCOUNT_VERSION);
+        assert_eq!(admin_settings.admin, admin);
+        assert_eq!(admin_settings.fee_recepient, fee_recepient);
+        assert_eq!(admin_settings.fee_rate_bp, 30);
+        assert_eq!(admin_settings.fee_rate_cap_bp, 100);
+        assert_eq!(
+            admin_settings.preimage_retention_period,
+            DEFAULT_PREIMAGE_RETENTION_PERIOD
+        );
+        assert_eq!(admin_settings.fee_rounding, FeeRounding::Floor);
+        assert_eq!(admin_settings.admin_threshold, 0);
+        assert_eq!(
+            legacy::read::<legacy::AdminSettings, AdminSettings>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+    }
+
+    #[test]
+    fn migrate_baseline_token_settings() {
+        let data = baseline_account_data(TokenSettings::DISCRIMINATOR, 16, &[&50u64.to_le_bytes()]);
+        let token_settings: TokenSettings =
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&data)
+                .unwrap()
+                .into();
+        let (migrated, token_settings) = reload(&token_settings);
+
+        assert_eq!(token_settings.version, ACCOUNT_VERSION);
+        assert_eq!(token_settings.max_fee, 50);
+        assert_eq!(token_settings.fee_rate_bp, None);
+        assert!(token_settings.enabled);
+        assert_eq!(token_settings.max_amount, 0);
+        assert_eq!(token_settings.tier_fee_rate_bp(u64::MAX), None);
+        assert_eq!(
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+    }
+
+    #[test]
+    fn migrate_baseline_escrow() {
+        let keys: Vec<Pubkey> = (1..=6).map(|i| Pubkey::new_from_array([i; 32])).collect();
+        let mut fields: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        let amounts = [1_000_000u64, 500, 1_000, 5].map(u64::to_le_bytes);
+        fields.extend(amounts.iter().map(|amount| &amount[..]));
+        let times = [1_700_000_000i64, 5, 10, 1_700_000_100].map(i64::to_le_bytes);
+        fields.push(&[7; 32]);
+        fields.extend(times.iter().map(|time| &time[..]));
+        fields.push(&[1]);
+        let mut data = baseline_account_data(Escrow::DISCRIMINATOR, 304, &fields);
+
+        let escrow: Escrow = legacy::read::<legacy::Escrow, Escrow>(&data)
+            .unwrap()
+            .into();
+        let (migrated, escrow) = reload(&escrow);
+
+        assert_eq!(escrow.version, ACCOUNT_VERSION);
+        assert_eq!(escrow.from, keys[0]);
+        assert_eq!(escrow.to, keys[1]);
+        assert_eq!(escrow.token_program, keys[2]);
+        assert_eq!(escrow.escrow_ata, keys[5]);
+        assert_eq!(escrow.sol_amount, 1_000_000);
+        assert_eq!(escrow.token_fee, 5);
+        assert_eq!(escrow.lock.hash, [7; 32]);
+        assert_eq!(escrow.lock.tolerant_single_step_time, 10);
+        assert_eq!(escrow.lock.earliest_refund_time, 1_700_000_100);
+        assert!(escrow.lock.hash_algorithm == HashAlgorithm::Keccak256);
+        assert_eq!(escrow.lock.max_preimage_len, 0);
+        assert!(escrow.is_out);
+        assert!(escrow.fee_mode == FeeMode::Deducted);
+        assert_eq!(escrow.referrer, Pubkey::default());
+        assert_eq!(escrow.referral_share_bp, 0);
+        assert_eq!(
+            legacy::read::<legacy::Escrow, Escrow>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+
+        // confirm and refund load the escrow whether or not it was migrated
+        let loaded = AnyVersionEscrow::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(loaded.from, keys[0]);
+        assert_eq!(loaded.lock.earliest_refund_time, 1_700_000_100);
+        let loaded = AnyVersionEscrow::try_deserialize(&mut &migrated[..]).unwrap();
+        assert_eq!(loaded.version, ACCOUNT_VERSION);
+
+        data[0] ^= 1;
+        assert!(legacy::read::<legacy::Escrow, Escrow>(&data).is_err());
+        assert!(AnyVersionEscrow::try_deserialize(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn hook_accounts_only_go_to_hooked_mints() {
+        let key = Pubkey::new_from_array([9; 32]);
+        for (hook_program, expected) in
+            [(None, false), (Some(Pubkey::new_from_array([8; 32])), true)]
+        {
+            let mut lamports = 0;
+            let mut data = token_2022_mint_data(hook_program, None);
+            let info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &spl_token_2022::ID,
+                false,
+                0,
+            );
+            let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+            assert_eq!(has_transfer_hook(&mint).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn on_top_fees_are_refused_for_transfer_fee_mints() {
+        let key = Pubkey::new_from_array([9; 32]);
+        for (transfer_fee_bp, expected) in [(None, false), (Some(100), true)] {
+            let mut lamports = 0;
+            let mut data = token_2022_mint_data(None, transfer_fee_bp);
+            let info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &spl_token_2022::ID,
+                false,
+                0,
+            );
+            let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+            assert_eq!(has_transfer_fee(&mint).unwrap(), expected);
+        }
+    }
 }
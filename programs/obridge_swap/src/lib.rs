@@ -1,46 +1,82 @@
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{self, CloseAccount, Mint, Token, TokenAccount};
-use std::mem::size_of;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee, transfer_hook, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token_interface::{self, CloseAccount, Mint, TokenAccount, TokenInterface};
+use std::ops::{Deref, DerefMut};
 
 declare_id!("DnSgZFH2hMgZ7bXmJUdcL8bgB1MgDpVtddNhwzZACTKQ");
 
 const ADMIN_SETTINGS_SEED: &[u8] = b"settings";
 const TOKEN_SETTINGS_SEED_PREFIX: &[u8] = b"token";
+const FEE_DISCOUNT_SEED_PREFIX: &[u8] = b"discount";
+const PENDING_CHANGE_SEED_PREFIX: &[u8] = b"pending";
+const REFERRAL_VAULT_SEED_PREFIX: &[u8] = b"referral";
+const MAX_FEE_TIERS: usize = 8;
+const MAX_ADMIN_SIGNERS: usize = 10;
+/// Written to the `version` of config and escrow accounts, older accounts are upgraded by the
+/// `migrate_*` instructions. New fields take their space from the zeroed `reserved` tail.
+const ACCOUNT_VERSION: u8 = 1;
 
 mod helpers {
     use super::*;
     use anchor_lang::system_program;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn handle_token_transfer<'info>(
-        token_program: &Program<'info, Token>,
+        token_program: &Interface<'info, TokenInterface>,
         from: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
         to: &AccountInfo<'info>,
         authority: &AccountInfo<'info>,
         amount: u64,
+        hook_accounts: &[AccountInfo<'info>],
         seeds: Option<&[&[&[u8]]]>,
     ) -> Result<()> {
-        let transfer_ctx = CpiContext::new(
-            token_program.to_account_info(),
-            token::Transfer {
-                from: from.to_account_info(),
-                to: to.to_account_info(),
-                authority: authority.to_account_info(),
-            },
-        );
+        let mut ix = spl_token_2022::instruction::transfer_checked(
+            token_program.key,
+            from.key,
+            &mint.key(),
+            to.key,
+            authority.key,
+            &[],
+            amount,
+            mint.decimals,
+        )?;
+        let mut account_infos = vec![
+            from.to_account_info(),
+            mint.to_account_info(),
+            to.to_account_info(),
+            authority.to_account_info(),
+        ];
+
+        // Token-2022 picks the transfer hook's extra accounts out of whatever follows the transfer
+        // accounts, transfers of mints without a hook get none of them
+        if has_transfer_hook(mint)? {
+            for account in hook_accounts {
+                ix.accounts.push(AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: false,
+                    is_writable: account.is_writable,
+                });
+                account_infos.push(account.to_account_info());
+            }
+        }
 
-        if let Some(seeds) = seeds {
-            token::transfer(
-                CpiContext::new_with_signer(
-                    token_program.to_account_info(),
-                    transfer_ctx.accounts,
-                    seeds,
-                ),
-                amount,
-            )
-        } else {
-            token::transfer(transfer_ctx, amount)
+        solana_program::program::invoke_signed(&ix, &account_infos, seeds.unwrap_or_default())?;
+        Ok(())
+    }
+
+    pub fn has_transfer_hook(mint: &InterfaceAccount<Mint>) -> Result<bool> {
+        let mint = mint.to_account_info();
+        if *mint.owner != spl_token_2022::ID {
+            return Ok(false);
         }
+        let data = mint.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        Ok(transfer_hook::get_program_id(&state).is_some())
     }
 
     pub fn handle_sol_transfer<'info>(
@@ -61,43 +97,152 @@ mod helpers {
         )
     }
 
+    /// Reallocs `account` to the current layout of `T` and writes `value`, `payer` covers the extra rent.
+    pub fn write_migrated_account<'info, T: AccountSerialize + Space>(
+        account: &AccountInfo<'info>,
+        payer: &AccountInfo<'info>,
+        system_program: &AccountInfo<'info>,
+        value: &T,
+    ) -> Result<()> {
+        let new_len = 8 + T::INIT_SPACE;
+        let rent = Rent::get()?;
+        let extra_rent = rent
+            .minimum_balance(new_len)
+            .saturating_sub(rent.minimum_balance(account.data_len()));
+        if extra_rent > 0 {
+            handle_sol_transfer(payer, account, system_program, extra_rent)?;
+        }
+        account.realloc(new_len, true)?;
+        value.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])
+    }
+
     pub fn verify_token_settings(
-        token: Option<&Account<Mint>>,
-        token_settings: Option<&Account<TokenSettings>>,
+        token: Option<&InterfaceAccount<Mint>>,
+        token_settings: &Account<TokenSettings>,
         program_id: &Pubkey,
     ) -> Result<()> {
-        if let Some(settings) = token_settings {
-            let (expected_settings, _) = if let Some(token) = token {
-                Pubkey::find_program_address(
-                    &[
-                        TOKEN_SETTINGS_SEED_PREFIX,
-                        &token.to_account_info().key().to_bytes(),
-                    ],
-                    program_id,
-                )
-            } else {
-                Pubkey::find_program_address(&[TOKEN_SETTINGS_SEED_PREFIX, &[0u8; 32]], program_id)
-            };
+        let (expected_settings, _) = if let Some(token) = token {
+            Pubkey::find_program_address(
+                &[
+                    TOKEN_SETTINGS_SEED_PREFIX,
+                    &token.to_account_info().key().to_bytes(),
+                ],
+                program_id,
+            )
+        } else {
+            Pubkey::find_program_address(&[TOKEN_SETTINGS_SEED_PREFIX, &[0u8; 32]], program_id)
+        };
+        require!(
+            token_settings.key() == expected_settings,
+            Errors::InvalidTokenSettings
+        );
+        require!(token_settings.enabled, Errors::TokenNotEnabled);
+        Ok(())
+    }
+
+    pub fn calculate_fee(
+        amount: u64,
+        admin_settings: &AdminSettings,
+        token_settings: &TokenSettings,
+    ) -> Result<u64> {
+        let fee_rate_bp = token_settings
+            .tier_fee_rate_bp(amount)
+            .or(token_settings.fee_rate_bp)
+            .unwrap_or(admin_settings.fee_rate_bp);
+        let mut fee = mul_bp(amount, fee_rate_bp, admin_settings.fee_rounding)?;
+        if fee < token_settings.min_fee {
+            fee = token_settings.min_fee;
+        }
+        if token_settings.max_fee > 0 && fee > token_settings.max_fee {
+            fee = token_settings.max_fee;
+        }
+        Ok(fee.min(mul_bp(
+            amount,
+            admin_settings.fee_rate_cap_bp,
+            admin_settings.fee_rounding,
+        )?))
+    }
+
+    pub fn check_fee_tiers(fee_tiers: &[FeeTier], fee_rate_cap_bp: u16) -> Result<()> {
+        require!(fee_tiers.len() <= MAX_FEE_TIERS, Errors::InvalidFeeTiers);
+        for (i, tier) in fee_tiers.iter().enumerate() {
+            require!(tier.fee_rate_bp < 10000, Errors::InvalidFeeRate);
+            require!(tier.fee_rate_bp <= fee_rate_cap_bp, Errors::FeeRateAboveCap);
             require!(
-                settings.key() == expected_settings,
-                Errors::InvalidTokenSettings
+                i == 0 || tier.min_amount > fee_tiers[i - 1].min_amount,
+                Errors::InvalidFeeTiers
             );
         }
         Ok(())
     }
 
-    pub fn calculate_fee(amount: u64, fee_rate_bp: u16, max_fee: Option<u64>) -> u64 {
-        let mut fee = amount * fee_rate_bp as u64 / 10000;
-        if let Some(max) = max_fee {
-            if max > 0 && fee > max {
-                fee = max;
-            }
+    pub fn apply_fee_discount(
+        fee: u64,
+        fee_discount: Option<&Account<FeeDiscount>>,
+    ) -> Result<u64> {
+        let discount_bp = fee_discount.map_or(0, |d| d.discount_bp);
+        Ok(fee - mul_bp(fee, discount_bp, FeeRounding::Floor)?)
+    }
+
+    /// `amount * bp / 10000` without overflow, the result never exceeds `amount` as `bp > 10000` is rejected.
+    pub fn mul_bp(amount: u64, bp: u16, rounding: FeeRounding) -> Result<u64> {
+        require!(bp <= 10000, Errors::InvalidFeeRate);
+        let product = amount as u128 * bp as u128;
+        let result = match rounding {
+            FeeRounding::Floor => product / 10000,
+            FeeRounding::Ceil => product.div_ceil(10000),
+        };
+        Ok(u64::try_from(result).map_err(|_| Errors::InvalidFeeRate)?)
+    }
+
+    pub fn harvest_withheld_fees<'info>(
+        token_program: &Interface<'info, TokenInterface>,
+        account: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
+    ) -> Result<()> {
+        // Token-2022 transfer fees are withheld in the receiving account and block closing it
+        let withheld_amount = {
+            let data = account.try_borrow_data()?;
+            let state = StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)?;
+            state
+                .get_extension::<transfer_fee::TransferFeeAmount>()
+                .map_or(0, |fee_amount| u64::from(fee_amount.withheld_amount))
+        };
+        if withheld_amount == 0 {
+            return Ok(());
         }
-        fee
+
+        let ix = transfer_fee::instruction::harvest_withheld_tokens_to_mint(
+            &token_program.key(),
+            &mint.key(),
+            &[account.key],
+        )?;
+        solana_program::program::invoke(&ix, &[mint.to_account_info(), account.clone()])?;
+        Ok(())
+    }
+
+    pub fn close_token_account<'info>(
+        token_program: &Interface<'info, TokenInterface>,
+        account: &AccountInfo<'info>,
+        mint: &InterfaceAccount<'info, Mint>,
+        destination: &AccountInfo<'info>,
+        authority: &AccountInfo<'info>,
+        seeds: &[&[&[u8]]],
+    ) -> Result<()> {
+        harvest_withheld_fees(token_program, account, mint)?;
+        token_interface::close_account(CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            CloseAccount {
+                account: account.to_account_info(),
+                destination: destination.to_account_info(),
+                authority: authority.to_account_info(),
+            },
+            seeds,
+        ))
     }
 
     pub fn close_escrow_account<'info>(
-        escrow: &Account<'info, Escrow>,
+        escrow: &Account<'info, AnyVersionEscrow>,
         from: &AccountInfo<'info>,
         from_add_lamports: u64,
         escrow_minus_lamports: u64,
@@ -115,42 +260,420 @@ pub mod obridge_swap {
     use super::*;
     use helpers::*;
 
-    pub fn initialize(ctx: Context<Initialize>, admin: Pubkey) -> Result<()> {
-        ctx.accounts.admin_settings.admin = admin;
+    pub fn initialize(ctx: Context<Initialize>, admin: Pubkey, fee_rate_cap_bp: u16) -> Result<()> {
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        admin_settings.version = ACCOUNT_VERSION;
+        admin_settings.admin = admin;
+        admin_settings.fee_rate_cap_bp = fee_rate_cap_bp;
         Ok(())
     }
 
     pub fn change_admin(ctx: Context<ChangeAdmin>) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.admin = ctx.accounts.new_admin.key();
         Ok(())
     }
 
+    pub fn set_admin_signers(
+        ctx: Context<SetAdminSigners>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        require!(
+            signers.len() <= MAX_ADMIN_SIGNERS && threshold as usize <= signers.len(),
+            Errors::InvalidAdminSigners
+        );
+        for (i, signer) in signers.iter().enumerate() {
+            require!(!signers[..i].contains(signer), Errors::InvalidAdminSigners);
+        }
+
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        admin_settings.admin_signers = [Pubkey::default(); MAX_ADMIN_SIGNERS];
+        admin_settings.admin_signers[..signers.len()].copy_from_slice(&signers);
+        admin_settings.admin_signer_count = signers.len() as u8;
+        admin_settings.admin_threshold = threshold;
+        Ok(())
+    }
+
+    pub fn set_role(ctx: Context<SetRole>, role: Role, key: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        match role {
+            Role::FeeManager => admin_settings.fee_manager = key,
+            Role::TokenManager => admin_settings.token_manager = key,
+            Role::Pauser => admin_settings.pauser = key,
+        }
+        Ok(())
+    }
+
     pub fn set_fee_recepient(ctx: Context<SetFeeRecepient>) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.fee_recepient = ctx.accounts.fee_recepient.key();
         Ok(())
     }
 
     pub fn set_fee_rate(ctx: Context<SetFeeRate>, fee_rate_bp: u16) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
         ctx.accounts.admin_settings.fee_rate_bp = fee_rate_bp;
         Ok(())
     }
 
+    pub fn set_fee_rounding(ctx: Context<SetFeeRounding>, fee_rounding: FeeRounding) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.fee_rounding = fee_rounding;
+        Ok(())
+    }
+
+    pub fn set_max_referral_share(
+        ctx: Context<SetMaxReferralShare>,
+        max_referral_share_bp: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.max_referral_share_bp = max_referral_share_bp;
+        Ok(())
+    }
+
     pub fn set_max_fee_for_token(
         ctx: Context<SetMaxFeeForToken>,
         _mint: Pubkey,
         max_fee: u64,
     ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.version = ACCOUNT_VERSION;
         ctx.accounts.token_settings.max_fee = max_fee;
         Ok(())
     }
 
-    pub fn submit_swap(
-        ctx: Context<SubmitSwap>,
-        _uuid: [u8; 32],
+    pub fn set_fee_rate_for_token(
+        ctx: Context<SetFeeRateForToken>,
+        _mint: Pubkey,
+        fee_rate_bp: Option<u16>,
+        min_fee: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.fee_rate_bp = fee_rate_bp;
+        token_settings.min_fee = min_fee;
+        Ok(())
+    }
+
+    pub fn set_fee_tiers_for_token(
+        ctx: Context<SetFeeTiersForToken>,
+        _mint: Pubkey,
+        fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        check_fee_tiers(&fee_tiers, ctx.accounts.admin_settings.fee_rate_cap_bp)?;
+
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        token_settings.fee_tiers[..fee_tiers.len()].copy_from_slice(&fee_tiers);
+        token_settings.fee_tier_count = fee_tiers.len() as u8;
+        Ok(())
+    }
+
+    pub fn set_fee_discount(
+        ctx: Context<SetFeeDiscount>,
+        _user: Pubkey,
+        discount_bp: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.fee_discount.discount_bp = discount_bp;
+        Ok(())
+    }
+
+    pub fn remove_fee_discount(ctx: Context<RemoveFeeDiscount>, _user: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        Ok(())
+    }
+
+    pub fn set_amount_limits_for_token(
+        ctx: Context<SetAmountLimitsForToken>,
+        _mint: Pubkey,
+        min_amount: u64,
+        max_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        let token_settings = &mut ctx.accounts.token_settings;
+        token_settings.version = ACCOUNT_VERSION;
+        token_settings.min_amount = min_amount;
+        token_settings.max_amount = max_amount;
+        Ok(())
+    }
+
+    pub fn register_token(ctx: Context<RegisterToken>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.version = ACCOUNT_VERSION;
+        ctx.accounts.token_settings.enabled = true;
+        Ok(())
+    }
+
+    pub fn deregister_token(ctx: Context<DeregisterToken>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        ctx.accounts.token_settings.enabled = false;
+        Ok(())
+    }
+
+    pub fn set_timelock_delay(ctx: Context<SetTimelockDelay>, delay: i64) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        ctx.accounts.admin_settings.timelock_delay = delay;
+        Ok(())
+    }
+
+    pub fn queue_admin_change(
+        ctx: Context<QueueAdminChange>,
+        _id: u64,
+        change: AdminChange,
+        effective_time: i64,
+    ) -> Result<()> {
+        let admin_settings = &ctx.accounts.admin_settings;
+        admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        change.validate(admin_settings.fee_rate_cap_bp)?;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            effective_time >= timestamp.saturating_add(admin_settings.timelock_delay),
+            Errors::InvalidEffectiveTime
+        );
+
+        let pending_change = &mut ctx.accounts.pending_change;
+        pending_change.change = change;
+        pending_change.effective_time = effective_time;
+        pending_change.payer = ctx.accounts.payer.key();
+        Ok(())
+    }
+
+    pub fn execute_admin_change(ctx: Context<ExecuteAdminChange>, _id: u64) -> Result<()> {
+        let timestamp = Clock::get()?.unix_timestamp;
+        let pending_change = &ctx.accounts.pending_change;
+        require!(
+            timestamp >= pending_change.effective_time,
+            Errors::TimelockNotElapsed
+        );
+
+        let token_settings = match pending_change.change.mint() {
+            Some(mint) => {
+                let token_settings = ctx
+                    .accounts
+                    .token_settings
+                    .as_mut()
+                    .ok_or(Errors::InvalidTokenSettings)?;
+                let (expected_settings, _) = Pubkey::find_program_address(
+                    &[TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+                    ctx.program_id,
+                );
+                require!(
+                    token_settings.key() == expected_settings,
+                    Errors::InvalidTokenSettings
+                );
+                Some(&mut **token_settings)
+            }
+            None => None,
+        };
+        let fee_discount = match pending_change.change.discount_user() {
+            Some(user) => {
+                let fee_discount = ctx
+                    .accounts
+                    .fee_discount
+                    .as_mut()
+                    .ok_or(Errors::InvalidFeeDiscount)?;
+                let (expected_discount, _) = Pubkey::find_program_address(
+                    &[FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()],
+                    ctx.program_id,
+                );
+                require!(
+                    fee_discount.key() == expected_discount,
+                    Errors::InvalidFeeDiscount
+                );
+                Some(&mut **fee_discount)
+            }
+            None => None,
+        };
+        pending_change.change.apply(
+            &mut ctx.accounts.admin_settings,
+            token_settings,
+            fee_discount,
+        );
+        Ok(())
+    }
+
+    pub fn cancel_admin_change(ctx: Context<CancelAdminChange>, _id: u64) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        Ok(())
+    }
+
+    pub fn migrate_admin_settings(
+        ctx: Context<MigrateAdminSettings>,
+        fee_rate_cap_bp: u16,
+    ) -> Result<()> {
+        let account = ctx.accounts.admin_settings.to_account_info();
+        let admin_settings =
+            legacy::read::<legacy::AdminSettings, AdminSettings>(&account.try_borrow_data()?)?
+                .upgrade(fee_rate_cap_bp)?;
+        require_keys_eq!(
+            admin_settings.admin,
+            ctx.accounts.admin.key(),
+            Errors::AccountMismatch
+        );
+        admin_settings.check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &admin_settings,
+        )
+    }
+
+    pub fn migrate_token_settings(ctx: Context<MigrateTokenSettings>, _mint: Pubkey) -> Result<()> {
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.admin.key(), ctx.remaining_accounts)?;
+        let account = ctx.accounts.token_settings.to_account_info();
+        let token_settings: TokenSettings =
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&account.try_borrow_data()?)?
+                .into();
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &token_settings,
+        )
+    }
+
+    /// Open to anyone, the upgrade keeps the parties, amounts and lock of the escrow as they are.
+    pub fn migrate_escrow(ctx: Context<MigrateEscrow>, _uuid: [u8; 32]) -> Result<()> {
+        let account = ctx.accounts.escrow.to_account_info();
+        let escrow: Escrow =
+            legacy::read::<legacy::Escrow, Escrow>(&account.try_borrow_data()?)?.into();
+        write_migrated_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &escrow,
+        )
+    }
+
+    /// Pausing is the emergency brake, so the pauser or the admin can pull it alone. Unpausing
+    /// reopens new swaps and needs the admin approvals.
+    pub fn set_pause(ctx: Context<SetPause>, paused: bool) -> Result<()> {
+        let admin_settings = &mut ctx.accounts.admin_settings;
+        if admin_settings.paused && !paused {
+            admin_settings
+                .check_admin_approvals(&ctx.accounts.authority.key(), ctx.remaining_accounts)?;
+        }
+        admin_settings.paused = paused;
+        Ok(())
+    }
+
+    /// Remaining accounts hold `co_signer_count` admin co-signers, then for each mint its
+    /// (mint, fee_vault, destination, token_program) followed by `hook_account_counts[i]`
+    /// transfer hook accounts. Token fees go to whatever destination the approving admins
+    /// picked, SOL fees always go to `fee_recepient`.
+    pub fn withdraw_fees<'info>(
+        ctx: Context<'_, '_, 'info, 'info, WithdrawFees<'info>>,
+        co_signer_count: u8,
+        hook_account_counts: Vec<u8>,
+    ) -> Result<()> {
+        require!(
+            ctx.remaining_accounts.len() >= co_signer_count as usize,
+            Errors::AccountMismatch
+        );
+        let (co_signers, mut accounts) = ctx.remaining_accounts.split_at(co_signer_count as usize);
+        ctx.accounts
+            .admin_settings
+            .check_admin_approvals(&ctx.accounts.authority.key(), co_signers)?;
+        let admin_settings = ctx.accounts.admin_settings.to_account_info();
+        let seeds: &[&[&[u8]]] = &[&[ADMIN_SETTINGS_SEED, &[ctx.bumps.admin_settings]]];
+
+        for hook_account_count in hook_account_counts {
+            let mint_accounts_len = 4 + hook_account_count as usize;
+            require!(accounts.len() >= mint_accounts_len, Errors::AccountMismatch);
+            let (mint_accounts, rest) = accounts.split_at(mint_accounts_len);
+            accounts = rest;
+
+            let mint = InterfaceAccount::<Mint>::try_from(&mint_accounts[0])?;
+            let fee_vault = InterfaceAccount::<TokenAccount>::try_from(&mint_accounts[1])?;
+            let destination = InterfaceAccount::<TokenAccount>::try_from(&mint_accounts[2])?;
+            let token_program = Interface::<TokenInterface>::try_from(&mint_accounts[3])?;
+            require!(
+                fee_vault.owner == admin_settings.key()
+                    && fee_vault.mint == mint.key()
+                    && destination.mint == mint.key()
+                    && *mint.to_account_info().owner == token_program.key(),
+                Errors::AccountMismatch
+            );
+
+            if fee_vault.amount > 0 {
+                handle_token_transfer(
+                    &token_program,
+                    &mint_accounts[1],
+                    &mint,
+                    &mint_accounts[2],
+                    &admin_settings,
+                    fee_vault.amount,
+                    &mint_accounts[4..],
+                    Some(seeds),
+                )?;
+            }
+        }
+
+        // sweep SOL fees, keeping the admin settings account rent exempt
+        let rent_exempt = Rent::get()?.minimum_balance(admin_settings.data_len());
+        let sol_fee = admin_settings.lamports().saturating_sub(rent_exempt);
+        if sol_fee > 0 {
+            ctx.accounts.admin_settings.sub_lamports(sol_fee)?;
+            ctx.accounts.fee_recepient.add_lamports(sol_fee)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn submit_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, SubmitSwap<'info>>,
+        uuid: [u8; 32],
         src_amount: u64,
         dst_amount: u64,
         lock: Lock,
         _memo: Vec<u8>,
+        referral: Option<Referral>,
     ) -> Result<()> {
         require!(
             ctx.accounts.payer.key() == ctx.accounts.from.key(),
@@ -158,6 +681,11 @@ pub mod obridge_swap {
         );
 
         require!(src_amount > 0 && dst_amount > 0, Errors::InvalidAmount);
+        require!(!ctx.accounts.admin_settings.paused, Errors::Paused);
+        ctx.accounts.admin_settings.check_referral(
+            referral.as_ref(),
+            ctx.accounts.referrer.as_ref().map(|r| r.as_ref()),
+        )?;
 
         let timestamp = Clock::get()?.unix_timestamp;
         require!(
@@ -165,40 +693,40 @@ pub mod obridge_swap {
             Errors::DeadlineExceeded
         );
 
-        // Verify token settings and calculate fees
+        // Verify token settings
         verify_token_settings(
             ctx.accounts.src_token.as_ref(),
-            ctx.accounts.src_token_settings.as_ref(),
+            &ctx.accounts.src_token_settings,
             &ctx.program_id,
         )?;
         verify_token_settings(
             ctx.accounts.dst_token.as_ref(),
-            ctx.accounts.dst_token_settings.as_ref(),
+            &ctx.accounts.dst_token_settings,
             &ctx.program_id,
         )?;
-
-        let fee_rate_bp = ctx.accounts.admin_settings.fee_rate_bp;
-        let src_token_fee = calculate_fee(
-            src_amount,
-            fee_rate_bp,
-            ctx.accounts.src_token_settings.as_ref().map(|s| s.max_fee),
-        );
-        let dst_token_fee = calculate_fee(
-            dst_amount,
-            fee_rate_bp,
-            ctx.accounts.dst_token_settings.as_ref().map(|s| s.max_fee),
-        );
+        ctx.accounts.src_token_settings.check_amount(src_amount)?;
+        ctx.accounts.dst_token_settings.check_amount(dst_amount)?;
 
         // Handle token transfers
-        if ctx.accounts.src_token.is_some() {
+        let mut src_received = src_amount;
+        if let Some(src_token) = &ctx.accounts.src_token {
+            let escrow_ata = ctx.accounts.escrow_ata.as_mut().unwrap();
+            let balance_before = escrow_ata.amount;
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.source.as_ref().unwrap().to_account_info(),
-                &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                src_token,
+                &escrow_ata.to_account_info(),
                 &ctx.accounts.from.to_account_info(),
                 src_amount,
+                ctx.remaining_accounts,
                 None,
             )?;
+
+            // transfer-fee mints deliver less than src_amount, account for what actually arrived
+            escrow_ata.reload()?;
+            src_received = escrow_ata.amount - balance_before;
+            require!(src_received > 0, Errors::InvalidAmount);
         } else {
             // Handle SOL transfer
             handle_sol_transfer(
@@ -209,11 +737,29 @@ pub mod obridge_swap {
             )?;
         }
 
+        let src_token_fee = apply_fee_discount(
+            calculate_fee(
+                src_received,
+                &ctx.accounts.admin_settings,
+                &ctx.accounts.src_token_settings,
+            )?,
+            ctx.accounts.fee_discount.as_ref(),
+        )?;
+        let dst_token_fee = apply_fee_discount(
+            calculate_fee(
+                dst_amount,
+                &ctx.accounts.admin_settings,
+                &ctx.accounts.dst_token_settings,
+            )?,
+            ctx.accounts.fee_discount.as_ref(),
+        )?;
+
         // Update escrow account
         let escrow = &mut ctx.accounts.escrow;
+        escrow.version = ACCOUNT_VERSION;
         escrow.from = ctx.accounts.from.key();
         escrow.to = ctx.accounts.to.key();
-        escrow.src_amount = src_amount;
+        escrow.src_amount = src_received;
         escrow.dst_amount = dst_amount;
         escrow.src_token_fee = src_token_fee;
         escrow.dst_token_fee = dst_token_fee;
@@ -228,11 +774,45 @@ pub mod obridge_swap {
             .dst_token
             .as_ref()
             .map_or(Pubkey::new_from_array([0; 32]), |t| t.key());
+        escrow.src_token_program = ctx
+            .accounts
+            .src_token
+            .as_ref()
+            .map_or(Pubkey::new_from_array([0; 32]), |t| {
+                *t.to_account_info().owner
+            });
+        escrow.dst_token_program = ctx
+            .accounts
+            .dst_token
+            .as_ref()
+            .map_or(Pubkey::new_from_array([0; 32]), |t| {
+                *t.to_account_info().owner
+            });
+
+        if let Some(referral) = referral {
+            escrow.referrer = referral.referrer;
+            escrow.referral_share_bp = referral.share_bp;
+        }
+
+        emit_cpi!(SwapSubmitted {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            src_token: escrow.src_token,
+            dst_token: escrow.dst_token,
+            src_amount: escrow.src_amount,
+            dst_amount: escrow.dst_amount,
+            src_token_fee: escrow.src_token_fee,
+            dst_token_fee: escrow.dst_token_fee,
+        });
 
         Ok(())
     }
 
-    pub fn confirm_swap(ctx: Context<ConfirmSwap>, uuid: [u8; 32]) -> Result<()> {
+    pub fn confirm_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, ConfirmSwap<'info>>,
+        uuid: [u8; 32],
+    ) -> Result<()> {
         require!(
             ctx.accounts.payer.key() == ctx.accounts.to.key(),
             Errors::InvalidSender
@@ -246,18 +826,57 @@ pub mod obridge_swap {
             Errors::DeadlineExceeded
         );
 
+        let src_referral_fee = escrow.referral_fee(escrow.src_token_fee)?;
+        let dst_referral_fee = escrow.referral_fee(escrow.dst_token_fee)?;
+
+        let event = SwapConfirmed {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            src_token: escrow.src_token,
+            dst_token: escrow.dst_token,
+            src_amount: escrow.src_amount,
+            dst_amount: escrow.dst_amount,
+            src_token_fee: escrow.src_token_fee,
+            dst_token_fee: escrow.dst_token_fee,
+            referrer: escrow.referrer,
+            src_referral_fee,
+            dst_referral_fee,
+        };
+
         let zero_pubkey = Pubkey::new_from_array([0; 32]);
         let seeds: &[&[&[u8]]] = &[&[&uuid, &[Pubkey::find_program_address(&[&uuid], &id()).1]]];
 
+        // the referral vault is only opened for escrows that carry a referrer
+        if escrow.has_referrer() {
+            require!(
+                ctx.accounts.referral_vault.is_some(),
+                Errors::AccountMismatch
+            );
+            ctx.accounts.referral_vault.as_mut().unwrap().referrer = escrow.referrer;
+        }
+
         // Handle destination token transfers (SOL or SPL)
         if escrow.dst_token == zero_pubkey {
             // Handle SOL transfers
             handle_sol_transfer(
                 &ctx.accounts.to.to_account_info(),
-                &ctx.accounts.fee_recepient.to_account_info(),
+                &ctx.accounts.admin_settings.to_account_info(),
                 &ctx.accounts.system_program.to_account_info(),
-                escrow.dst_token_fee,
+                escrow.dst_token_fee - dst_referral_fee,
             )?;
+            if dst_referral_fee > 0 {
+                handle_sol_transfer(
+                    &ctx.accounts.to.to_account_info(),
+                    &ctx.accounts
+                        .referral_vault
+                        .as_ref()
+                        .unwrap()
+                        .to_account_info(),
+                    &ctx.accounts.system_program.to_account_info(),
+                    dst_referral_fee,
+                )?;
+            }
             handle_sol_transfer(
                 &ctx.accounts.to.to_account_info(),
                 &ctx.accounts.from.to_account_info(),
@@ -267,31 +886,57 @@ pub mod obridge_swap {
         } else {
             // Handle SPL token transfers
             require!(
-                ctx.accounts.token_program.is_some()
+                ctx.accounts.dst_token_program.is_some()
+                    && ctx.accounts.dst_token.is_some()
                     && ctx.accounts.to_source.is_some()
-                    && ctx.accounts.dst_fee_destination.is_some()
+                    && ctx.accounts.dst_fee_vault.is_some()
                     && ctx.accounts.from_destination.is_some(),
                 Errors::AccountMismatch
             );
 
             // Transfer fee
             handle_token_transfer(
-                ctx.accounts.token_program.as_ref().unwrap(),
+                ctx.accounts.dst_token_program.as_ref().unwrap(),
                 &ctx.accounts.to_source.as_ref().unwrap().to_account_info(),
+                ctx.accounts.dst_token.as_ref().unwrap(),
                 &ctx.accounts
-                    .dst_fee_destination
+                    .dst_fee_vault
                     .as_ref()
                     .unwrap()
                     .to_account_info(),
                 &ctx.accounts.to.to_account_info(),
-                escrow.dst_token_fee,
+                escrow.dst_token_fee - dst_referral_fee,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
+            // Accrue referral share of the fee until the referrer claims it
+            if dst_referral_fee > 0 {
+                require!(
+                    ctx.accounts.dst_referral_vault_ata.is_some(),
+                    Errors::AccountMismatch
+                );
+                handle_token_transfer(
+                    ctx.accounts.dst_token_program.as_ref().unwrap(),
+                    &ctx.accounts.to_source.as_ref().unwrap().to_account_info(),
+                    ctx.accounts.dst_token.as_ref().unwrap(),
+                    &ctx.accounts
+                        .dst_referral_vault_ata
+                        .as_ref()
+                        .unwrap()
+                        .to_account_info(),
+                    &ctx.accounts.to.to_account_info(),
+                    dst_referral_fee,
+                    ctx.remaining_accounts,
+                    Some(seeds),
+                )?;
+            }
+
             // Transfer amount minus fee
             handle_token_transfer(
-                ctx.accounts.token_program.as_ref().unwrap(),
+                ctx.accounts.dst_token_program.as_ref().unwrap(),
                 &ctx.accounts.to_source.as_ref().unwrap().to_account_info(),
+                ctx.accounts.dst_token.as_ref().unwrap(),
                 &ctx.accounts
                     .from_destination
                     .as_ref()
@@ -299,6 +944,7 @@ pub mod obridge_swap {
                     .to_account_info(),
                 &ctx.accounts.to.to_account_info(),
                 escrow.dst_amount - escrow.dst_token_fee,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
         }
@@ -306,8 +952,15 @@ pub mod obridge_swap {
         // Handle source token transfers (SOL or SPL)
         if escrow.src_token == zero_pubkey {
             ctx.accounts
-                .fee_recepient
-                .add_lamports(escrow.src_token_fee)?;
+                .admin_settings
+                .add_lamports(escrow.src_token_fee - src_referral_fee)?;
+            if src_referral_fee > 0 {
+                ctx.accounts
+                    .referral_vault
+                    .as_ref()
+                    .unwrap()
+                    .add_lamports(src_referral_fee)?;
+            }
             ctx.accounts
                 .to
                 .add_lamports(escrow.src_amount - escrow.src_token_fee)?;
@@ -322,8 +975,9 @@ pub mod obridge_swap {
         } else {
             require!(
                 ctx.accounts.token_program.is_some()
+                    && ctx.accounts.src_token.is_some()
                     && ctx.accounts.escrow_ata.is_some()
-                    && ctx.accounts.src_fee_destination.is_some()
+                    && ctx.accounts.src_fee_vault.is_some()
                     && ctx.accounts.to_destination.is_some(),
                 Errors::AccountMismatch
             );
@@ -332,19 +986,43 @@ pub mod obridge_swap {
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.src_token.as_ref().unwrap(),
                 &ctx.accounts
-                    .src_fee_destination
+                    .src_fee_vault
                     .as_ref()
                     .unwrap()
                     .to_account_info(),
                 &escrow.to_account_info(),
-                escrow.src_token_fee,
+                escrow.src_token_fee - src_referral_fee,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
+            if src_referral_fee > 0 {
+                require!(
+                    ctx.accounts.src_referral_vault_ata.is_some(),
+                    Errors::AccountMismatch
+                );
+                handle_token_transfer(
+                    ctx.accounts.token_program.as_ref().unwrap(),
+                    &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                    ctx.accounts.src_token.as_ref().unwrap(),
+                    &ctx.accounts
+                        .src_referral_vault_ata
+                        .as_ref()
+                        .unwrap()
+                        .to_account_info(),
+                    &escrow.to_account_info(),
+                    src_referral_fee,
+                    ctx.remaining_accounts,
+                    Some(seeds),
+                )?;
+            }
+
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.src_token.as_ref().unwrap(),
                 &ctx.accounts
                     .to_destination
                     .as_ref()
@@ -352,23 +1030,19 @@ pub mod obridge_swap {
                     .to_account_info(),
                 &escrow.to_account_info(),
                 escrow.src_amount - escrow.src_token_fee,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
             // Close escrow ATA account
-            token::close_account(CpiContext::new_with_signer(
-                ctx.accounts
-                    .token_program
-                    .as_ref()
-                    .unwrap()
-                    .to_account_info(),
-                CloseAccount {
-                    account: ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
-                    destination: ctx.accounts.from.to_account_info(),
-                    authority: escrow.to_account_info(),
-                },
+            close_token_account(
+                ctx.accounts.token_program.as_ref().unwrap(),
+                &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.src_token.as_ref().unwrap(),
+                &ctx.accounts.from.to_account_info(),
+                &escrow.to_account_info(),
                 seeds,
-            ))?;
+            )?;
 
             // Close escrow account
             let escrow_lamports = escrow.to_account_info().lamports();
@@ -380,10 +1054,58 @@ pub mod obridge_swap {
             )?;
         }
 
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn claim_referral_fees<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimReferralFees<'info>>,
+    ) -> Result<()> {
+        let referrer = ctx.accounts.referrer.key();
+        let seeds: &[&[&[u8]]] = &[&[
+            REFERRAL_VAULT_SEED_PREFIX,
+            referrer.as_ref(),
+            &[ctx.bumps.referral_vault],
+        ]];
+
+        if let Some(referral_vault_ata) = ctx.accounts.referral_vault_ata.as_ref() {
+            require!(
+                ctx.accounts.token.is_some()
+                    && ctx.accounts.destination.is_some()
+                    && ctx.accounts.token_program.is_some(),
+                Errors::AccountMismatch
+            );
+            if referral_vault_ata.amount > 0 {
+                handle_token_transfer(
+                    ctx.accounts.token_program.as_ref().unwrap(),
+                    &referral_vault_ata.to_account_info(),
+                    ctx.accounts.token.as_ref().unwrap(),
+                    &ctx.accounts.destination.as_ref().unwrap().to_account_info(),
+                    &ctx.accounts.referral_vault.to_account_info(),
+                    referral_vault_ata.amount,
+                    ctx.remaining_accounts,
+                    Some(seeds),
+                )?;
+            }
+        }
+
+        // sweep SOL shares, keeping the vault rent exempt
+        let referral_vault = ctx.accounts.referral_vault.to_account_info();
+        let rent_exempt = Rent::get()?.minimum_balance(referral_vault.data_len());
+        let sol_fee = referral_vault.lamports().saturating_sub(rent_exempt);
+        if sol_fee > 0 {
+            ctx.accounts.referral_vault.sub_lamports(sol_fee)?;
+            ctx.accounts.referrer.add_lamports(sol_fee)?;
+        }
+
         Ok(())
     }
 
-    pub fn refund_swap(ctx: Context<RefundSwap>, uuid: [u8; 32]) -> Result<()> {
+    pub fn refund_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, RefundSwap<'info>>,
+        uuid: [u8; 32],
+    ) -> Result<()> {
         let escrow = &mut ctx.accounts.escrow;
         let timestamp = Clock::get()?.unix_timestamp;
 
@@ -392,12 +1114,25 @@ pub mod obridge_swap {
             Errors::NotRefundable
         );
 
+        let event = SwapRefunded {
+            uuid,
+            from: escrow.from,
+            to: escrow.to,
+            src_token: escrow.src_token,
+            dst_token: escrow.dst_token,
+            src_amount: escrow.src_amount,
+            dst_amount: escrow.dst_amount,
+            src_token_fee: escrow.src_token_fee,
+            dst_token_fee: escrow.dst_token_fee,
+        };
+
         let zero_pubkey = Pubkey::new_from_array([0; 32]);
         let seeds: &[&[&[u8]]] = &[&[&uuid, &[Pubkey::find_program_address(&[&uuid], &id()).1]]];
 
         if escrow.src_token != zero_pubkey {
             require!(
                 ctx.accounts.token_program.is_some()
+                    && ctx.accounts.src_token.is_some()
                     && ctx.accounts.escrow_ata.is_some()
                     && ctx.accounts.source.is_some(),
                 Errors::AccountMismatch
@@ -407,26 +1142,23 @@ pub mod obridge_swap {
             handle_token_transfer(
                 ctx.accounts.token_program.as_ref().unwrap(),
                 &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.src_token.as_ref().unwrap(),
                 &ctx.accounts.source.as_ref().unwrap().to_account_info(),
                 &escrow.to_account_info(),
                 escrow.src_amount,
+                ctx.remaining_accounts,
                 Some(seeds),
             )?;
 
             // Close escrow ATA account
-            token::close_account(CpiContext::new_with_signer(
-                ctx.accounts
-                    .token_program
-                    .as_ref()
-                    .unwrap()
-                    .to_account_info(),
-                CloseAccount {
-                    account: ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
-                    destination: ctx.accounts.from.to_account_info(),
-                    authority: escrow.to_account_info(),
-                },
+            close_token_account(
+                ctx.accounts.token_program.as_ref().unwrap(),
+                &ctx.accounts.escrow_ata.as_ref().unwrap().to_account_info(),
+                ctx.accounts.src_token.as_ref().unwrap(),
+                &ctx.accounts.from.to_account_info(),
+                &escrow.to_account_info(),
                 seeds,
-            ))?;
+            )?;
         }
 
         // Close escrow account and refund SOL
@@ -438,6 +1170,8 @@ pub mod obridge_swap {
             escrow_lamports,
         )?;
 
+        emit_cpi!(event);
+
         Ok(())
     }
 }
@@ -466,54 +1200,367 @@ pub enum Errors {
     NotSOLToken,
     #[msg("invalid token settings")]
     InvalidTokenSettings,
+    #[msg("paused")]
+    Paused,
+    #[msg("token not enabled")]
+    TokenNotEnabled,
+    #[msg("invalid amount limits")]
+    InvalidAmountLimits,
+    #[msg("amount out of range")]
+    AmountOutOfRange,
+    #[msg("invalid fee tiers")]
+    InvalidFeeTiers,
+    #[msg("invalid fee discount")]
+    InvalidFeeDiscount,
+    #[msg("invalid referral share")]
+    InvalidReferralShare,
+    #[msg("invalid admin signers")]
+    InvalidAdminSigners,
+    #[msg("not enough admin signers")]
+    NotEnoughAdminSigners,
+    #[msg("change must be queued")]
+    TimelockRequired,
+    #[msg("invalid effective time")]
+    InvalidEffectiveTime,
+    #[msg("timelock not elapsed")]
+    TimelockNotElapsed,
+    #[msg("fee rate above protocol cap")]
+    FeeRateAboveCap,
+    #[msg("account already migrated")]
+    AlreadyMigrated,
+    #[msg("invalid referrer")]
+    InvalidReferrer,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Lock {
-    pub agreement_reached_time: i64,
-    pub step_time: i64,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    FeeManager,
+    TokenManager,
+    Pauser,
 }
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
-
-    #[account(init, payer = payer, space = size_of::<AdminSettings>() + 8, seeds = [ADMIN_SETTINGS_SEED], bump)]
-    pub admin_settings: Account<'info, AdminSettings>,
-
-    pub system_program: Program<'info, System>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum FeeRounding {
+    Floor,
+    Ceil,
 }
 
-#[derive(Accounts)]
-pub struct ChangeAdmin<'info> {
-    pub admin: Signer<'info>,
-    pub new_admin: Signer<'info>,
-
-    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
-    pub admin_settings: Account<'info, AdminSettings>,
+/// Fee parameter updates that go through `queue_admin_change` once a timelock delay is set.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub enum AdminChange {
+    FeeRate {
+        fee_rate_bp: u16,
+    },
+    FeeRounding {
+        fee_rounding: FeeRounding,
+    },
+    TimelockDelay {
+        delay: i64,
+    },
+    TokenMaxFee {
+        mint: Pubkey,
+        max_fee: u64,
+    },
+    TokenFeeRate {
+        mint: Pubkey,
+        fee_rate_bp: Option<u16>,
+        min_fee: u64,
+    },
+    TokenFeeTiers {
+        mint: Pubkey,
+        fee_tiers: [FeeTier; MAX_FEE_TIERS],
+        fee_tier_count: u8,
+    },
+    FeeRecepient {
+        fee_recepient: Pubkey,
+    },
+    MaxReferralShare {
+        max_referral_share_bp: u16,
+    },
+    FeeDiscount {
+        user: Pubkey,
+        discount_bp: u16,
+    },
+    TokenAmountLimits {
+        mint: Pubkey,
+        min_amount: u64,
+        max_amount: u64,
+    },
 }
 
-#[derive(Accounts)]
-pub struct SetFeeRecepient<'info> {
-    pub admin: Signer<'info>,
-    pub fee_recepient: Signer<'info>,
+impl AdminChange {
+    /// `None` restricts the change to the super-admin.
+    fn role(&self) -> Option<Role> {
+        match self {
+            AdminChange::FeeRate { .. }
+            | AdminChange::FeeRounding { .. }
+            | AdminChange::FeeRecepient { .. }
+            | AdminChange::MaxReferralShare { .. }
+            | AdminChange::FeeDiscount { .. } => Some(Role::FeeManager),
+            AdminChange::TimelockDelay { .. } => None,
+            AdminChange::TokenMaxFee { .. }
+            | AdminChange::TokenFeeRate { .. }
+            | AdminChange::TokenFeeTiers { .. }
+            | AdminChange::TokenAmountLimits { .. } => Some(Role::TokenManager),
+        }
+    }
 
-    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
-    pub admin_settings: Account<'info, AdminSettings>,
-}
+    fn mint(&self) -> Option<Pubkey> {
+        match self {
+            AdminChange::TokenMaxFee { mint, .. }
+            | AdminChange::TokenFeeRate { mint, .. }
+            | AdminChange::TokenFeeTiers { mint, .. }
+            | AdminChange::TokenAmountLimits { mint, .. } => Some(*mint),
+            _ => None,
+        }
+    }
+
+    fn discount_user(&self) -> Option<Pubkey> {
+        match self {
+            AdminChange::FeeDiscount { user, .. } => Some(*user),
+            _ => None,
+        }
+    }
+
+    fn validate(&self, fee_rate_cap_bp: u16) -> Result<()> {
+        let check_rate = |fee_rate_bp: Option<u16>| -> Result<()> {
+            let fee_rate_bp = fee_rate_bp.unwrap_or(0);
+            require!(fee_rate_bp < 10000, Errors::InvalidFeeRate);
+            require!(fee_rate_bp <= fee_rate_cap_bp, Errors::FeeRateAboveCap);
+            Ok(())
+        };
+        match self {
+            AdminChange::FeeRate { fee_rate_bp } => check_rate(Some(*fee_rate_bp)),
+            AdminChange::TimelockDelay { delay } => {
+                require!(*delay >= 0, Errors::InvalidEffectiveTime);
+                Ok(())
+            }
+            AdminChange::TokenFeeRate { fee_rate_bp, .. } => check_rate(*fee_rate_bp),
+            AdminChange::TokenFeeTiers {
+                fee_tiers,
+                fee_tier_count,
+                ..
+            } => {
+                require!(
+                    *fee_tier_count as usize <= MAX_FEE_TIERS,
+                    Errors::InvalidFeeTiers
+                );
+                helpers::check_fee_tiers(&fee_tiers[..*fee_tier_count as usize], fee_rate_cap_bp)
+            }
+            AdminChange::MaxReferralShare {
+                max_referral_share_bp,
+            } => {
+                require!(
+                    *max_referral_share_bp <= 10000,
+                    Errors::InvalidReferralShare
+                );
+                Ok(())
+            }
+            AdminChange::FeeDiscount { discount_bp, .. } => {
+                require!(*discount_bp <= 10000, Errors::InvalidFeeDiscount);
+                Ok(())
+            }
+            AdminChange::TokenAmountLimits {
+                min_amount,
+                max_amount,
+                ..
+            } => {
+                require!(
+                    *max_amount == 0 || min_amount <= max_amount,
+                    Errors::InvalidAmountLimits
+                );
+                Ok(())
+            }
+            AdminChange::FeeRounding { .. }
+            | AdminChange::TokenMaxFee { .. }
+            | AdminChange::FeeRecepient { .. } => Ok(()),
+        }
+    }
+
+    /// `token_settings` must be the settings of `mint()` for token changes and `fee_discount` the
+    /// discount account of `discount_user()` for discount changes.
+    fn apply(
+        &self,
+        admin_settings: &mut AdminSettings,
+        token_settings: Option<&mut TokenSettings>,
+        fee_discount: Option<&mut FeeDiscount>,
+    ) {
+        match (*self, token_settings) {
+            (AdminChange::FeeRate { fee_rate_bp }, _) => admin_settings.fee_rate_bp = fee_rate_bp,
+            (AdminChange::FeeRounding { fee_rounding }, _) => {
+                admin_settings.fee_rounding = fee_rounding
+            }
+            (AdminChange::TimelockDelay { delay }, _) => admin_settings.timelock_delay = delay,
+            (AdminChange::TokenMaxFee { max_fee, .. }, Some(token_settings)) => {
+                token_settings.max_fee = max_fee
+            }
+            (
+                AdminChange::TokenFeeRate {
+                    fee_rate_bp,
+                    min_fee,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.fee_rate_bp = fee_rate_bp;
+                token_settings.min_fee = min_fee;
+            }
+            (
+                AdminChange::TokenFeeTiers {
+                    fee_tiers,
+                    fee_tier_count,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.fee_tiers = fee_tiers;
+                token_settings.fee_tier_count = fee_tier_count;
+            }
+            (AdminChange::FeeRecepient { fee_recepient }, _) => {
+                admin_settings.fee_recepient = fee_recepient
+            }
+            (
+                AdminChange::MaxReferralShare {
+                    max_referral_share_bp,
+                },
+                _,
+            ) => admin_settings.max_referral_share_bp = max_referral_share_bp,
+            (AdminChange::FeeDiscount { discount_bp, .. }, _) => {
+                if let Some(fee_discount) = fee_discount {
+                    fee_discount.discount_bp = discount_bp;
+                }
+            }
+            (
+                AdminChange::TokenAmountLimits {
+                    min_amount,
+                    max_amount,
+                    ..
+                },
+                Some(token_settings),
+            ) => {
+                token_settings.min_amount = min_amount;
+                token_settings.max_amount = max_amount;
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Referral {
+    pub referrer: Pubkey,
+    pub share_bp: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Lock {
+    pub agreement_reached_time: i64,
+    pub step_time: i64,
+}
+
+#[derive(Accounts)]
+#[instruction(admin: Pubkey, fee_rate_cap_bp: u16)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + AdminSettings::INIT_SPACE,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = fee_rate_cap_bp <= 10000 @ Errors::InvalidFeeRate,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ChangeAdmin<'info> {
+    pub admin: Signer<'info>,
+    pub new_admin: Signer<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetAdminSigners<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetRole<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeRecepient<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: fee recepient
+    pub fee_recepient: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
 
 #[derive(Accounts)]
 #[instruction(value: u16)]
 pub struct SetFeeRate<'info> {
-    pub admin: Signer<'info>,
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
         seeds = [ADMIN_SETTINGS_SEED],
         bump,
-        has_one = admin @ Errors::AccountMismatch,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
         constraint = value < 10000 @ Errors::InvalidFeeRate,
+        constraint = value <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeRounding<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(value: u16)]
+pub struct SetMaxReferralShare<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+        constraint = value <= 10000 @ Errors::InvalidReferralShare,
     )]
     pub admin_settings: Account<'info, AdminSettings>,
 }
@@ -523,22 +1570,363 @@ pub struct SetFeeRate<'info> {
 pub struct SetMaxFeeForToken<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    pub admin: Signer<'info>,
+    pub authority: Signer<'info>,
 
-    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey, fee_rate_bp: Option<u16>)]
+pub struct SetFeeRateForToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        constraint = fee_rate_bp.unwrap_or(0) < 10000 @ Errors::InvalidFeeRate,
+        constraint = fee_rate_bp.unwrap_or(0) <= admin_settings.fee_rate_cap_bp @ Errors::FeeRateAboveCap,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct SetFeeTiersForToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey, discount_bp: u16)]
+pub struct SetFeeDiscount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
     pub admin_settings: Account<'info, AdminSettings>,
     #[account(
         init_if_needed,
         payer = payer,
-        space = size_of::<TokenSettings>() + 8,
+        space = 8 + FeeDiscount::INIT_SPACE,
+        seeds = [FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()],
+        bump,
+        constraint = discount_bp <= 10000 @ Errors::InvalidFeeDiscount,
+    )]
+    pub fee_discount: Account<'info, FeeDiscount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct RemoveFeeDiscount<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(mut, close = authority, seeds = [FEE_DISCOUNT_SEED_PREFIX, &user.to_bytes()], bump)]
+    pub fee_discount: Account<'info, FeeDiscount>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey, min_amount: u64, max_amount: u64)]
+pub struct SetAmountLimitsForToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+        constraint = admin_settings.timelock_delay == 0 @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
         seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
         bump,
+        constraint = max_amount == 0 || min_amount <= max_amount @ Errors::InvalidAmountLimits,
     )]
     pub token_settings: Account<'info, TokenSettings>,
 
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct RegisterToken<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + TokenSettings::INIT_SPACE,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+    )]
+    pub token_settings: Account<'info, TokenSettings>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct DeregisterToken<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::TokenManager, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(mut, seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()], bump)]
+    pub token_settings: Account<'info, TokenSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(delay: i64)]
+pub struct SetTimelockDelay<'info> {
+    pub admin: Signer<'info>,
+
+    /// Shortening the delay has to wait out the current one through `queue_admin_change`.
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        has_one = admin @ Errors::AccountMismatch,
+        constraint = delay >= admin_settings.timelock_delay @ Errors::TimelockRequired,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64, change: AdminChange)]
+pub struct QueueAdminChange<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.can_change(&change, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PendingChange::INIT_SPACE,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    /// Opened empty for `FeeDiscount` changes, the permissionless execute cannot pay for it.
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + FeeDiscount::INIT_SPACE,
+        seeds = [FEE_DISCOUNT_SEED_PREFIX, &change.discount_user().unwrap_or_default().to_bytes()],
+        bump,
+    )]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct ExecuteAdminChange<'info> {
+    /// CHECK: rent receiver, checked against `pending_change.payer`
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        mut,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+        has_one = payer @ Errors::AccountMismatch,
+        close = payer,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+    #[account(mut)]
+    pub token_settings: Option<Account<'info, TokenSettings>>,
+    #[account(mut)]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(id: u64)]
+pub struct CancelAdminChange<'info> {
+    pub authority: Signer<'info>,
+    /// CHECK: rent receiver, checked against `pending_change.payer`
+    #[account(mut)]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    #[account(
+        mut,
+        seeds = [PENDING_CHANGE_SEED_PREFIX, &id.to_le_bytes()],
+        bump,
+        has_one = payer @ Errors::AccountMismatch,
+        constraint = admin_settings.can_change(&pending_change.change, &authority.key()) @ Errors::AccountMismatch,
+        close = payer,
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAdminSettings<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub admin: Signer<'info>,
+
+    /// CHECK: legacy layout, checked and upgraded by `migrate_admin_settings`
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump, owner = crate::ID)]
+    pub admin_settings: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mint: Pubkey)]
+pub struct MigrateTokenSettings<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub admin: Signer<'info>,
+
+    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = admin @ Errors::AccountMismatch)]
+    pub admin_settings: Account<'info, AdminSettings>,
+    /// CHECK: legacy layout, checked and upgraded by `migrate_token_settings`
+    #[account(
+        mut,
+        seeds = [TOKEN_SETTINGS_SEED_PREFIX, &mint.to_bytes()],
+        bump,
+        owner = crate::ID,
+    )]
+    pub token_settings: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(uuid: [u8; 32])]
+pub struct MigrateEscrow<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: legacy layout, checked and upgraded by `migrate_escrow`
+    #[account(mut, seeds = [&uuid], bump, owner = crate::ID)]
+    pub escrow: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetPause<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::Pauser, &authority.key()) @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [ADMIN_SETTINGS_SEED],
+        bump,
+        constraint = admin_settings.has_role(Role::FeeManager, &authority.key()) @ Errors::AccountMismatch,
+        has_one = fee_recepient @ Errors::AccountMismatch,
+    )]
+    pub admin_settings: Account<'info, AdminSettings>,
+    /// CHECK: fee recepient
+    #[account(mut)]
+    pub fee_recepient: UncheckedAccount<'info>,
+}
+
+#[event_cpi]
 #[derive(Accounts)]
 #[instruction(uuid: [u8; 32])]
 pub struct SubmitSwap<'info> {
@@ -548,27 +1936,38 @@ pub struct SubmitSwap<'info> {
     pub from: Signer<'info>,
     pub to: SystemAccount<'info>,
 
-    pub src_token: Option<Account<'info, Mint>>,
+    pub src_token: Option<InterfaceAccount<'info, Mint>>,
     #[account(mut)]
-    pub source: Option<Account<'info, TokenAccount>>,
+    pub source: Option<InterfaceAccount<'info, TokenAccount>>,
 
-    pub dst_token: Option<Account<'info, Mint>>,
+    pub dst_token: Option<InterfaceAccount<'info, Mint>>,
 
-    #[account(init, payer = payer, space = size_of::<Escrow>() + 8, seeds = [&uuid], bump)]
+    #[account(init, payer = payer, space = 8 + Escrow::INIT_SPACE, seeds = [&uuid], bump)]
     pub escrow: Account<'info, Escrow>,
-    #[account(init, payer = payer, associated_token::mint = src_token, associated_token::authority = escrow)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = src_token,
+        associated_token::authority = escrow,
+        associated_token::token_program = token_program,
+    )]
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(seeds = [ADMIN_SETTINGS_SEED], bump)]
     pub admin_settings: Account<'info, AdminSettings>,
-    pub src_token_settings: Option<Account<'info, TokenSettings>>,
-    pub dst_token_settings: Option<Account<'info, TokenSettings>>,
+    pub src_token_settings: Account<'info, TokenSettings>,
+    pub dst_token_settings: Account<'info, TokenSettings>,
+    #[account(seeds = [FEE_DISCOUNT_SEED_PREFIX, from.key().as_ref()], bump)]
+    pub fee_discount: Option<Account<'info, FeeDiscount>>,
+    /// CHECK: referrer of the escrow, only checked to be able to claim
+    pub referrer: Option<UncheckedAccount<'info>>,
 
     pub associated_token_program: Option<Program<'info, AssociatedToken>>,
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
+    pub token_program: Option<Interface<'info, TokenInterface>>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 #[instruction(uuid: [u8; 32])]
 pub struct ConfirmSwap<'info> {
@@ -576,14 +1975,29 @@ pub struct ConfirmSwap<'info> {
     pub payer: Signer<'info>,
     #[account(mut)]
     pub from: SystemAccount<'info>,
-    #[account(mut, associated_token::mint = escrow.dst_token, associated_token::authority = escrow.from)]
-    pub from_destination: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        associated_token::mint = escrow.dst_token,
+        associated_token::authority = escrow.from,
+        associated_token::token_program = dst_token_program,
+    )]
+    pub from_destination: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut)]
     pub to: Signer<'info>,
-    #[account(mut, associated_token::mint = escrow.dst_token, associated_token::authority = escrow.to)]
-    pub to_source: Option<Account<'info, TokenAccount>>,
-    #[account(mut, associated_token::mint = escrow.src_token, associated_token::authority = escrow.to)]
-    pub to_destination: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        associated_token::mint = escrow.dst_token,
+        associated_token::authority = escrow.to,
+        associated_token::token_program = dst_token_program,
+    )]
+    pub to_source: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        associated_token::mint = escrow.src_token,
+        associated_token::authority = escrow.to,
+        associated_token::token_program = token_program,
+    )]
+    pub to_destination: Option<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
@@ -594,31 +2008,103 @@ pub struct ConfirmSwap<'info> {
         constraint = escrow.src_amount > 0 @ Errors::EscrowClosed,
         constraint = escrow.dst_amount > 0 @ Errors::EscrowClosed,
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub escrow: Account<'info, AnyVersionEscrow>,
     #[account(mut)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = escrow.src_token @ Errors::AccountMismatch)]
+    pub src_token: Option<InterfaceAccount<'info, Mint>>,
+    #[account(address = escrow.dst_token @ Errors::AccountMismatch)]
+    pub dst_token: Option<InterfaceAccount<'info, Mint>>,
 
-    #[account(seeds = [ADMIN_SETTINGS_SEED], bump, has_one = fee_recepient)]
+    #[account(mut, seeds = [ADMIN_SETTINGS_SEED], bump)]
     pub admin_settings: Account<'info, AdminSettings>,
-    /// CHECK: fee recepient
-    #[account(mut)]
-    pub fee_recepient: UncheckedAccount<'info>,
-    #[account(mut, associated_token::mint = escrow.src_token, associated_token::authority = admin_settings.fee_recepient)]
-    pub src_fee_destination: Option<Account<'info, TokenAccount>>,
-    #[account(mut, associated_token::mint = escrow.dst_token, associated_token::authority = admin_settings.fee_recepient)]
-    pub dst_fee_destination: Option<Account<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = src_token,
+        associated_token::authority = admin_settings,
+        associated_token::token_program = token_program,
+    )]
+    pub src_fee_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = dst_token,
+        associated_token::authority = admin_settings,
+        associated_token::token_program = dst_token_program,
+    )]
+    pub dst_fee_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + ReferralVault::INIT_SPACE,
+        seeds = [REFERRAL_VAULT_SEED_PREFIX, escrow.referrer.as_ref()],
+        bump,
+        constraint = escrow.has_referrer() @ Errors::AccountMismatch,
+    )]
+    pub referral_vault: Option<Account<'info, ReferralVault>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = src_token,
+        associated_token::authority = referral_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub src_referral_vault_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = dst_token,
+        associated_token::authority = referral_vault,
+        associated_token::token_program = dst_token_program,
+    )]
+    pub dst_referral_vault_ata: Option<InterfaceAccount<'info, TokenAccount>>,
 
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
+    #[account(address = escrow.src_token_program @ Errors::AccountMismatch)]
+    pub token_program: Option<Interface<'info, TokenInterface>>,
+    #[account(address = escrow.dst_token_program @ Errors::AccountMismatch)]
+    pub dst_token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRAL_VAULT_SEED_PREFIX, referrer.key().as_ref()],
+        bump,
+        has_one = referrer @ Errors::AccountMismatch,
+    )]
+    pub referral_vault: Account<'info, ReferralVault>,
+    pub token: Option<InterfaceAccount<'info, Mint>>,
+    #[account(
+        mut,
+        associated_token::mint = token,
+        associated_token::authority = referral_vault,
+        associated_token::token_program = token_program,
+    )]
+    pub referral_vault_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Option<Interface<'info, TokenInterface>>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 #[instruction(uuid: [u8; 32])]
 pub struct RefundSwap<'info> {
     #[account(mut)]
     pub from: SystemAccount<'info>,
-    #[account(mut)]
-    pub source: Option<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = source.owner == escrow.from && source.mint == escrow.src_token @ Errors::AccountMismatch,
+    )]
+    pub source: Option<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
@@ -627,28 +2113,219 @@ pub struct RefundSwap<'info> {
         has_one = from @ Errors::AccountMismatch,
         constraint = escrow.src_amount > 0 @ Errors::EscrowClosed,
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub escrow: Account<'info, AnyVersionEscrow>,
     #[account(mut)]
-    pub escrow_ata: Option<Account<'info, TokenAccount>>,
+    pub escrow_ata: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, address = escrow.src_token @ Errors::AccountMismatch)]
+    pub src_token: Option<InterfaceAccount<'info, Mint>>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Option<Program<'info, Token>>,
+    #[account(address = escrow.src_token_program @ Errors::AccountMismatch)]
+    pub token_program: Option<Interface<'info, TokenInterface>>,
 }
 
 #[account]
+#[derive(InitSpace)]
 pub struct AdminSettings {
+    pub version: u8,
     pub admin: Pubkey,
     pub fee_recepient: Pubkey,
     pub fee_rate_bp: u16,
+    /// Blocks new swaps, confirm and refund stay available.
+    pub paused: bool,
+    /// Upper bound for the fee share a referrer can be given on new escrows.
+    pub max_referral_share_bp: u16,
+    /// Rounding applied to fee rates, `Ceil` keeps split orders from paying less.
+    pub fee_rounding: FeeRounding,
+    /// Admin instructions also need `admin_threshold` of these keys to sign, 0 disables the check.
+    pub admin_signers: [Pubkey; MAX_ADMIN_SIGNERS],
+    pub admin_signer_count: u8,
+    pub admin_threshold: u8,
+    /// Role keys, `admin` acts as super-admin and holds every role.
+    pub fee_manager: Pubkey,
+    pub token_manager: Pubkey,
+    pub pauser: Pubkey,
+    /// Minimum wait for queued fee changes, direct fee setters are disabled while non-zero.
+    pub timelock_delay: i64,
+    /// Set at initialize and never updated, no fee can exceed this share of the amount.
+    pub fee_rate_cap_bp: u16,
+    pub reserved: [u8; 256],
+}
+
+impl Default for AdminSettings {
+    /// An account as `initialize` leaves it before the admin and fee cap are set.
+    fn default() -> Self {
+        Self {
+            version: ACCOUNT_VERSION,
+            admin: Pubkey::default(),
+            fee_recepient: Pubkey::default(),
+            fee_rate_bp: 0,
+            paused: false,
+            max_referral_share_bp: 0,
+            fee_rounding: FeeRounding::Floor,
+            admin_signers: [Pubkey::default(); MAX_ADMIN_SIGNERS],
+            admin_signer_count: 0,
+            admin_threshold: 0,
+            fee_manager: Pubkey::default(),
+            token_manager: Pubkey::default(),
+            pauser: Pubkey::default(),
+            timelock_delay: 0,
+            fee_rate_cap_bp: 0,
+            reserved: [0; 256],
+        }
+    }
+}
+
+impl AdminSettings {
+    fn has_role(&self, role: Role, key: &Pubkey) -> bool {
+        let role_key = match role {
+            Role::FeeManager => self.fee_manager,
+            Role::TokenManager => self.token_manager,
+            Role::Pauser => self.pauser,
+        };
+        *key == self.admin || *key == role_key
+    }
+
+    fn can_change(&self, change: &AdminChange, key: &Pubkey) -> bool {
+        match change.role() {
+            Some(role) => self.has_role(role, key),
+            None => *key == self.admin,
+        }
+    }
+
+    /// Counts `admin` and the signing remaining accounts that belong to the signer set.
+    fn check_admin_approvals(&self, admin: &Pubkey, co_signers: &[AccountInfo]) -> Result<()> {
+        let members = &self.admin_signers[..self.admin_signer_count as usize];
+        let signers = co_signers
+            .iter()
+            .filter(|account| account.is_signer)
+            .map(|account| account.key);
+        let mut approvals: Vec<&Pubkey> = Vec::new();
+        for signer in std::iter::once(admin).chain(signers) {
+            if members.contains(signer) && !approvals.contains(&signer) {
+                approvals.push(signer);
+            }
+        }
+        require!(
+            approvals.len() >= self.admin_threshold as usize,
+            Errors::NotEnoughAdminSigners
+        );
+        Ok(())
+    }
+
+    /// The referrer claims its shares later, so it has to be a key that can sign.
+    fn check_referral(
+        &self,
+        referral: Option<&Referral>,
+        referrer: Option<&AccountInfo>,
+    ) -> Result<()> {
+        if let Some(referral) = referral {
+            require!(
+                referral.share_bp <= self.max_referral_share_bp,
+                Errors::InvalidReferralShare
+            );
+            if referral.share_bp > 0 {
+                let referrer = referrer.ok_or(Errors::InvalidReferrer)?;
+                require!(
+                    referral.referrer != Pubkey::default()
+                        && referrer.key() == referral.referrer
+                        && !referrer.executable,
+                    Errors::InvalidReferrer
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct FeeTier {
+    pub min_amount: u64,
+    pub fee_rate_bp: u16,
 }
 
 #[account]
+#[derive(InitSpace)]
 pub struct TokenSettings {
+    pub version: u8,
     pub max_fee: u64,
+    /// Overrides `AdminSettings.fee_rate_bp` when set and no fee tier applies.
+    pub fee_rate_bp: Option<u16>,
+    pub min_fee: u64,
+    /// Only registered tokens, or native SOL under the zero key, can be used on either side of a new swap.
+    pub enabled: bool,
+    pub min_amount: u64,
+    /// 0 means no upper limit.
+    pub max_amount: u64,
+    /// Volume pricing, `fee_tier_count` tiers sorted by ascending `min_amount`.
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    pub fee_tier_count: u8,
+    pub reserved: [u8; 128],
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            version: ACCOUNT_VERSION,
+            max_fee: 0,
+            fee_rate_bp: None,
+            min_fee: 0,
+            enabled: false,
+            min_amount: 0,
+            max_amount: 0,
+            fee_tiers: [FeeTier::default(); MAX_FEE_TIERS],
+            fee_tier_count: 0,
+            reserved: [0; 128],
+        }
+    }
+}
+
+impl TokenSettings {
+    fn tier_fee_rate_bp(&self, amount: u64) -> Option<u16> {
+        self.fee_tiers[..self.fee_tier_count as usize]
+            .iter()
+            .rev()
+            .find(|tier| amount >= tier.min_amount)
+            .map(|tier| tier.fee_rate_bp)
+    }
+
+    fn check_amount(&self, amount: u64) -> Result<()> {
+        require!(
+            amount >= self.min_amount && (self.max_amount == 0 || amount <= self.max_amount),
+            Errors::AmountOutOfRange
+        );
+        Ok(())
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct FeeDiscount {
+    /// Share of the fee waived for the user, 10000 means fee exempt.
+    pub discount_bp: u16,
+}
+
+/// Holds the referral shares of `referrer`, SOL as lamports and tokens in its associated token accounts.
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralVault {
+    pub referrer: Pubkey,
 }
 
+/// Escrows snapshot their fees at submit, so the change only affects later ones.
 #[account]
+#[derive(InitSpace)]
+pub struct PendingChange {
+    pub change: AdminChange,
+    pub effective_time: i64,
+    /// Gets the rent back once the change is executed or cancelled.
+    pub payer: Pubkey,
+}
+
+#[account]
+#[derive(InitSpace)]
 pub struct Escrow {
+    pub version: u8,
     pub from: Pubkey,
     pub to: Pubkey,
     pub src_token: Pubkey,
@@ -658,4 +2335,523 @@ pub struct Escrow {
     pub src_token_fee: u64,
     pub dst_token_fee: u64,
     pub lock: Lock,
+    pub src_token_program: Pubkey,
+    pub dst_token_program: Pubkey,
+    /// Receives `referral_share_bp` of the fees on confirm, zero when there is no referrer.
+    pub referrer: Pubkey,
+    pub referral_share_bp: u16,
+    pub reserved: [u8; 64],
+}
+
+impl Escrow {
+    fn has_referrer(&self) -> bool {
+        self.referral_share_bp > 0 && self.referrer != Pubkey::default()
+    }
+
+    fn referral_fee(&self, fee: u64) -> Result<u64> {
+        helpers::mul_bp(fee, self.referral_share_bp, FeeRounding::Floor)
+    }
+}
+
+/// An `Escrow` of the current or the legacy layout, legacy ones upgraded in memory. Confirm and
+/// refund close the escrow, so a legacy escrow settles without being migrated first and the
+/// upgraded value is never written back.
+#[derive(Clone)]
+pub struct AnyVersionEscrow(Escrow);
+
+impl AccountDeserialize for AnyVersionEscrow {
+    fn try_deserialize(buf: &mut &[u8]) -> Result<Self> {
+        if buf.len() == <legacy::Escrow as legacy::LegacyAccount>::LEN {
+            return Ok(Self(legacy::read::<legacy::Escrow, Escrow>(buf)?.into()));
+        }
+        Escrow::try_deserialize(buf).map(Self)
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> Result<Self> {
+        Escrow::try_deserialize_unchecked(buf).map(Self)
+    }
+}
+
+impl AccountSerialize for AnyVersionEscrow {
+    fn try_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        self.0.try_serialize(writer)
+    }
+}
+
+impl Owner for AnyVersionEscrow {
+    fn owner() -> Pubkey {
+        Escrow::owner()
+    }
+}
+
+impl Deref for AnyVersionEscrow {
+    type Target = Escrow;
+
+    fn deref(&self) -> &Escrow {
+        &self.0
+    }
+}
+
+impl DerefMut for AnyVersionEscrow {
+    fn deref_mut(&mut self) -> &mut Escrow {
+        &mut self.0
+    }
+}
+
+#[event]
+pub struct SwapSubmitted {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub src_token: Pubkey,
+    pub dst_token: Pubkey,
+    pub src_amount: u64,
+    pub dst_amount: u64,
+    pub src_token_fee: u64,
+    pub dst_token_fee: u64,
+}
+
+#[event]
+pub struct SwapConfirmed {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub src_token: Pubkey,
+    pub dst_token: Pubkey,
+    pub src_amount: u64,
+    pub dst_amount: u64,
+    pub src_token_fee: u64,
+    pub dst_token_fee: u64,
+    pub referrer: Pubkey,
+    /// Shares of the fees accrued to the referral vault of `referrer`.
+    pub src_referral_fee: u64,
+    pub dst_referral_fee: u64,
+}
+
+#[event]
+pub struct SwapRefunded {
+    pub uuid: [u8; 32],
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub src_token: Pubkey,
+    pub dst_token: Pubkey,
+    pub src_amount: u64,
+    pub dst_amount: u64,
+    pub src_token_fee: u64,
+    pub dst_token_fee: u64,
+}
+
+/// Layouts of the first, unversioned release. Their accounts count as version 0.
+mod legacy {
+    use super::*;
+
+    #[derive(AnchorDeserialize)]
+    pub struct AdminSettings {
+        pub admin: Pubkey,
+        pub fee_recepient: Pubkey,
+        pub fee_rate_bp: u16,
+    }
+
+    #[derive(AnchorDeserialize)]
+    pub struct TokenSettings {
+        pub max_fee: u64,
+    }
+
+    #[derive(AnchorDeserialize)]
+    pub struct Escrow {
+        pub from: Pubkey,
+        pub to: Pubkey,
+        pub src_token: Pubkey,
+        pub dst_token: Pubkey,
+        pub src_amount: u64,
+        pub dst_amount: u64,
+        pub src_token_fee: u64,
+        pub dst_token_fee: u64,
+        pub lock: Lock,
+    }
+
+    impl AdminSettings {
+        /// The legacy program had no fee cap, so the migrating admin picks one that covers the current rate.
+        pub fn upgrade(self, fee_rate_cap_bp: u16) -> Result<super::AdminSettings> {
+            require!(
+                fee_rate_cap_bp >= self.fee_rate_bp && fee_rate_cap_bp <= 10000,
+                Errors::InvalidFeeRate
+            );
+            Ok(super::AdminSettings {
+                admin: self.admin,
+                fee_recepient: self.fee_recepient,
+                fee_rate_bp: self.fee_rate_bp,
+                fee_rate_cap_bp,
+                ..Default::default()
+            })
+        }
+    }
+
+    impl From<TokenSettings> for super::TokenSettings {
+        fn from(legacy: TokenSettings) -> Self {
+            Self {
+                max_fee: legacy.max_fee,
+                // the legacy program accepted every token it had settings for
+                enabled: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<Escrow> for super::Escrow {
+        fn from(legacy: Escrow) -> Self {
+            // the legacy program only moved tokens of the classic token program
+            let token_program = |token: Pubkey| {
+                if token == Pubkey::default() {
+                    Pubkey::default()
+                } else {
+                    anchor_spl::token::ID
+                }
+            };
+            Self {
+                version: ACCOUNT_VERSION,
+                from: legacy.from,
+                to: legacy.to,
+                src_token: legacy.src_token,
+                dst_token: legacy.dst_token,
+                src_amount: legacy.src_amount,
+                dst_amount: legacy.dst_amount,
+                src_token_fee: legacy.src_token_fee,
+                dst_token_fee: legacy.dst_token_fee,
+                lock: legacy.lock,
+                src_token_program: token_program(legacy.src_token),
+                dst_token_program: token_program(legacy.dst_token),
+                referrer: Pubkey::default(),
+                referral_share_bp: 0,
+                reserved: [0; 64],
+            }
+        }
+    }
+
+    /// A layout of the first release and the length its accounts were allocated with,
+    /// discriminator included.
+    pub trait LegacyAccount: AnchorDeserialize {
+        const LEN: usize;
+    }
+
+    impl LegacyAccount for AdminSettings {
+        const LEN: usize = 74;
+    }
+
+    impl LegacyAccount for TokenSettings {
+        const LEN: usize = 16;
+    }
+
+    impl LegacyAccount for Escrow {
+        const LEN: usize = 184;
+    }
+
+    /// Reads `data` as a legacy `L` account of the current type `T`.
+    ///
+    /// Accounts of exactly `L::LEN` bytes are legacy, accounts of any other size carry their
+    /// layout version right after the discriminator.
+    pub fn read<L, T>(data: &[u8]) -> Result<L>
+    where
+        L: LegacyAccount,
+        T: anchor_lang::Discriminator,
+    {
+        require!(
+            data.starts_with(&T::DISCRIMINATOR),
+            anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+        );
+        let version = if data.len() == L::LEN {
+            0
+        } else {
+            data.get(8).copied().unwrap_or_default()
+        };
+        require!(version < ACCOUNT_VERSION, Errors::AlreadyMigrated);
+        Ok(L::deserialize(&mut &data[8..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::Discriminator;
+    use helpers::{calculate_fee, mul_bp};
+    use proptest::prelude::*;
+
+    fn admin_settings(fee_rate_bp: u16, fee_rounding: FeeRounding) -> AdminSettings {
+        AdminSettings {
+            fee_rate_bp,
+            fee_rounding,
+            fee_rate_cap_bp: 10000,
+            ..Default::default()
+        }
+    }
+
+    fn token_settings(min_fee: u64, max_fee: u64) -> TokenSettings {
+        TokenSettings {
+            max_fee,
+            min_fee,
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn fee_rounding() -> impl Strategy<Value = FeeRounding> {
+        prop_oneof![Just(FeeRounding::Floor), Just(FeeRounding::Ceil)]
+    }
+
+    #[test]
+    fn fee_on_max_amount_does_not_overflow() {
+        let expected_floor = (u64::MAX as u128 * 9999 / 10000) as u64;
+        let token_settings = token_settings(0, 0);
+        let floor = calculate_fee(
+            u64::MAX,
+            &admin_settings(9999, FeeRounding::Floor),
+            &token_settings,
+        )
+        .unwrap();
+        let ceil = calculate_fee(
+            u64::MAX,
+            &admin_settings(9999, FeeRounding::Ceil),
+            &token_settings,
+        )
+        .unwrap();
+        assert_eq!(floor, expected_floor);
+        assert_eq!(ceil, expected_floor + 1);
+    }
+
+    #[test]
+    fn mul_bp_rejects_rates_above_one_hundred_percent() {
+        assert!(mul_bp(u64::MAX, 10001, FeeRounding::Floor).is_err());
+        assert!(mul_bp(1, u16::MAX, FeeRounding::Ceil).is_err());
+        assert_eq!(
+            mul_bp(u64::MAX, 10000, FeeRounding::Ceil).unwrap(),
+            u64::MAX
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn mul_bp_rounds_to_nearest_bound(amount: u64, bp in 0u16..=10000) {
+            let exact = amount as u128 * bp as u128;
+            let floor = mul_bp(amount, bp, FeeRounding::Floor).unwrap();
+            let ceil = mul_bp(amount, bp, FeeRounding::Ceil).unwrap();
+            prop_assert!(floor as u128 * 10000 <= exact);
+            prop_assert!(ceil as u128 * 10000 >= exact);
+            prop_assert!(ceil - floor <= 1);
+            prop_assert_eq!(ceil == floor, floor as u128 * 10000 == exact);
+            prop_assert!(ceil <= amount);
+        }
+
+        #[test]
+        fn fee_stays_within_bounds(
+            amount: u64,
+            fee_rate_bp in 0u16..10000,
+            min_fee: u64,
+            max_fee: u64,
+            fee_rounding in fee_rounding(),
+        ) {
+            let fee = calculate_fee(
+                amount,
+                &admin_settings(fee_rate_bp, fee_rounding),
+                &token_settings(min_fee, max_fee),
+            ).unwrap();
+            prop_assert!(fee <= amount);
+            if max_fee > 0 {
+                prop_assert!(fee <= max_fee);
+            }
+            let floor = if max_fee > 0 { min_fee.min(max_fee) } else { min_fee };
+            prop_assert!(fee >= floor.min(amount));
+        }
+
+        #[test]
+        fn fee_never_exceeds_protocol_cap(
+            amount: u64,
+            fee_rate_bp in 0u16..10000,
+            fee_rate_cap_bp in 0u16..=10000,
+            min_fee: u64,
+            max_fee: u64,
+            fee_rounding in fee_rounding(),
+        ) {
+            let mut admin_settings = admin_settings(fee_rate_bp, fee_rounding);
+            admin_settings.fee_rate_cap_bp = fee_rate_cap_bp;
+            let fee = calculate_fee(
+                amount,
+                &admin_settings,
+                &token_settings(min_fee, max_fee),
+            ).unwrap();
+            prop_assert!(fee <= mul_bp(amount, fee_rate_cap_bp, fee_rounding).unwrap());
+        }
+
+        #[test]
+        fn ceil_fee_is_at_most_one_above_floor(amount: u64, fee_rate_bp in 0u16..10000) {
+            let token_settings = token_settings(0, 0);
+            let floor_settings = admin_settings(fee_rate_bp, FeeRounding::Floor);
+            let ceil_settings = admin_settings(fee_rate_bp, FeeRounding::Ceil);
+            let floor = calculate_fee(amount, &floor_settings, &token_settings).unwrap();
+            let ceil = calculate_fee(amount, &ceil_settings, &token_settings).unwrap();
+            prop_assert!(floor <= ceil && ceil - floor <= 1);
+        }
+
+        #[test]
+        fn ceil_fee_cannot_be_reduced_by_splitting(
+            first in 0u64..u64::MAX / 2,
+            second in 0u64..u64::MAX / 2,
+            fee_rate_bp in 0u16..10000,
+        ) {
+            let admin_settings = admin_settings(fee_rate_bp, FeeRounding::Ceil);
+            let token_settings = token_settings(0, 0);
+            let split = calculate_fee(first, &admin_settings, &token_settings).unwrap()
+                + calculate_fee(second, &admin_settings, &token_settings).unwrap();
+            let whole = calculate_fee(first + second, &admin_settings, &token_settings).unwrap();
+            prop_assert!(split >= whole);
+        }
+
+        #[test]
+        fn tier_rates_use_the_same_rounding(
+            amount: u64,
+            tier_min_amount: u64,
+            tier_fee_rate_bp in 0u16..10000,
+            fee_rounding in fee_rounding(),
+        ) {
+            let admin_settings = admin_settings(0, fee_rounding);
+            let mut token_settings = token_settings(0, 0);
+            token_settings.fee_tiers[0] = FeeTier {
+                min_amount: tier_min_amount,
+                fee_rate_bp: tier_fee_rate_bp,
+            };
+            token_settings.fee_tier_count = 1;
+
+            let expected_rate_bp = if amount >= tier_min_amount {
+                tier_fee_rate_bp
+            } else {
+                0
+            };
+            let fee = calculate_fee(amount, &admin_settings, &token_settings).unwrap();
+            prop_assert_eq!(fee, mul_bp(amount, expected_rate_bp, fee_rounding).unwrap());
+        }
+    }
+
+    /// Account data as the first release allocated and wrote it: `len` bytes holding the
+    /// discriminator and the Borsh encoded `fields`, zero padded.
+    fn baseline_account_data(discriminator: [u8; 8], len: usize, fields: &[&[u8]]) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        fields
+            .iter()
+            .for_each(|field| data.extend_from_slice(field));
+        assert!(data.len() <= len);
+        data.resize(len, 0);
+        data
+    }
+
+    /// Writes `value` into an account of the current size and reads it back.
+    fn reload<T: AccountSerialize + AccountDeserialize + Space>(value: &T) -> (Vec<u8>, T) {
+        let mut data = vec![0; 8 + T::INIT_SPACE];
+        value.try_serialize(&mut &mut data[..]).unwrap();
+        let reloaded = T::try_deserialize(&mut &data[..]).unwrap();
+        (data, reloaded)
+    }
+
+    #[test]
+    fn migrate_baseline_admin_settings() {
+        let admin = Pubkey::new_from_array([1; 32]);
+        let fee_recepient = Pubkey::new_from_array([2; 32]);
+        let data = baseline_account_data(
+            AdminSettings::DISCRIMINATOR,
+            74,
+            &[admin.as_ref(), fee_recepient.as_ref(), &30u16.to_le_bytes()],
+        );
+        let read = || legacy::read::<legacy::AdminSettings, AdminSettings>(&data).unwrap();
+
+        // the cap has to cover the rate the account already charges
+        assert_eq!(
+            read().upgrade(29).err(),
+            Some(Errors::InvalidFeeRate.into())
+        );
+        assert_eq!(
+            read().upgrade(10001).err(),
+            Some(Errors::InvalidFeeRate.into())
+        );
+        let (migrated, admin_settings) = reload(&read().upgrade(100).unwrap());
+
+        assert!(migrated.len() > data.len());
+        assert_eq!(admin_settings.version, ACCOUNT_VERSION);
+        assert_eq!(admin_settings.admin, admin);
+        assert_eq!(admin_settings.fee_recepient, fee_recepient);
+        assert_eq!(admin_settings.fee_rate_bp, 30);
+        assert_eq!(admin_settings.fee_rate_cap_bp, 100);
+        assert!(!admin_settings.paused);
+        assert_eq!(admin_settings.fee_rounding, FeeRounding::Floor);
+        assert_eq!(admin_settings.admin_threshold, 0);
+        assert_eq!(
+            legacy::read::<legacy::AdminSettings, AdminSettings>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+    }
+
+    #[test]
+    fn migrate_baseline_token_settings() {
+        let data = baseline_account_data(TokenSettings::DISCRIMINATOR, 16, &[&50u64.to_le_bytes()]);
+        let token_settings: TokenSettings =
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&data)
+                .unwrap()
+                .into();
+        let (migrated, token_settings) = reload(&token_settings);
+
+        assert_eq!(token_settings.version, ACCOUNT_VERSION);
+        assert_eq!(token_settings.max_fee, 50);
+        assert_eq!(token_settings.fee_rate_bp, None);
+        assert!(token_settings.enabled);
+        assert_eq!(token_settings.max_amount, 0);
+        assert_eq!(token_settings.tier_fee_rate_bp(u64::MAX), None);
+        assert_eq!(
+            legacy::read::<legacy::TokenSettings, TokenSettings>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+    }
+
+    #[test]
+    fn migrate_baseline_escrow() {
+        let from = Pubkey::new_from_array([1; 32]);
+        let to = Pubkey::new_from_array([2; 32]);
+        let src_token = Pubkey::new_from_array([3; 32]);
+        let amounts = [1_000_000u64, 500, 1_000, 5].map(u64::to_le_bytes);
+        let times = [1_700_000_000i64, 5].map(i64::to_le_bytes);
+        let mut fields: Vec<&[u8]> = vec![from.as_ref(), to.as_ref(), src_token.as_ref(), &[0; 32]];
+        fields.extend(amounts.iter().map(|amount| &amount[..]));
+        fields.extend(times.iter().map(|time| &time[..]));
+        let mut data = baseline_account_data(Escrow::DISCRIMINATOR, 184, &fields);
+
+        let escrow: Escrow = legacy::read::<legacy::Escrow, Escrow>(&data)
+            .unwrap()
+            .into();
+        let (migrated, escrow) = reload(&escrow);
+
+        assert_eq!(escrow.version, ACCOUNT_VERSION);
+        assert_eq!(escrow.from, from);
+        assert_eq!(escrow.to, to);
+        assert_eq!(escrow.src_token, src_token);
+        assert_eq!(escrow.dst_token, Pubkey::default());
+        assert_eq!(escrow.src_amount, 1_000_000);
+        assert_eq!(escrow.dst_token_fee, 5);
+        assert_eq!(escrow.lock.agreement_reached_time, 1_700_000_000);
+        assert_eq!(escrow.lock.step_time, 5);
+        assert_eq!(escrow.src_token_program, anchor_spl::token::ID);
+        assert_eq!(escrow.dst_token_program, Pubkey::default());
+        assert_eq!(escrow.referrer, Pubkey::default());
+        assert_eq!(escrow.referral_share_bp, 0);
+        assert_eq!(
+            legacy::read::<legacy::Escrow, Escrow>(&migrated).err(),
+            Some(Errors::AlreadyMigrated.into())
+        );
+
+        // confirm and refund load the escrow whether or not it was migrated
+        let loaded = AnyVersionEscrow::try_deserialize(&mut &data[..]).unwrap();
+        assert_eq!(loaded.from, from);
+        assert_eq!(loaded.lock.step_time, 5);
+        let loaded = AnyVersionEscrow::try_deserialize(&mut &migrated[..]).unwrap();
+        assert_eq!(loaded.version, ACCOUNT_VERSION);
+
+        data[0] ^= 1;
+        assert!(legacy::read::<legacy::Escrow, Escrow>(&data).is_err());
+        assert!(AnyVersionEscrow::try_deserialize(&mut &data[..]).is_err());
+    }
 }
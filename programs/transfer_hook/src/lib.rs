@@ -0,0 +1,108 @@
+use anchor_lang::prelude::*;
+use spl_tlv_account_resolution::{
+    account::ExtraAccountMeta, seeds::Seed, state::ExtraAccountMetaList,
+};
+use spl_transfer_hook_interface::instruction::{ExecuteInstruction, TransferHookInstruction};
+
+declare_id!("9A3xhWK5VXMgzEcQ3v3pfoD6mt8g9ABzSKMMumToLhwQ");
+
+const EXTRA_ACCOUNT_METAS_SEED: &[u8] = b"extra-account-metas";
+const COUNTER_SEED: &[u8] = b"counter";
+
+/// Transfer hook for the tests, it requires a per mint counter as extra account and
+/// counts the transfers so the tests can tell the hook accounts were forwarded.
+#[program]
+pub mod transfer_hook {
+    use super::*;
+
+    pub fn initialize_extra_account_meta_list(
+        ctx: Context<InitializeExtraAccountMetaList>,
+    ) -> Result<()> {
+        // the counter is derived from the mint, which is account 1 of the execute instruction
+        let extra_account_metas = [ExtraAccountMeta::new_with_seeds(
+            &[
+                Seed::Literal {
+                    bytes: COUNTER_SEED.to_vec(),
+                },
+                Seed::AccountKey { index: 1 },
+            ],
+            false,
+            true,
+        )?];
+        ExtraAccountMetaList::init::<ExecuteInstruction>(
+            &mut ctx.accounts.extra_account_meta_list.try_borrow_mut_data()?,
+            &extra_account_metas,
+        )?;
+        Ok(())
+    }
+
+    pub fn execute(ctx: Context<Execute>, _amount: u64) -> Result<()> {
+        ctx.accounts.counter.count += 1;
+        Ok(())
+    }
+
+    /// Token-2022 calls the hook with the interface discriminator instead of the anchor one.
+    pub fn fallback<'info>(
+        program_id: &Pubkey,
+        accounts: &'info [AccountInfo<'info>],
+        data: &[u8],
+    ) -> Result<()> {
+        match TransferHookInstruction::unpack(data)? {
+            TransferHookInstruction::Execute { amount } => {
+                __private::__global::execute(program_id, accounts, &amount.to_le_bytes())
+            }
+            _ => Err(ProgramError::InvalidInstructionData.into()),
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeExtraAccountMetaList<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: written as an ExtraAccountMetaList
+    #[account(
+        init,
+        payer = payer,
+        space = ExtraAccountMetaList::size_of(1)?,
+        seeds = [EXTRA_ACCOUNT_METAS_SEED, mint.key().as_ref()],
+        bump,
+    )]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+    /// CHECK: mint the hook is configured for
+    pub mint: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Counter::INIT_SPACE,
+        seeds = [COUNTER_SEED, mint.key().as_ref()],
+        bump,
+    )]
+    pub counter: Account<'info, Counter>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Execute<'info> {
+    /// CHECK: source token account
+    pub source: UncheckedAccount<'info>,
+    /// CHECK: mint
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: destination token account
+    pub destination: UncheckedAccount<'info>,
+    /// CHECK: source owner or delegate
+    pub authority: UncheckedAccount<'info>,
+    /// CHECK: ExtraAccountMetaList
+    #[account(seeds = [EXTRA_ACCOUNT_METAS_SEED, mint.key().as_ref()], bump)]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+    #[account(mut, seeds = [COUNTER_SEED, mint.key().as_ref()], bump)]
+    pub counter: Account<'info, Counter>,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Counter {
+    pub count: u64,
+}
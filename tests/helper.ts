@@ -1,4 +1,4 @@
-import { web3 } from "@coral-xyz/anchor";
+import { web3, Program, utils } from "@coral-xyz/anchor";
 import {
     createMint,
     getOrCreateAssociatedTokenAccount,
@@ -6,6 +6,13 @@ import {
     TOKEN_PROGRAM_ID,
     AccountLayout,
     getMint,
+    ExtensionType,
+    getMintLen,
+    createInitializeTransferFeeConfigInstruction,
+    createInitializeTransferHookInstruction,
+    createInitializeMintInstruction,
+    getExtraAccountMetaAddress,
+    TOKEN_2022_PROGRAM_ID,
 } from "@solana/spl-token";
 import BN from "bn.js";
 import { keccak_256 } from "@noble/hashes/sha3";
@@ -46,6 +53,7 @@ export async function createSPLTokenAndMintToUser(
     payer: web3.Keypair,
     user: web3.Keypair,
     amountInLamports: number,
+    tokenProgramId: web3.PublicKey = TOKEN_PROGRAM_ID,
 ) {
     // create SPL Token Mint
     const mint = await createMint(
@@ -54,17 +62,191 @@ export async function createSPLTokenAndMintToUser(
         payer.publicKey,
         payer.publicKey,
         9, // token decimals
+        undefined,
+        undefined,
+        tokenProgramId,
     );
 
     // create user ata account for token mint
-    let ataTokenAccount = await getOrCreateAssociatedTokenAccount(connection, payer, mint, user.publicKey);
+    let ataTokenAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        mint,
+        user.publicKey,
+        false,
+        undefined,
+        undefined,
+        tokenProgramId,
+    );
+
+    // mint amount token to user
+    await mintTo(
+        connection,
+        payer,
+        mint,
+        ataTokenAccount.address,
+        payer.publicKey,
+        amountInLamports,
+        [],
+        undefined,
+        tokenProgramId,
+    );
+
+    return {
+        mint,
+        ataTokenAccount,
+    };
+}
+
+export async function createTransferFeeTokenAndMintToUser(
+    connection: web3.Connection,
+    payer: web3.Keypair,
+    user: web3.Keypair,
+    amountInLamports: number,
+    transferFeeBp: number,
+    maxTransferFee: bigint,
+) {
+    // create Token-2022 mint with the TransferFee extension
+    const mintKeypair = web3.Keypair.generate();
+    const mintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+    const lamports = await connection.getMinimumBalanceForRentExemption(mintLen);
+    const tx = new web3.Transaction().add(
+        web3.SystemProgram.createAccount({
+            fromPubkey: payer.publicKey,
+            newAccountPubkey: mintKeypair.publicKey,
+            space: mintLen,
+            lamports,
+            programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        createInitializeTransferFeeConfigInstruction(
+            mintKeypair.publicKey,
+            payer.publicKey,
+            payer.publicKey,
+            transferFeeBp,
+            maxTransferFee,
+            TOKEN_2022_PROGRAM_ID,
+        ),
+        createInitializeMintInstruction(mintKeypair.publicKey, 9, payer.publicKey, null, TOKEN_2022_PROGRAM_ID),
+    );
+    await web3.sendAndConfirmTransaction(connection, tx, [payer, mintKeypair]);
+    const mint = mintKeypair.publicKey;
+
+    // create user ata account for token mint
+    let ataTokenAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        mint,
+        user.publicKey,
+        false,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID,
+    );
+
+    // mint amount token to user
+    await mintTo(
+        connection,
+        payer,
+        mint,
+        ataTokenAccount.address,
+        payer.publicKey,
+        amountInLamports,
+        [],
+        undefined,
+        TOKEN_2022_PROGRAM_ID,
+    );
+
+    return {
+        mint,
+        ataTokenAccount,
+    };
+}
+
+export async function createTransferHookTokenAndMintToUser(
+    connection: web3.Connection,
+    payer: web3.Keypair,
+    user: web3.Keypair,
+    amountInLamports: number,
+    hookProgram: Program<any>,
+) {
+    // create Token-2022 mint with the TransferHook extension
+    const mintKeypair = web3.Keypair.generate();
+    const mintLen = getMintLen([ExtensionType.TransferHook]);
+    const lamports = await connection.getMinimumBalanceForRentExemption(mintLen);
+    const tx = new web3.Transaction().add(
+        web3.SystemProgram.createAccount({
+            fromPubkey: payer.publicKey,
+            newAccountPubkey: mintKeypair.publicKey,
+            space: mintLen,
+            lamports,
+            programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        createInitializeTransferHookInstruction(
+            mintKeypair.publicKey,
+            payer.publicKey,
+            hookProgram.programId,
+            TOKEN_2022_PROGRAM_ID,
+        ),
+        createInitializeMintInstruction(mintKeypair.publicKey, 9, payer.publicKey, null, TOKEN_2022_PROGRAM_ID),
+    );
+    await web3.sendAndConfirmTransaction(connection, tx, [payer, mintKeypair]);
+    const mint = mintKeypair.publicKey;
+
+    // the hook requires a per mint counter on every transfer
+    const extraAccountMetaList = getExtraAccountMetaAddress(mint, hookProgram.programId);
+    const [counter] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("counter"), mint.toBytes()],
+        hookProgram.programId,
+    );
+    await hookProgram.methods
+        .initializeExtraAccountMetaList()
+        .accounts({
+            payer: payer.publicKey,
+            extraAccountMetaList,
+            mint,
+            counter,
+            systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+    // create user ata account for token mint
+    let ataTokenAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        mint,
+        user.publicKey,
+        false,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID,
+    );
 
     // mint amount token to user
-    await mintTo(connection, payer, mint, ataTokenAccount.address, payer.publicKey, amountInLamports);
+    await mintTo(
+        connection,
+        payer,
+        mint,
+        ataTokenAccount.address,
+        payer.publicKey,
+        amountInLamports,
+        [],
+        undefined,
+        TOKEN_2022_PROGRAM_ID,
+    );
+
+    // passed as remaining accounts wherever the mint is transferred
+    const hookAccounts: web3.AccountMeta[] = [
+        { pubkey: extraAccountMetaList, isSigner: false, isWritable: false },
+        { pubkey: counter, isSigner: false, isWritable: true },
+        { pubkey: hookProgram.programId, isSigner: false, isWritable: false },
+    ];
 
     return {
         mint,
         ataTokenAccount,
+        counter,
+        hookAccounts,
     };
 }
 
@@ -105,6 +287,33 @@ export async function transferSOL(
     return confirm;
 }
 
+export async function getCpiEvents(connection: web3.Connection, program: Program<any>, tx: string) {
+    // events emitted by emit_cpi! are carried in the data of self-invoked inner instructions
+    let txResult = await connection.getTransaction(tx, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+    });
+    if (!txResult || !txResult.meta || !txResult.meta.innerInstructions) {
+        return [];
+    }
+
+    let accountKeys = txResult.transaction.message.getAccountKeys();
+    let events = [];
+    for (let innerInstruction of txResult.meta.innerInstructions) {
+        for (let ix of innerInstruction.instructions) {
+            if (!accountKeys.get(ix.programIdIndex)?.equals(program.programId)) {
+                continue;
+            }
+            let ixData = utils.bytes.bs58.decode(ix.data);
+            let event = program.coder.events.decode(utils.bytes.base64.encode(ixData.subarray(8)));
+            if (event) {
+                events.push(event);
+            }
+        }
+    }
+    return events;
+}
+
 export function generateUuid(
     sender: web3.PublicKey,
     receiver: web3.PublicKey,
@@ -2,6 +2,7 @@ import crypto from "crypto";
 import * as anchor from "@coral-xyz/anchor";
 import { Program, web3, AnchorError } from "@coral-xyz/anchor";
 import { Obridge } from "../target/types/obridge";
+import { TransferHook } from "../target/types/transfer_hook";
 import {
     getAccount,
     TOKEN_PROGRAM_ID,
@@ -9,22 +10,29 @@ import {
     getAssociatedTokenAddressSync,
     getOrCreateAssociatedTokenAccount,
     Account,
+    TOKEN_2022_PROGRAM_ID,
 } from "@solana/spl-token";
 import { keccak_256 } from "@noble/hashes/sha3";
+import { sha256 } from "@noble/hashes/sha256";
 import BN from "bn.js";
 import {
     createAccountOnChain,
     airdropSOL,
     createSPLTokenAndMintToUser,
+    createTransferFeeTokenAndMintToUser,
+    createTransferHookTokenAndMintToUser,
     transferSOL,
     sleep,
     splTokensBalance,
     generateUuid,
+    getCpiEvents,
 } from "./helper";
 import { expect } from "chai";
 
 type Lock = {
     hash: Array<number>;
+    hashAlgorithm: object;
+    maxPreimageLen: number;
     agreementReachedTime: BN;
     expectedSingleStepTime: BN;
     tolerantSingleStepTime: BN;
@@ -39,6 +47,7 @@ describe("OBridge", () => {
     const connection = provider.connection;
     console.log(`network connected: ${connection.rpcEndpoint}`);
     const program = anchor.workspace.Obridge as Program<Obridge>;
+    const hookProgram = anchor.workspace.TransferHook as Program<TransferHook>;
     console.log(`obridge program: ${program.programId}`);
 
     // create user and airdrop SOL
@@ -51,6 +60,8 @@ describe("OBridge", () => {
     const newAdmin = web3.Keypair.generate();
     console.log(`new admin: ${newAdmin.publicKey}`);
 
+    const feeRateCapBp = 5000;
+
     let feeRecepient = web3.Keypair.generate();
     console.log(`feeRecepient: ${feeRecepient.publicKey}`);
 
@@ -69,6 +80,7 @@ describe("OBridge", () => {
     let lpAtaTokenMint2Account: Account;
 
     let adminSettings: web3.PublicKey;
+    let solSettings: web3.PublicKey;
     let preimage: Array<number>;
     let hashlock: Array<number>;
 
@@ -77,6 +89,36 @@ describe("OBridge", () => {
 
     let tx: string;
 
+    function tokenSettingsOf(mint: web3.PublicKey) {
+        return web3.PublicKey.findProgramAddressSync([Buffer.from("token"), mint.toBytes()], program.programId)[0];
+    }
+
+    async function registerToken(mint: web3.PublicKey) {
+        return await program.methods
+            .registerToken(mint)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: tokenSettingsOf(mint),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+    }
+
+    async function deregisterToken(mint: web3.PublicKey) {
+        return await program.methods
+            .deregisterToken(mint)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: tokenSettingsOf(mint),
+            })
+            .signers([newAdmin])
+            .rpc();
+    }
+
     beforeEach(async () => {
         console.log(`========== setup up ==========`);
         await airdropSOL(connection, payer, 1000 * 10 ** 9);
@@ -132,6 +174,15 @@ describe("OBridge", () => {
         [adminSettings] = web3.PublicKey.findProgramAddressSync([Buffer.from("settings")], program.programId);
         console.log(`offchain adminSettings: ${adminSettings.toBase58()}`);
 
+        solSettings = tokenSettingsOf(web3.PublicKey.default);
+
+        // prepare only accepts registered tokens, register them once the program is initialized
+        if (await connection.getAccountInfo(adminSettings)) {
+            await registerToken(mint1);
+            await registerToken(mint2);
+            await registerToken(web3.PublicKey.default);
+        }
+
         let _preimage = new Uint8Array(32);
         preimage = Array.from(crypto.getRandomValues(_preimage));
         hashlock = Array.from(keccak_256(Buffer.from(preimage)));
@@ -149,9 +200,9 @@ describe("OBridge", () => {
         let lpMint2BalBefore = new BN(lpAtaTokenMint2Account.amount.toString());
         let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
 
-        let feeRecepientMint1BalBefore = new BN(0);
-        let feeRecepientMint2BalBefore = new BN(0);
-        let feeRecepientSOLBalBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultMint1BalBefore = new BN(0);
+        let feeVaultMint2BalBefore = new BN(0);
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -164,6 +215,8 @@ describe("OBridge", () => {
 
         let lock: Lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -201,7 +254,7 @@ describe("OBridge", () => {
         // got error before initialize program
         try {
             await program.methods
-                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -210,7 +263,9 @@ describe("OBridge", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    tokenSettings: null,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -226,7 +281,7 @@ describe("OBridge", () => {
 
         // initialize program
         tx = await program.methods
-            .initialize(admin.publicKey)
+            .initialize(admin.publicKey, feeRateCapBp)
             .accounts({
                 payer: payer.publicKey,
                 adminSettings: adminSettings,
@@ -240,7 +295,7 @@ describe("OBridge", () => {
         // cannot initialize program again
         try {
             await program.methods
-                .initialize(newAdmin.publicKey)
+                .initialize(newAdmin.publicKey, feeRateCapBp)
                 .accounts({
                     payer: payer.publicKey,
                     adminSettings: adminSettings,
@@ -272,11 +327,11 @@ describe("OBridge", () => {
         tx = await program.methods
             .setFeeRecepient()
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 feeRecepient: feeRecepient.publicKey,
                 adminSettings: adminSettings,
             })
-            .signers([newAdmin, feeRecepient])
+            .signers([newAdmin])
             .rpc();
 
         console.log(`set fee recepient tx: ${tx}`);
@@ -285,7 +340,7 @@ describe("OBridge", () => {
         tx = await program.methods
             .setFeeRate(1000)
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
             })
             .signers([newAdmin])
@@ -293,13 +348,17 @@ describe("OBridge", () => {
 
         console.log(`set fee rate tx: ${tx}`);
 
+        await registerToken(mint1);
+        await registerToken(mint2);
+        await registerToken(web3.PublicKey.default);
+
         let feeMint1 = tokenAmount.mul(new BN(1000)).div(new BN(10000));
         let feeMint2 = tokenAmountBack.mul(new BN(1000)).div(new BN(10000));
         let feeSOL = solAmountBack.mul(new BN(1000)).div(new BN(10000));
 
         // transfer out
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -308,7 +367,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint1Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -321,7 +382,7 @@ describe("OBridge", () => {
         // try to use same uuid for wrong test
         try {
             await program.methods
-                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -330,7 +391,9 @@ describe("OBridge", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    tokenSettings: null,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -369,7 +432,7 @@ describe("OBridge", () => {
 
         // lp response to the swap initiated by user (transfer in)
         const tx2 = await program.methods
-            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo)
+            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo, { deducted: {} }, null)
             .accounts({
                 payer: lp.publicKey,
                 from: lp.publicKey,
@@ -378,7 +441,9 @@ describe("OBridge", () => {
                 escrow: escrow2,
                 escrowAta: escrow2AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint2Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -394,7 +459,7 @@ describe("OBridge", () => {
         tx = await program.methods
             .setFeeRate(2000)
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
             })
             .signers([newAdmin])
@@ -405,13 +470,8 @@ describe("OBridge", () => {
         // lp token mint1 ata address
         let lpAtaTokenMint1Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, lp.publicKey);
 
-        // fee recepient mint1 ata address
-        let feeMin1Destination = await getOrCreateAssociatedTokenAccount(
-            connection,
-            payer,
-            mint1,
-            feeRecepient.publicKey,
-        );
+        // fee vault mint1 ata address
+        let feeMint1Vault = getAssociatedTokenAddressSync(mint1, adminSettings, true);
 
         // user confirm the swap (transfer out)
         const tx3 = await program.methods
@@ -423,9 +483,10 @@ describe("OBridge", () => {
                 destination: lpAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                mint: mint1,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                feeDestination: feeMin1Destination.address,
+                feeVault: feeMint1Vault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
@@ -443,13 +504,8 @@ describe("OBridge", () => {
             user.publicKey,
         );
 
-        // fee recepient mint2 ata address
-        let feeMin2Destination = await getOrCreateAssociatedTokenAccount(
-            connection,
-            payer,
-            mint2,
-            feeRecepient.publicKey,
-        );
+        // fee vault mint2 ata address
+        let feeMint2Vault = getAssociatedTokenAddressSync(mint2, adminSettings, true);
 
         // lp confirm the swap (transfer in)
         const tx4 = await program.methods
@@ -461,9 +517,10 @@ describe("OBridge", () => {
                 destination: userAtaTokenMint2Account.address,
                 escrow: escrow2,
                 escrowAta: escrow2AtaTokenAccount,
+                mint: mint2,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                feeDestination: feeMin2Destination.address,
+                feeVault: feeMint2Vault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
@@ -490,22 +547,18 @@ describe("OBridge", () => {
         expect(lpMint2BalBefore.sub(lpMint2BalAfter).toString()).to.be.eq(tokenAmountBack.toString());
         expect(lpSOLBalBefore.sub(lpSOLBalAfter).toNumber()).to.be.eq(solAmountBack.toNumber());
 
-        let feeRecepientMint1BalAfter = new BN(
-            (await getAccount(connection, feeMin1Destination.address)).amount.toString(),
-        );
-        let feeRecepientMint2BalAfter = new BN(
-            (await getAccount(connection, feeMin2Destination.address)).amount.toString(),
-        );
-        let feeRecepientSOLBalAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
-        expect(feeRecepientMint1BalAfter.sub(feeRecepientMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
-        expect(feeRecepientMint2BalAfter.sub(feeRecepientMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
-        expect(feeRecepientSOLBalAfter.sub(feeRecepientSOLBalBefore).toString()).to.be.eq(feeSOL.toString());
+        let feeVaultMint1BalAfter = new BN((await getAccount(connection, feeMint1Vault)).amount.toString());
+        let feeVaultMint2BalAfter = new BN((await getAccount(connection, feeMint2Vault)).amount.toString());
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(feeVaultMint1BalAfter.sub(feeVaultMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
+        expect(feeVaultMint2BalAfter.sub(feeVaultMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(feeSOL.toString());
     });
 
     it("swap SOL <-> SOL", async () => {
         let userSOLBalBefore = new BN(await connection.getBalance(user.publicKey));
         let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
-        let feeRecepientSOLBalBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -518,6 +571,8 @@ describe("OBridge", () => {
 
         let lock: Lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -553,11 +608,18 @@ describe("OBridge", () => {
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
         console.log(`offchain escrow1: ${escrow1}`);
 
+        // calculate preimage record address offchain, it is created by the first confirm
+        let [preimageRecord] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("preimage"), Buffer.from(lock.hash)],
+            program.programId,
+        );
+        console.log(`offchain preimageRecord: ${preimageRecord}`);
+
         let memo = Buffer.from([1, 2, 3, 4, 5]);
 
         // transfer out
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -566,7 +628,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: null,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -597,7 +661,7 @@ describe("OBridge", () => {
 
         // lp response to the swap initiated by user (transfer in)
         const tx2 = await program.methods
-            .prepare(uuid2, user.publicKey, solAmount1, tokenAmount1, lock, isIn, memo)
+            .prepare(uuid2, user.publicKey, solAmount1, tokenAmount1, lock, isIn, memo, { deducted: {} }, null)
             .accounts({
                 payer: lp.publicKey,
                 from: lp.publicKey,
@@ -606,7 +670,9 @@ describe("OBridge", () => {
                 escrow: escrow2,
                 escrowAta: null,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -628,17 +694,29 @@ describe("OBridge", () => {
                 destination: null,
                 escrow: escrow1,
                 escrowAta: null,
+                mint: null,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                feeDestination: null,
+                feeVault: null,
+                associatedTokenProgram: null,
+                preimageRecord: preimageRecord,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
             })
             .signers([user])
-            .rpc();
+            .rpc({ commitment: "confirmed" });
 
         console.log(`confirm transfer out tx: ${tx3}`);
 
+        // confirm event carries the revealed preimage
+        let confirmed = (await getCpiEvents(connection, program, tx3)).find((e) => e.name === "Confirmed");
+        expect(confirmed).not.to.be.undefined;
+        expect(Array.from(confirmed!.data.preimage as number[])).to.deep.eq(preimage);
+        expect(confirmed!.data.solAmount.toString()).to.be.eq(solAmount1.toString());
+        expect(confirmed!.data.solFee.toString()).to.be.eq(feeSOL.toString());
+        expect(confirmed!.data.feeMode).to.deep.eq({ deducted: {} });
+        expect(confirmed!.data.referrer.toBase58()).to.be.eq(web3.PublicKey.default.toBase58());
+        expect(confirmed!.data.solReferralFee.toString()).to.be.eq("0");
+
         console.log(`========== confirm transfer in ==========`);
         // lp confirm the swap (transfer in)
         const tx4 = await program.methods
@@ -650,41 +728,2393 @@ describe("OBridge", () => {
                 destination: null,
                 escrow: escrow2,
                 escrowAta: null,
+                mint: null,
+                adminSettings: adminSettings,
+                feeVault: null,
+                associatedTokenProgram: null,
+                preimageRecord: preimageRecord,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([lp])
+            .rpc();
+
+        console.log(`confirm transfer in tx: ${tx4}`);
+
+        // the revealed preimage stays readable after both escrows are closed
+        let record = await program.account.preimageRecord.fetch(preimageRecord);
+        expect(record.hash).to.deep.eq(hashlock);
+        expect(record.preimage.slice(0, record.preimageLen)).to.deep.eq(preimage);
+        expect(record.payer.toBase58()).to.be.eq(user.publicKey.toBase58());
+
+        // the record is kept for the retention period before anyone can close it
+        try {
+            await program.methods
+                .closePreimageRecord(lock.hash)
+                .accounts({
+                    payer: user.publicKey,
+                    preimageRecord: preimageRecord,
+                    adminSettings: adminSettings,
+                })
+                .rpc();
+            expect.fail("closePreimageRecord within the retention period should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("RetentionNotElapsed");
+        }
+
+        try {
+            await program.methods
+                .setPreimageRetention(new BN(0))
+                .accounts({
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("a zero retention period should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidRetentionPeriod");
+        }
+
+        tx = await program.methods
+            .setPreimageRetention(new BN(1))
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set preimage retention tx: ${tx}`);
+        await sleep(2000);
+
+        tx = await program.methods
+            .closePreimageRecord(lock.hash)
+            .accounts({
+                payer: user.publicKey,
+                preimageRecord: preimageRecord,
+                adminSettings: adminSettings,
+            })
+            .rpc();
+        console.log(`close preimage record tx: ${tx}`);
+        expect(await connection.getAccountInfo(preimageRecord)).to.be.null;
+
+        let userSOLBalAfter = new BN(await connection.getBalance(user.publicKey));
+        expect(userSOLBalBefore.sub(userSOLBalAfter).toNumber()).to.be.eq(feeSOL.toNumber());
+
+        let lpSOLBalAfter = new BN(await connection.getBalance(lp.publicKey));
+        expect(lpSOLBalBefore.sub(lpSOLBalAfter).toNumber()).to.be.eq(feeSOL.toNumber());
+
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(
+            feeSOL.mul(new BN(2)).toString(),
+        );
+    });
+
+    it("swap SOL with sha256 hashlock", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        // the same secret can unlock a bitcoin-family htlc that uses sha256
+        let sha256Hashlock = Array.from(sha256(Buffer.from(preimage)));
+        let lock: Lock = {
+            hash: sha256Hashlock,
+            hashAlgorithm: { sha256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        const ZERO_PUBKEY = new web3.PublicKey(new Uint8Array(32).fill(0));
+        const solAmount1 = new BN(2 * 10 ** 9);
+        const tokenAmount1 = new BN(0);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            ZERO_PUBKEY,
+            tokenAmount1,
+            solAmount1,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { deducted: {} }, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                mint: null,
+                source: null,
+                escrow: escrow1,
+                escrowAta: null,
+                adminSettings: adminSettings,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        // a keccak256 hash of the preimage is not accepted for a sha256 lock
+        let wrongPreimage = Array.from(keccak_256(Buffer.from(preimage)));
+        try {
+            await program.methods
+                .confirm(uuid1, wrongPreimage, isOut)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    destination: null,
+                    escrow: escrow1,
+                    escrowAta: null,
+                    mint: null,
+                    adminSettings: adminSettings,
+                    feeVault: null,
+                    associatedTokenProgram: null,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: null,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("confirm with wrong preimage should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("PreimageMismatch");
+        }
+
+        let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        const feeSOL = solAmount1.mul(new BN(feeRateBp)).div(new BN(10000));
+
+        tx = await program.methods
+            .confirm(uuid1, preimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: null,
+                escrow: escrow1,
+                escrowAta: null,
+                mint: null,
+                adminSettings: adminSettings,
+                feeVault: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        let lpSOLBalAfter = new BN(await connection.getBalance(lp.publicKey));
+        expect(lpSOLBalAfter.sub(lpSOLBalBefore).toString()).to.be.eq(solAmount1.sub(feeSOL).toString());
+    });
+
+    it("swap SOL with variable-length preimage", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        // counterparty htlc uses a 48-byte secret
+        let longPreimage = Buffer.from(crypto.getRandomValues(new Uint8Array(48)));
+        let lock: Lock = {
+            hash: Array.from(keccak_256(longPreimage)),
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 48,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        const ZERO_PUBKEY = new web3.PublicKey(new Uint8Array(32).fill(0));
+        const solAmount1 = new BN(2 * 10 ** 9);
+        const tokenAmount1 = new BN(0);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            ZERO_PUBKEY,
+            tokenAmount1,
+            solAmount1,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { deducted: {} }, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                mint: null,
+                source: null,
+                escrow: escrow1,
+                escrowAta: null,
+                adminSettings: adminSettings,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        // the fixed-size path cannot reveal a 48-byte secret
+        try {
+            await program.methods
+                .confirm(uuid1, Array.from(longPreimage.subarray(0, 32)), isOut)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    destination: null,
+                    escrow: escrow1,
+                    escrowAta: null,
+                    mint: null,
+                    adminSettings: adminSettings,
+                    feeVault: null,
+                    associatedTokenProgram: null,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: null,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("confirm with truncated preimage should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("PreimageMismatch");
+        }
+
+        let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        const feeSOL = solAmount1.mul(new BN(feeRateBp)).div(new BN(10000));
+
+        tx = await program.methods
+            .confirmWithPreimage(uuid1, longPreimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: null,
+                escrow: escrow1,
+                escrowAta: null,
+                mint: null,
+                adminSettings: adminSettings,
+                feeVault: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        let lpSOLBalAfter = new BN(await connection.getBalance(lp.publicKey));
+        expect(lpSOLBalAfter.sub(lpSOLBalBefore).toString()).to.be.eq(solAmount1.sub(feeSOL).toString());
+    });
+
+    it("swap Token-2022 token", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        let ret = await createSPLTokenAndMintToUser(
+            connection,
+            payer,
+            user,
+            tokenAmount.toNumber(),
+            TOKEN_2022_PROGRAM_ID,
+        );
+        let mint2022 = ret.mint;
+        await registerToken(mint2022);
+        let userAtaTokenMint2022Account = ret.ataTokenAccount;
+        console.log(`create Token-2022 mint ${mint2022}`);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint2022,
+            tokenAmount,
+            solAmount,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint2022, escrow1, true, TOKEN_2022_PROGRAM_ID);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                mint: mint2022,
+                source: userAtaTokenMint2022Account.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                adminSettings: adminSettings,
+                tokenSettings: tokenSettingsOf(mint2022),
+                solSettings: solSettings,
+                feeDiscount: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        let lpAtaTokenMint2022Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint2022,
+            lp.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        let feeMint2022Vault = getAssociatedTokenAddressSync(mint2022, adminSettings, true, TOKEN_2022_PROGRAM_ID);
+
+        // the escrow remembers its token program, the legacy token program is rejected
+        try {
+            await program.methods
+                .confirm(uuid1, preimage, isOut)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    destination: lpAtaTokenMint2022Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    mint: mint2022,
+                    adminSettings: adminSettings,
+                    feeVault: feeMint2022Vault,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("confirm with wrong token program should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).logs).not.to.be.empty;
+        }
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        let feeMint2022 = tokenAmount.mul(new BN(feeRateBp)).div(new BN(10000));
+
+        tx = await program.methods
+            .confirm(uuid1, preimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: lpAtaTokenMint2022Account.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                mint: mint2022,
+                adminSettings: adminSettings,
+                feeVault: feeMint2022Vault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        let lpMint2022BalAfter = (
+            await getAccount(connection, lpAtaTokenMint2022Account.address, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        let feeVaultMint2022BalAfter = (
+            await getAccount(connection, feeMint2022Vault, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        expect(lpMint2022BalAfter.toString()).to.be.eq(tokenAmount.sub(feeMint2022).toString());
+        expect(feeVaultMint2022BalAfter.toString()).to.be.eq(feeMint2022.toString());
+    });
+
+    it("swap Token-2022 token with transfer fee", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+
+        // 1% transfer fee charged by the mint on every transfer
+        let transferFeeBp = 100;
+        let ret = await createTransferFeeTokenAndMintToUser(
+            connection,
+            payer,
+            user,
+            tokenAmount.toNumber(),
+            transferFeeBp,
+            BigInt(tokenAmount.toString()),
+        );
+        let feeMint = ret.mint;
+        await registerToken(feeMint);
+        let userAtaFeeMintAccount = ret.ataTokenAccount;
+        console.log(`create transfer fee mint ${feeMint}`);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            feeMint,
+            tokenAmount,
+            solAmount,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(feeMint, escrow1, true, TOKEN_2022_PROGRAM_ID);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        let prepare = (feeMode: { deducted: {} } | { onTop: {} }) =>
+            program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, feeMode, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: feeMint,
+                    source: userAtaFeeMintAccount.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(feeMint),
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+
+        // the mint would withhold part of an on top pull and leave lp short
+        try {
+            await prepare({ onTop: {} });
+            expect.fail("prepare with fee on top should fail for a transfer fee mint");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("OnTopFeeWithTransferFee");
+        }
+
+        tx = await prepare({ deducted: {} });
+        console.log(`transfer out tx: ${tx}`);
+
+        // the escrow records what it actually received, not what was sent
+        let received = tokenAmount.sub(tokenAmount.mul(new BN(transferFeeBp)).div(new BN(10000)));
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Data.tokenAmount.toString()).to.be.eq(received.toString());
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        let protocolFee = received.mul(new BN(feeRateBp)).div(new BN(10000));
+        expect(escrow1Data.tokenFee.toString()).to.be.eq(protocolFee.toString());
+
+        let lpAtaFeeMintAccount = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            feeMint,
+            lp.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        let feeFeeMintVault = getAssociatedTokenAddressSync(feeMint, adminSettings, true, TOKEN_2022_PROGRAM_ID);
+
+        tx = await program.methods
+            .confirm(uuid1, preimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: lpAtaFeeMintAccount.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                mint: feeMint,
+                adminSettings: adminSettings,
+                feeVault: feeFeeMintVault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        // the escrow ata is closed even though the incoming transfer left withheld fees in it
+        expect(await connection.getAccountInfo(escrow1AtaTokenAccount)).to.be.null;
+    });
+
+    it("swap Token-2022 token with transfer hook", async () => {
+        let ret = await createTransferHookTokenAndMintToUser(
+            connection,
+            payer,
+            user,
+            tokenAmount.toNumber(),
+            hookProgram,
+        );
+        let hookMint = ret.mint;
+        let hookAccounts = ret.hookAccounts;
+        let transferCount = async () => (await hookProgram.account.counter.fetch(ret.counter)).count.toNumber();
+        await registerToken(hookMint);
+        let userAtaHookMintAccount = ret.ataTokenAccount;
+        console.log(`create Token-2022 mint ${hookMint} with transfer hook ${hookProgram.programId}`);
+
+        let lpAtaHookMintAccount = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            hookMint,
+            lp.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        let feeHookMintVault = getAssociatedTokenAddressSync(hookMint, adminSettings, true, TOKEN_2022_PROGRAM_ID);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        let halfTokenAmount = tokenAmount.divn(2);
+
+        let prepare = (uuid: number[], escrow: web3.PublicKey, escrowAta: web3.PublicKey, lock: Lock) =>
+            program.methods
+                .prepare(uuid, lp.publicKey, solAmount, halfTokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: hookMint,
+                    source: userAtaHookMintAccount.address,
+                    escrow: escrow,
+                    escrowAta: escrowAta,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(hookMint),
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                })
+                .remainingAccounts(hookAccounts)
+                .signers([user])
+                .rpc();
+
+        console.log(`========== prepare and confirm ==========`);
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(5),
+            tolerantSingleStepTime: new BN(10),
+            earliestRefundTime: new BN(agreementReachedTime + 3 * 5 + 3 * 10 + 1),
+        };
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            hookMint,
+            halfTokenAmount,
+            solAmount,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(hookMint, escrow1, true, TOKEN_2022_PROGRAM_ID);
+
+        // the hook rejects transfers without its extra accounts
+        try {
+            await program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, halfTokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: hookMint,
+                    source: userAtaHookMintAccount.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(hookMint),
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("prepare without the hook accounts should fail");
+        } catch (err: any) {
+            expect(err.logs).not.to.be.empty;
+        }
+
+        tx = await prepare(uuid1, escrow1, escrow1AtaTokenAccount, lock);
+        console.log(`transfer out tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(1);
+
+        tx = await program.methods
+            .confirm(uuid1, preimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: lpAtaHookMintAccount.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                mint: hookMint,
+                adminSettings: adminSettings,
+                feeVault: feeHookMintVault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .remainingAccounts(hookAccounts)
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        // the fee and the payout both went through the hook
+        expect(await transferCount()).to.be.eq(3);
+        let feeVaultHookMintBal = (
+            await getAccount(connection, feeHookMintVault, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        expect(feeVaultHookMintBal > BigInt(0)).to.be.true;
+
+        console.log(`========== prepare and refund ==========`);
+        slot = await connection.getSlot();
+        agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let earliestRefundTime = agreementReachedTime + 3 * 2 + 3 * 1 + 1;
+        let lock2: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(2),
+            tolerantSingleStepTime: new BN(1),
+            earliestRefundTime: new BN(earliestRefundTime),
+        };
+        let uuid2 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock2.hash,
+            lock2.agreementReachedTime,
+            lock2.expectedSingleStepTime,
+            lock2.tolerantSingleStepTime,
+            lock2.earliestRefundTime,
+            hookMint,
+            halfTokenAmount,
+            solAmount,
+        );
+        let [escrow2] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid2)], program.programId);
+        let escrow2AtaTokenAccount = getAssociatedTokenAddressSync(hookMint, escrow2, true, TOKEN_2022_PROGRAM_ID);
+
+        tx = await prepare(uuid2, escrow2, escrow2AtaTokenAccount, lock2);
+        console.log(`transfer out tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(4);
+
+        console.log(`wait until the earliestRefundTime: ${earliestRefundTime}`);
+        while (true) {
+            let slot = await connection.getSlot();
+            let currentTime = await connection.getBlockTime(slot);
+            if (!currentTime) {
+                throw new Error("currentTime is null");
+            }
+            if (currentTime >= earliestRefundTime) {
+                break;
+            }
+            await sleep(1000);
+        }
+
+        tx = await program.methods
+            .refund(uuid2, isOut)
+            .accounts({
+                from: user.publicKey,
+                source: userAtaHookMintAccount.address,
+                escrow: escrow2,
+                escrowAta: escrow2AtaTokenAccount,
+                mint: hookMint,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .remainingAccounts(hookAccounts)
+            .rpc();
+        console.log(`user refund transfer out tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(5);
+
+        console.log(`========== withdraw fees ==========`);
+        let feeRecepientHookMintAccount = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            hookMint,
+            feeRecepient.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        // withdraw also sweeps the SOL fees, so the fee recepient has to be rent exempt
+        await transferSOL(connection, payer, feeRecepient.publicKey, web3.LAMPORTS_PER_SOL);
+        tx = await program.methods
+            .withdrawFees(0, Buffer.from([hookAccounts.length]))
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeRecepient: feeRecepient.publicKey,
+            })
+            .remainingAccounts([
+                { pubkey: hookMint, isSigner: false, isWritable: false },
+                { pubkey: feeHookMintVault, isSigner: false, isWritable: true },
+                { pubkey: feeRecepientHookMintAccount.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_2022_PROGRAM_ID, isSigner: false, isWritable: false },
+                ...hookAccounts,
+            ])
+            .signers([newAdmin])
+            .rpc();
+        console.log(`withdraw fees tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(6);
+        let feeRecepientHookMintBal = (
+            await getAccount(connection, feeRecepientHookMintAccount.address, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        expect(feeRecepientHookMintBal.toString()).to.be.eq(feeVaultHookMintBal.toString());
+    });
+
+    it("cannot call initiate after deadline", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 1;
+        let tolerantSingleStepTime = 1;
+
+        let lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint1,
+            tokenAmount,
+            solAmount,
+        );
+        console.log(`generate uuid1: ${uuid1}`);
+
+        // calculate escrow account address offchain without create it
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        console.log(`offchain escrow1: ${escrow1}`);
+
+        // calculate escrowAtaTokenAccount account address offchain without create it
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        // sleep until the transfer out deadline
+        await sleep(5000);
+
+        try {
+            await program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+        } catch (err: any) {
+            console.log(`if it exceeds transfer out time limit, it should throw error`);
+            console.log(`========== error ==========`);
+            console.log((err as AnchorError).logs);
+            expect((err as AnchorError).logs).not.to.be.empty;
+        }
+    });
+
+    it("cannot call initiate with amount 0", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 2;
+        let tolerantSingleStepTime = 3;
+
+        let lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint1,
+            tokenAmount,
+            solAmount,
+        );
+        console.log(`generate uuid1: ${uuid1}`);
+
+        // calculate escrow account address offchain without create it
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        console.log(`offchain escrow1: ${escrow1}`);
+
+        // calculate escrowAtaTokenAccount account address offchain without create it
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        try {
+            await program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, new BN(0), lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user, user])
+                .rpc();
+        } catch (err: any) {
+            console.log(`if the token amount is 0, it should throw error`);
+            console.log(`========== error ==========`);
+            console.log((err as AnchorError).logs);
+            expect((err as AnchorError).logs).not.to.be.empty;
+        }
+    });
+
+    it("cannot call prepare while paused", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint1,
+            tokenAmount,
+            solAmount,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = (isOut: boolean) =>
+            program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+        let setPause = (paused: boolean, pausedOut: boolean, pausedIn: boolean) =>
+            program.methods
+                .setPause(paused, pausedOut, pausedIn)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+
+        // only admin can pause
+        try {
+            await program.methods
+                .setPause(true, false, false)
+                .accounts({
+                    authority: user.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("set pause by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        try {
+            await setPause(true, false, false);
+            try {
+                await prepare(isOut);
+                expect.fail("prepare while paused should fail");
+            } catch (err: any) {
+                expect((err as AnchorError).error.errorCode.code).to.be.eq("Paused");
+            }
+
+            // pausing one direction leaves the other one open
+            await setPause(false, true, false);
+            try {
+                await prepare(isOut);
+                expect.fail("prepare while out direction is paused should fail");
+            } catch (err: any) {
+                expect((err as AnchorError).error.errorCode.code).to.be.eq("Paused");
+            }
+
+            await setPause(false, false, true);
+            tx = await prepare(isOut);
+            console.log(`transfer out while in direction is paused tx: ${tx}`);
+        } finally {
+            await setPause(false, false, false);
+        }
+    });
+
+    it("cannot call prepare with unregistered token", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint1,
+            tokenAmount,
+            solAmount,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = () =>
+            program.methods
+                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+
+        await deregisterToken(mint1);
+        try {
+            await prepare();
+            expect.fail("prepare with deregistered token should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TokenNotEnabled");
+        }
+
+        // only admin can register tokens
+        try {
+            await program.methods
+                .registerToken(mint1)
+                .accounts({
+                    payer: user.publicKey,
+                    authority: user.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("register token by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        await registerToken(mint1);
+        tx = await prepare();
+        console.log(`transfer out after registering token again tx: ${tx}`);
+    });
+
+    it("check the SOL leg against the native SOL settings", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let solAmount1 = new BN(10 ** 9);
+
+        let uuid1 = generateUuid(
+            user.publicKey,
+            lp.publicKey,
+            lock.hash,
+            lock.agreementReachedTime,
+            lock.expectedSingleStepTime,
+            lock.tolerantSingleStepTime,
+            lock.earliestRefundTime,
+            mint1,
+            tokenAmount,
+            solAmount1,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = (solLegSettings: web3.PublicKey | null) =>
+            program.methods
+                .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solLegSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+
+        // a SOL leg needs the native SOL settings, not the ones of the mint
+        for (let solLegSettings of [null, mint1Settings]) {
+            try {
+                await prepare(solLegSettings);
+                expect.fail("prepare without the native SOL settings should fail");
+            } catch (err: any) {
+                expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidTokenSettings");
+            }
+        }
+
+        await deregisterToken(web3.PublicKey.default);
+        try {
+            await prepare(solSettings);
+            expect.fail("prepare with deregistered native SOL should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TokenNotEnabled");
+        }
+
+        await registerToken(web3.PublicKey.default);
+        tx = await prepare(solSettings);
+        console.log(`transfer out with a SOL leg tx: ${tx}`);
+
+        let escrow1Account = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Account.solAmount.toNumber()).to.be.eq(solAmount1.toNumber());
+    });
+
+    it("charge direction specific fee rates", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        let halfTokenAmount = tokenAmount.divn(2);
+
+        let prepare = async (isOut: boolean) => {
+            let uuid = generateUuid(
+                user.publicKey,
+                lp.publicKey,
+                lock.hash,
+                lock.agreementReachedTime,
+                lock.expectedSingleStepTime,
+                lock.tolerantSingleStepTime,
+                lock.earliestRefundTime,
+                mint1,
+                halfTokenAmount.addn(isOut ? 0 : 1),
+                solAmount,
+            );
+            let [escrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid)], program.programId);
+            await program.methods
+                .prepare(uuid, lp.publicKey, solAmount, halfTokenAmount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow,
+                    escrowAta: getAssociatedTokenAddressSync(mint1, escrow, true),
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            return await program.account.escrow.fetch(escrow);
+        };
+        let setDirectionFeeRate = (feeRateBpOut: number | null, feeRateBpIn: number | null) =>
+            program.methods
+                .setDirectionFeeRate(feeRateBpOut, feeRateBpIn)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+
+        // outbound escrows use the global out rate, inbound ones the token's in override
+        let feeRateBpOut = 500;
+        let feeRateBpInForMint1 = 100;
+        await setDirectionFeeRate(feeRateBpOut, null);
+        try {
+            tx = await program.methods
+                .setDirectionFeeRateForToken(mint1, null, feeRateBpInForMint1)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            console.log(`set token direction fee rate tx: ${tx}`);
+
+            let escrowOut = await prepare(isOut);
+            expect(escrowOut.tokenFee.toString()).to.be.eq(
+                halfTokenAmount.mul(new BN(feeRateBpOut)).div(new BN(10000)).toString(),
+            );
+
+            let escrowIn = await prepare(isIn);
+            expect(escrowIn.tokenFee.toString()).to.be.eq(
+                halfTokenAmount.mul(new BN(feeRateBpInForMint1)).div(new BN(10000)).toString(),
+            );
+        } finally {
+            await setDirectionFeeRate(null, null);
+        }
+    });
+
+    it("cannot call prepare outside token amount limits", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = async (amount: BN) => {
+            let uuid = generateUuid(
+                user.publicKey,
+                lp.publicKey,
+                lock.hash,
+                lock.agreementReachedTime,
+                lock.expectedSingleStepTime,
+                lock.tolerantSingleStepTime,
+                lock.earliestRefundTime,
+                mint1,
+                amount,
+                solAmount,
+            );
+            let [escrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid)], program.programId);
+            await program.methods
+                .prepare(uuid, lp.publicKey, solAmount, amount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow,
+                    escrowAta: getAssociatedTokenAddressSync(mint1, escrow, true),
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            return await program.account.escrow.fetch(escrow);
+        };
+        let setAmountLimits = (minAmount: BN, maxAmount: BN) =>
+            program.methods
+                .setAmountLimitsForToken(mint1, minAmount, maxAmount)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+        let minAmount = tokenAmount.divn(4);
+        let maxAmount = tokenAmount.divn(2);
+
+        try {
+            await setAmountLimits(new BN(2), new BN(1));
+            expect.fail("min amount above max amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidAmountLimits");
+        }
+
+        await setAmountLimits(minAmount, maxAmount);
+        try {
+            await prepare(minAmount.subn(1));
+            expect.fail("prepare below min amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AmountOutOfRange");
+        }
+        try {
+            await prepare(maxAmount.addn(1));
+            expect.fail("prepare above max amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AmountOutOfRange");
+        }
+
+        // both bounds are inclusive
+        let escrow = await prepare(minAmount);
+        expect(escrow.tokenAmount.toString()).to.be.eq(minAmount.toString());
+        escrow = await prepare(maxAmount);
+        expect(escrow.tokenAmount.toString()).to.be.eq(maxAmount.toString());
+    });
+
+    it("set token fee rate and min fee", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = async (amount: BN) => {
+            let uuid = generateUuid(
+                user.publicKey,
+                lp.publicKey,
+                lock.hash,
+                lock.agreementReachedTime,
+                lock.expectedSingleStepTime,
+                lock.tolerantSingleStepTime,
+                lock.earliestRefundTime,
+                mint1,
+                amount,
+                solAmount,
+            );
+            let [escrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid)], program.programId);
+            await program.methods
+                .prepare(uuid, lp.publicKey, solAmount, amount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow,
+                    escrowAta: getAssociatedTokenAddressSync(mint1, escrow, true),
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            return await program.account.escrow.fetch(escrow);
+        };
+        let setFeeRateForToken = (feeRateBp: number | null, minFee: BN) =>
+            program.methods
+                .setFeeRateForToken(mint1, feeRateBp, minFee)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+
+        try {
+            await setFeeRateForToken(10000, new BN(0));
+            expect.fail("fee rate of 100% should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidFeeRate");
+        }
+
+        // the token rate overrides the global one
+        let feeRateBpForMint1 = 50;
+        tx = await setFeeRateForToken(feeRateBpForMint1, new BN(0));
+        console.log(`set token fee rate tx: ${tx}`);
+        let quarterTokenAmount = tokenAmount.divn(4);
+        let escrow = await prepare(quarterTokenAmount);
+        expect(escrow.tokenFee.toString()).to.be.eq(
+            quarterTokenAmount.mul(new BN(feeRateBpForMint1)).div(new BN(10000)).toString(),
+        );
+
+        // a zero rate still charges the minimum fee
+        let minFeeForMint1 = new BN(1000);
+        tx = await setFeeRateForToken(0, minFeeForMint1);
+        console.log(`set token min fee tx: ${tx}`);
+        escrow = await prepare(quarterTokenAmount.subn(1));
+        expect(escrow.tokenFee.toString()).to.be.eq(minFeeForMint1.toString());
+    });
+
+    it("set token fee tiers", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = async (amount: BN) => {
+            let uuid = generateUuid(
+                user.publicKey,
+                lp.publicKey,
+                lock.hash,
+                lock.agreementReachedTime,
+                lock.expectedSingleStepTime,
+                lock.tolerantSingleStepTime,
+                lock.earliestRefundTime,
+                mint1,
+                amount,
+                solAmount,
+            );
+            let [escrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid)], program.programId);
+            await program.methods
+                .prepare(uuid, lp.publicKey, solAmount, amount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow,
+                    escrowAta: getAssociatedTokenAddressSync(mint1, escrow, true),
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            return await program.account.escrow.fetch(escrow);
+        };
+        let setFeeTiersForToken = (feeTiers: { minAmount: BN; feeRateBp: number }[]) =>
+            program.methods
+                .setFeeTiersForToken(mint1, feeTiers)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+        let halfTokenAmount = tokenAmount.divn(2);
+        let quarterTokenAmount = tokenAmount.divn(4);
+
+        try {
+            await setFeeTiersForToken([
+                { minAmount: new BN(10), feeRateBp: 30 },
+                { minAmount: new BN(10), feeRateBp: 10 },
+            ]);
+            expect.fail("fee tiers without ascending min amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidFeeTiers");
+        }
+
+        // amounts reaching the tier pay its rate even with a direction override, smaller ones the override
+        let tierFeeRateBp = 10;
+        let feeRateBpOutForMint1 = 40;
+        tx = await setFeeTiersForToken([{ minAmount: halfTokenAmount, feeRateBp: tierFeeRateBp }]);
+        console.log(`set token fee tiers tx: ${tx}`);
+        tx = await program.methods
+            .setDirectionFeeRateForToken(mint1, feeRateBpOutForMint1, null)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: mint1Settings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`set token direction fee rate tx: ${tx}`);
+
+        let escrow = await prepare(halfTokenAmount);
+        expect(escrow.tokenFee.toString()).to.be.eq(
+            halfTokenAmount.mul(new BN(tierFeeRateBp)).div(new BN(10000)).toString(),
+        );
+        escrow = await prepare(quarterTokenAmount);
+        expect(escrow.tokenFee.toString()).to.be.eq(
+            quarterTokenAmount.mul(new BN(feeRateBpOutForMint1)).div(new BN(10000)).toString(),
+        );
+    });
+
+    it("apply user fee discount", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
+
+        let lock: Lock = {
+            hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
+            agreementReachedTime: new BN(agreementReachedTime),
+            expectedSingleStepTime: new BN(expectedSingleStepTime),
+            tolerantSingleStepTime: new BN(tolerantSingleStepTime),
+            earliestRefundTime: new BN(
+                agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
+            ),
+        };
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let prepare = async (amount: BN, feeDiscount: web3.PublicKey | null) => {
+            let uuid = generateUuid(
+                user.publicKey,
+                lp.publicKey,
+                lock.hash,
+                lock.agreementReachedTime,
+                lock.expectedSingleStepTime,
+                lock.tolerantSingleStepTime,
+                lock.earliestRefundTime,
+                mint1,
+                amount,
+                solAmount,
+            );
+            let [escrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid)], program.programId);
+            await program.methods
+                .prepare(uuid, lp.publicKey, solAmount, amount, lock, isOut, memo, { deducted: {} }, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow,
+                    escrowAta: getAssociatedTokenAddressSync(mint1, escrow, true),
+                    adminSettings: adminSettings,
+                    tokenSettings: mint1Settings,
+                    solSettings: solSettings,
+                    feeDiscount: feeDiscount,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+            return await program.account.escrow.fetch(escrow);
+        };
+        let [feeDiscount] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("discount"), user.publicKey.toBytes()],
+            program.programId,
+        );
+        let discountBp = 5000;
+        tx = await program.methods
+            .setFeeDiscount(user.publicKey, discountBp)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeDiscount: feeDiscount,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`set fee discount tx: ${tx}`);
+
+        let feeRateBpForMint1 = 100;
+        await program.methods
+            .setFeeRateForToken(mint1, feeRateBpForMint1, new BN(0))
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: mint1Settings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        let halfTokenAmount = tokenAmount.divn(2);
+        let fee = halfTokenAmount.mul(new BN(feeRateBpForMint1)).div(new BN(10000));
+
+        // the discount only applies when its account is passed
+        let escrow = await prepare(halfTokenAmount, null);
+        expect(escrow.tokenFee.toString()).to.be.eq(fee.toString());
+        escrow = await prepare(halfTokenAmount.divn(2), feeDiscount);
+        let discountedFee = fee.divn(2);
+        expect(escrow.tokenFee.toString()).to.be.eq(
+            discountedFee.sub(discountedFee.mul(new BN(discountBp)).div(new BN(10000))).toString(),
+        );
+
+        tx = await program.methods
+            .removeFeeDiscount(user.publicKey)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeDiscount: feeDiscount,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`remove fee discount tx: ${tx}`);
+        expect(await connection.getAccountInfo(feeDiscount)).to.be.null;
+    });
+
+    it("require admin signer threshold", async () => {
+        let coSigner1 = web3.Keypair.generate();
+        let coSigner2 = web3.Keypair.generate();
+        let setFeeRate = (feeRateBp: number, coSigners: web3.Keypair[]) =>
+            program.methods
+                .setFeeRate(feeRateBp)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .remainingAccounts(
+                    coSigners.map((coSigner) => ({ pubkey: coSigner.publicKey, isSigner: true, isWritable: false })),
+                )
+                .signers([newAdmin, ...coSigners])
+                .rpc();
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+
+        // threshold above the number of signers is rejected
+        try {
+            await program.methods
+                .setAdminSigners([newAdmin.publicKey, coSigner1.publicKey], 3)
+                .accounts({
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("threshold above the signer count should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidAdminSigners");
+        }
+
+        tx = await program.methods
+            .setAdminSigners([newAdmin.publicKey, coSigner1.publicKey, coSigner2.publicKey], 2)
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set admin signers tx: ${tx}`);
+
+        try {
+            await setFeeRate(feeRateBp + 1, []);
+            expect.fail("setFeeRate by a single admin key should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+
+        // signers outside the set do not count
+        try {
+            await setFeeRate(feeRateBp + 1, [user]);
+            expect.fail("setFeeRate with an unknown co-signer should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+
+        tx = await setFeeRate(feeRateBp + 1, [coSigner2]);
+        console.log(`set fee rate with co-signer tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp + 1);
+
+        // withdrawFees takes its co-signers ahead of the per-mint accounts
+        tx = await program.methods
+            .withdrawFees(1, Buffer.from([]))
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeRecepient: feeRecepient.publicKey,
+            })
+            .remainingAccounts([{ pubkey: coSigner2.publicKey, isSigner: true, isWritable: false }])
+            .signers([newAdmin, coSigner2])
+            .rpc();
+        console.log(`withdraw fees with co-signer tx: ${tx}`);
+
+        // the admin pauses alone, lifting the pause needs the co-signers
+        let setPause = (paused: boolean, coSigners: web3.Keypair[]) =>
+            program.methods
+                .setPause(paused, false, false)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .remainingAccounts(
+                    coSigners.map((coSigner) => ({ pubkey: coSigner.publicKey, isSigner: true, isWritable: false })),
+                )
+                .signers([newAdmin, ...coSigners])
+                .rpc();
+        tx = await setPause(true, []);
+        console.log(`pause by a single admin key tx: ${tx}`);
+        try {
+            await setPause(false, []);
+            expect.fail("unpausing by a single admin key should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+        tx = await setPause(false, [coSigner2]);
+        console.log(`unpause with co-signer tx: ${tx}`);
+
+        await setFeeRate(feeRateBp, [coSigner1]);
+        tx = await program.methods
+            .setAdminSigners([], 0)
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .remainingAccounts([{ pubkey: coSigner1.publicKey, isSigner: true, isWritable: false }])
+            .signers([newAdmin, coSigner1])
+            .rpc();
+        console.log(`reset admin signers tx: ${tx}`);
+    });
+
+    it("separate admin roles", async () => {
+        let pauser = web3.Keypair.generate();
+        let feeManager = web3.Keypair.generate();
+        let setRole = (role: object, key: web3.PublicKey) =>
+            program.methods
+                .setRole(role, key)
+                .accounts({
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+        let setPause = (authority: web3.Keypair, paused: boolean) =>
+            program.methods
+                .setPause(paused, false, false)
+                .accounts({
+                    authority: authority.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([authority])
+                .rpc();
+        let setFeeRate = (authority: web3.Keypair, feeRateBp: number) =>
+            program.methods
+                .setFeeRate(feeRateBp)
+                .accounts({
+                    authority: authority.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([authority])
+                .rpc();
+
+        // only the super-admin assigns roles
+        try {
+            await program.methods
+                .setRole({ pauser: {} }, pauser.publicKey)
+                .accounts({
+                    admin: pauser.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([pauser])
+                .rpc();
+            expect.fail("setRole by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        tx = await setRole({ pauser: {} }, pauser.publicKey);
+        console.log(`set pauser tx: ${tx}`);
+        tx = await setRole({ feeManager: {} }, feeManager.publicKey);
+        console.log(`set fee manager tx: ${tx}`);
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+
+        // the pauser can pause but cannot touch fees
+        await setPause(pauser, true);
+        await setPause(pauser, false);
+        try {
+            await setFeeRate(pauser, feeRateBp);
+            expect.fail("setFeeRate by the pauser should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        // the fee manager can set fees but cannot pause
+        await setFeeRate(feeManager, feeRateBp);
+        try {
+            await setPause(feeManager, true);
+            expect.fail("setPause by the fee manager should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        // the super-admin keeps every role
+        await setPause(newAdmin, false);
+        await setFeeRate(newAdmin, feeRateBp);
+
+        await setRole({ pauser: {} }, web3.PublicKey.default);
+        await setRole({ feeManager: {} }, web3.PublicKey.default);
+    });
+
+    it("cap fee rates at the protocol cap", async () => {
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateCapBp).to.be.eq(feeRateCapBp);
+
+        try {
+            await program.methods
+                .setFeeRate(feeRateCapBp + 1)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("fee rate above the protocol cap should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("FeeRateAboveCap");
+        }
+
+        try {
+            await program.methods
+                .setDirectionFeeRate(null, feeRateCapBp + 1)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("fee rate above the protocol cap should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("FeeRateAboveCap");
+        }
+    });
+
+    it("queue timelocked fee changes", async () => {
+        const pendingChangeFor = (id: number) =>
+            web3.PublicKey.findProgramAddressSync(
+                [Buffer.from("pending"), new BN(id).toArrayLike(Buffer, "le", 8)],
+                program.programId,
+            )[0];
+        const blockTime = async () => {
+            let time = await connection.getBlockTime(await connection.getSlot());
+            if (!time) {
+                throw new Error("block time is null");
+            }
+            return time;
+        };
+        const feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        const delay = 2;
+
+        tx = await program.methods
+            .setTimelockDelay(new BN(delay))
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set timelock delay tx: ${tx}`);
+
+        try {
+            await program.methods
+                .setFeeRate(9999)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("setFeeRate should be queued while the timelock is set");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockRequired");
+        }
+
+        try {
+            await program.methods
+                .setFeeRecepient()
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    feeRecepient: user.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("setFeeRecepient should be queued while the timelock is set");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockRequired");
+        }
+
+        try {
+            await program.methods
+                .queueAdminChange(new BN(1), { feeRate: { feeRateBp: feeRateBp + 10 } }, new BN(await blockTime()))
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    pendingChange: pendingChangeFor(1),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            expect.fail("queueAdminChange before the delay should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidEffectiveTime");
+        }
+
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(1),
+                { feeRate: { feeRateBp: feeRateBp + 10 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(1),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`queue fee rate tx: ${tx}`);
+
+        try {
+            await program.methods
+                .executeAdminChange(new BN(1))
+                .accounts({
+                    payer: payer.publicKey,
+                    adminSettings: adminSettings,
+                    pendingChange: pendingChangeFor(1),
+                    tokenSettings: null,
+                })
+                .rpc();
+            expect.fail("executeAdminChange before the effective time should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockNotElapsed");
+        }
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp);
+
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(2),
+                { feeRate: { feeRateBp: feeRateBp + 20 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(2),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        tx = await program.methods
+            .cancelAdminChange(new BN(2))
+            .accounts({
+                authority: newAdmin.publicKey,
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(2),
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`cancel fee rate tx: ${tx}`);
+        expect(await connection.getAccountInfo(pendingChangeFor(2))).to.be.null;
+
+        // queueing a fee discount opens the discount account empty, execute sets it
+        let discountUser = web3.Keypair.generate().publicKey;
+        let [feeDiscount] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("discount"), discountUser.toBytes()],
+            program.programId,
+        );
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(4),
+                { feeDiscount: { user: discountUser, discountBp: 500 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(4),
+                feeDiscount: feeDiscount,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`queue fee discount tx: ${tx}`);
+        expect((await program.account.feeDiscount.fetch(feeDiscount)).discountBp).to.be.eq(0);
+
+        await sleep((delay + 3) * 1000);
+        tx = await program.methods
+            .executeAdminChange(new BN(1))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(1),
+                tokenSettings: null,
+            })
+            .rpc();
+        console.log(`execute fee rate tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp + 10);
+        expect(await connection.getAccountInfo(pendingChangeFor(1))).to.be.null;
+
+        tx = await program.methods
+            .executeAdminChange(new BN(4))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(4),
+                tokenSettings: null,
+                feeDiscount: feeDiscount,
+            })
+            .rpc();
+        console.log(`execute fee discount tx: ${tx}`);
+        expect((await program.account.feeDiscount.fetch(feeDiscount)).discountBp).to.be.eq(500);
+
+        // lift the timelock and restore the fee rate
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(3),
+                { timelockDelay: { delay: new BN(0) } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(3),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        await sleep((delay + 3) * 1000);
+        tx = await program.methods
+            .executeAdminChange(new BN(3))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(3),
+                tokenSettings: null,
+            })
+            .rpc();
+        expect((await program.account.adminSettings.fetch(adminSettings)).timelockDelay.toNumber()).to.be.eq(0);
+
+        tx = await program.methods
+            .setFeeRate(feeRateBp)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+
+        tx = await program.methods
+            .removeFeeDiscount(discountUser)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeDiscount: feeDiscount,
+            })
+            .signers([newAdmin])
+            .rpc();
+    });
+
+    it("keep current accounts on the versioned layout", async () => {
+        expect((await program.account.adminSettings.fetch(adminSettings)).version).to.be.eq(1);
+
+        try {
+            await program.methods
+                .migrateAdminSettings(feeRateCapBp)
+                .accounts({
+                    payer: payer.publicKey,
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            expect.fail("migrating an account on the current layout should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AlreadyMigrated");
+        }
+    });
+
+    it("migrate accounts written by the unversioned release", async () => {
+        // loaded from tests/fixtures, see Anchor.toml
+        let legacyMint = new web3.PublicKey(Buffer.alloc(32, 9));
+        let legacyTokenSettings = tokenSettingsOf(legacyMint);
+        let legacyUuid = Array.from(Buffer.alloc(32, 0x42));
+        let [legacyEscrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(legacyUuid)], program.programId);
+        expect((await connection.getAccountInfo(legacyTokenSettings)).data.length).to.be.eq(16);
+        expect((await connection.getAccountInfo(legacyEscrow)).data.length).to.be.eq(304);
+
+        // escrows need no admin to migrate, any payer can
+        let migrateEscrow = () =>
+            program.methods
+                .migrateEscrow(legacyUuid)
+                .accounts({
+                    payer: user.publicKey,
+                    escrow: legacyEscrow,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([user])
+                .rpc();
+
+        tx = await program.methods
+            .migrateTokenSettings(legacyMint)
+            .accounts({
+                payer: payer.publicKey,
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: legacyTokenSettings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`migrate token settings tx: ${tx}`);
+
+        let tokenSettings = await program.account.tokenSettings.fetch(legacyTokenSettings);
+        expect(tokenSettings.version).to.be.eq(1);
+        expect(tokenSettings.maxFee.toNumber()).to.be.eq(777);
+        expect(tokenSettings.feeRateBp).to.be.null;
+        expect(tokenSettings.enabled).to.be.true;
+
+        tx = await migrateEscrow();
+        console.log(`migrate escrow tx: ${tx}`);
+
+        let escrow = await program.account.escrow.fetch(legacyEscrow);
+        expect(escrow.version).to.be.eq(1);
+        expect(escrow.from.toBuffer()).to.deep.eq(Buffer.alloc(32, 1));
+        expect(escrow.tokenProgram.toBase58()).to.be.eq(TOKEN_PROGRAM_ID.toBase58());
+        expect(escrow.mint.toBase58()).to.be.eq(legacyMint.toBase58());
+        expect(escrow.solAmount.toNumber()).to.be.eq(1000000);
+        expect(escrow.tokenFee.toNumber()).to.be.eq(5);
+        expect(escrow.lock.hash).to.deep.eq(Array(32).fill(7));
+        expect(escrow.lock.earliestRefundTime.toNumber()).to.be.eq(1700000100);
+        expect(escrow.lock.hashAlgorithm).to.deep.eq({ keccak256: {} });
+        expect(escrow.isOut).to.be.true;
+        expect(escrow.feeMode).to.deep.eq({ deducted: {} });
+        expect(escrow.referrer.toBase58()).to.be.eq(web3.PublicKey.default.toBase58());
+
+        // the migrated account now carries its version
+        try {
+            await migrateEscrow();
+            expect.fail("migrating an escrow twice should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AlreadyMigrated");
+        }
+    });
+
+    it("set fee rounding", async () => {
+        try {
+            await program.methods
+                .setFeeRounding({ ceil: {} })
+                .accounts({
+                    authority: user.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("setFeeRounding by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        tx = await program.methods
+            .setFeeRounding({ ceil: {} })
+            .accounts({
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                feeDestination: null,
-                systemProgram: web3.SystemProgram.programId,
-                tokenProgram: null,
             })
-            .signers([lp])
+            .signers([newAdmin])
             .rpc();
+        console.log(`set fee rounding tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRounding).to.be.deep.eq({ ceil: {} });
 
-        console.log(`confirm transfer in tx: ${tx4}`);
-
-        let userSOLBalAfter = new BN(await connection.getBalance(user.publicKey));
-        expect(userSOLBalBefore.sub(userSOLBalAfter).toNumber()).to.be.eq(feeSOL.toNumber());
-
-        let lpSOLBalAfter = new BN(await connection.getBalance(lp.publicKey));
-        expect(lpSOLBalBefore.sub(lpSOLBalAfter).toNumber()).to.be.eq(feeSOL.toNumber());
-
-        let feeRecepientSOLBalAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
-        expect(feeRecepientSOLBalAfter.sub(feeRecepientSOLBalBefore).toString()).to.be.eq(
-            feeSOL.mul(new BN(2)).toString(),
-        );
+        tx = await program.methods
+            .setFeeRounding({ floor: {} })
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRounding).to.be.deep.eq({ floor: {} });
     });
 
-    it("cannot call initiate after deadline", async () => {
+    it("share SOL fee with referrer", async () => {
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
         if (!agreementReachedTime) {
             throw new Error("agreementReachedTime is null");
         }
 
-        let expectedSingleStepTime = 1;
-        let tolerantSingleStepTime = 1;
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
 
-        let lock = {
+        let lock: Lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -693,7 +3123,9 @@ describe("OBridge", () => {
             ),
         };
 
-        console.log(`lock: ${JSON.stringify(lock)}`);
+        const ZERO_PUBKEY = new web3.PublicKey(new Uint8Array(32).fill(0));
+        const solAmount1 = new BN(2 * 10 ** 9);
+        const tokenAmount1 = new BN(0);
 
         let uuid1 = generateUuid(
             user.publicKey,
@@ -703,63 +3135,184 @@ describe("OBridge", () => {
             lock.expectedSingleStepTime,
             lock.tolerantSingleStepTime,
             lock.earliestRefundTime,
-            mint1,
-            tokenAmount,
-            solAmount,
+            ZERO_PUBKEY,
+            tokenAmount1,
+            solAmount1,
         );
-        console.log(`generate uuid1: ${uuid1}`);
-
-        // calculate escrow account address offchain without create it
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
-        console.log(`offchain escrow1: ${escrow1}`);
-
-        // calculate escrowAtaTokenAccount account address offchain without create it
-        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
-        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
-
         let memo = Buffer.from([1, 2, 3, 4, 5]);
+        let referrer = await createAccountOnChain(connection, payer);
+        let [referralVault] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("referral"), referrer.publicKey.toBytes()],
+            program.programId,
+        );
+        let prepare = (shareBp: number, referrerKey: web3.PublicKey = referrer.publicKey) =>
+            program.methods
+                .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { deducted: {} }, {
+                    referrer: referrerKey,
+                    shareBp,
+                })
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    mint: null,
+                    source: null,
+                    escrow: escrow1,
+                    escrowAta: null,
+                    adminSettings: adminSettings,
+                    tokenSettings: solSettings,
+                    solSettings: null,
+                    feeDiscount: null,
+                    referrer: referrerKey,
+                    associatedTokenProgram: null,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: null,
+                })
+                .signers([user])
+                .rpc();
 
-        // sleep until the transfer out deadline
-        await sleep(5000);
+        let maxReferralShareBp = 3000;
+        tx = await program.methods
+            .setMaxReferralShare(maxReferralShareBp)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set max referral share tx: ${tx}`);
 
         try {
-            await program.methods
-                .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+            await prepare(maxReferralShareBp + 1);
+            expect.fail("referral share above the max should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidReferralShare");
+        }
+
+        let shareBp = 2000;
+
+        // shares are claimed later, the referrer has to be a key that can sign for them
+        for (let referrerKey of [web3.PublicKey.default, program.programId]) {
+            try {
+                await prepare(shareBp, referrerKey);
+                expect.fail("prepare with a referrer that cannot claim should fail");
+            } catch (err: any) {
+                expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidReferrer");
+            }
+        }
+
+        tx = await prepare(shareBp);
+        console.log(`transfer out tx: ${tx}`);
+
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Data.referrer.toBase58()).to.be.eq(referrer.publicKey.toBase58());
+        expect(escrow1Data.referralShareBp).to.be.eq(shareBp);
+
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
+
+        let confirm = (vault: web3.PublicKey | null) =>
+            program.methods
+                .confirm(uuid1, preimage, isOut)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
-                    mint: mint1,
-                    source: userAtaTokenMint1Account.address,
+                    to: lp.publicKey,
+                    destination: null,
                     escrow: escrow1,
-                    escrowAta: escrow1AtaTokenAccount,
+                    escrowAta: null,
+                    mint: null,
                     adminSettings: adminSettings,
-                    tokenSettings: null,
-                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    feeVault: null,
+                    associatedTokenProgram: null,
+                    referralVault: vault,
+                    referralVaultAta: null,
                     systemProgram: web3.SystemProgram.programId,
-                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenProgram: null,
+                })
+                .signers([user])
+                .rpc({ commitment: "confirmed" });
+
+        // an escrow with a referrer has to bring its referral vault
+        try {
+            await confirm(null);
+            expect.fail("confirm without the referral vault should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidAccount");
+        }
+
+        tx = await confirm(referralVault);
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        // the referral share accrues in the referral vault
+        let referralFee = escrow1Data.solFee.mul(new BN(shareBp)).div(new BN(10000));
+        let confirmed = (await getCpiEvents(connection, program, tx)).find((e) => e.name === "Confirmed");
+        expect(confirmed).not.to.be.undefined;
+        expect(confirmed!.data.referrer.toBase58()).to.be.eq(referrer.publicKey.toBase58());
+        expect(confirmed!.data.solReferralFee.toString()).to.be.eq(referralFee.toString());
+        expect(confirmed!.data.tokenReferralFee.toString()).to.be.eq("0");
+        let referralVaultRent = new BN(await connection.getMinimumBalanceForRentExemption(8 + 32));
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect((await program.account.referralVault.fetch(referralVault)).referrer.toBase58()).to.be.eq(
+            referrer.publicKey.toBase58(),
+        );
+        expect(await connection.getBalance(referralVault)).to.be.eq(referralVaultRent.add(referralFee).toNumber());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(
+            escrow1Data.solFee.sub(referralFee).toString(),
+        );
+
+        // only the referrer can claim
+        try {
+            await program.methods
+                .claimReferralFees()
+                .accounts({
+                    referrer: user.publicKey,
+                    referralVault: referralVault,
+                    mint: null,
+                    referralVaultAta: null,
+                    destination: null,
+                    tokenProgram: null,
                 })
                 .signers([user])
                 .rpc();
+            expect.fail("claim by another key should fail");
         } catch (err: any) {
-            console.log(`if it exceeds transfer out time limit, it should throw error`);
-            console.log(`========== error ==========`);
-            console.log((err as AnchorError).logs);
-            expect((err as AnchorError).logs).not.to.be.empty;
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("ConstraintSeeds");
         }
+
+        let referrerSOLBalBefore = new BN(await connection.getBalance(referrer.publicKey));
+        tx = await program.methods
+            .claimReferralFees()
+            .accounts({
+                referrer: referrer.publicKey,
+                referralVault: referralVault,
+                mint: null,
+                referralVaultAta: null,
+                destination: null,
+                tokenProgram: null,
+            })
+            .signers([referrer])
+            .rpc();
+        console.log(`claim referral fees tx: ${tx}`);
+
+        let referrerSOLBalAfter = new BN(await connection.getBalance(referrer.publicKey));
+        expect(referrerSOLBalAfter.sub(referrerSOLBalBefore).toString()).to.be.eq(referralFee.toString());
+        expect(await connection.getBalance(referralVault)).to.be.eq(referralVaultRent.toNumber());
     });
 
-    it("cannot call initiate with amount 0", async () => {
+    it("pay SOL fee on top of the quoted amount", async () => {
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
         if (!agreementReachedTime) {
             throw new Error("agreementReachedTime is null");
         }
 
-        let expectedSingleStepTime = 2;
-        let tolerantSingleStepTime = 3;
+        let expectedSingleStepTime = 5;
+        let tolerantSingleStepTime = 10;
 
-        let lock = {
+        let lock: Lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -767,7 +3320,10 @@ describe("OBridge", () => {
                 agreementReachedTime + 3 * expectedSingleStepTime + 3 * tolerantSingleStepTime + 1,
             ),
         };
-        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        const ZERO_PUBKEY = new web3.PublicKey(new Uint8Array(32).fill(0));
+        const solAmount1 = new BN(2 * 10 ** 9);
+        const tokenAmount1 = new BN(0);
 
         let uuid1 = generateUuid(
             user.publicKey,
@@ -777,46 +3333,153 @@ describe("OBridge", () => {
             lock.expectedSingleStepTime,
             lock.tolerantSingleStepTime,
             lock.earliestRefundTime,
-            mint1,
-            tokenAmount,
-            solAmount,
+            ZERO_PUBKEY,
+            tokenAmount1,
+            solAmount1,
         );
-        console.log(`generate uuid1: ${uuid1}`);
-
-        // calculate escrow account address offchain without create it
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
-        console.log(`offchain escrow1: ${escrow1}`);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
 
-        // calculate escrowAtaTokenAccount account address offchain without create it
-        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
-        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+        tx = await program.methods
+            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { onTop: {} }, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                mint: null,
+                source: null,
+                escrow: escrow1,
+                escrowAta: null,
+                adminSettings: adminSettings,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
 
-        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        // the escrow holds the quoted amount plus the fee
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        let escrow1Info = await connection.getAccountInfo(escrow1);
+        let rentExempt = await connection.getMinimumBalanceForRentExemption(escrow1Info.data.length);
+        expect(escrow1Data.feeMode).to.be.deep.eq({ onTop: {} });
+        expect(escrow1Data.solAmount.toString()).to.be.eq(solAmount1.toString());
+        expect(escrow1Data.solFee.toNumber()).to.be.gt(0);
+        expect(escrow1Info.lamports - rentExempt).to.be.eq(solAmount1.add(escrow1Data.solFee).toNumber());
+
+        let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
+
+        tx = await program.methods
+            .confirm(uuid1, preimage, isOut)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                destination: null,
+                escrow: escrow1,
+                escrowAta: null,
+                mint: null,
+                adminSettings: adminSettings,
+                feeVault: null,
+                associatedTokenProgram: null,
+                referralVault: null,
+                referralVaultAta: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`confirm transfer out tx: ${tx}`);
+
+        // the recepient receives exactly the quoted amount
+        let lpSOLBalAfter = new BN(await connection.getBalance(lp.publicKey));
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(lpSOLBalAfter.sub(lpSOLBalBefore).toString()).to.be.eq(solAmount1.toString());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(escrow1Data.solFee.toString());
+    });
+
+    it("withdraw collected fees", async () => {
+        let feeMint1Vault = getAssociatedTokenAddressSync(mint1, adminSettings, true);
+        let feeMint2Vault = getAssociatedTokenAddressSync(mint2, adminSettings, true);
+        let feeRecepientMint1Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint1,
+            feeRecepient.publicKey,
+        );
+        // the approving admins may pick any destination, not only accounts of the fee recepient
+        let treasury = web3.Keypair.generate();
+        let treasuryMint2Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint2,
+            treasury.publicKey,
+        );
 
+        let vaultMint1BalBefore = (await getAccount(connection, feeMint1Vault)).amount;
+        let vaultMint2BalBefore = (await getAccount(connection, feeMint2Vault)).amount;
+        expect(vaultMint1BalBefore > BigInt(0)).to.be.true;
+        expect(vaultMint2BalBefore > BigInt(0)).to.be.true;
+
+        let adminSettingsInfo = await connection.getAccountInfo(adminSettings);
+        let rentExempt = await connection.getMinimumBalanceForRentExemption(adminSettingsInfo.data.length);
+        let vaultSOLBalBefore = adminSettingsInfo.lamports - rentExempt;
+        await transferSOL(connection, payer, feeRecepient.publicKey, web3.LAMPORTS_PER_SOL);
+        let feeRecepientSOLBalBefore = await connection.getBalance(feeRecepient.publicKey);
+
+        // only the admin can withdraw
         try {
             await program.methods
-                .prepare(uuid1, lp.publicKey, solAmount, new BN(0), lock, isOut, memo)
+                .withdrawFees(0, Buffer.from([]))
                 .accounts({
-                    payer: user.publicKey,
-                    from: user.publicKey,
-                    mint: mint1,
-                    source: userAtaTokenMint1Account.address,
-                    escrow: escrow1,
-                    escrowAta: escrow1AtaTokenAccount,
+                    authority: user.publicKey,
                     adminSettings: adminSettings,
-                    tokenSettings: null,
-                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-                    systemProgram: web3.SystemProgram.programId,
-                    tokenProgram: TOKEN_PROGRAM_ID,
+                    feeRecepient: feeRecepient.publicKey,
                 })
-                .signers([user, user])
+                .signers([user])
                 .rpc();
+            expect.fail("withdrawFees by non admin should fail");
         } catch (err: any) {
-            console.log(`if the token amount is 0, it should throw error`);
-            console.log(`========== error ==========`);
-            console.log((err as AnchorError).logs);
-            expect((err as AnchorError).logs).not.to.be.empty;
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
         }
+
+        let tx = await program.methods
+            .withdrawFees(0, Buffer.from([0, 0]))
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeRecepient: feeRecepient.publicKey,
+            })
+            .remainingAccounts([
+                { pubkey: mint1, isSigner: false, isWritable: false },
+                { pubkey: feeMint1Vault, isSigner: false, isWritable: true },
+                { pubkey: feeRecepientMint1Account.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+                { pubkey: mint2, isSigner: false, isWritable: false },
+                { pubkey: feeMint2Vault, isSigner: false, isWritable: true },
+                { pubkey: treasuryMint2Account.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+            ])
+            .signers([newAdmin])
+            .rpc();
+        console.log(`withdraw fees tx: ${tx}`);
+
+        expect((await getAccount(connection, feeMint1Vault)).amount.toString()).to.be.eq("0");
+        expect((await getAccount(connection, feeMint2Vault)).amount.toString()).to.be.eq("0");
+        expect((await getAccount(connection, feeRecepientMint1Account.address)).amount.toString()).to.be.eq(
+            (feeRecepientMint1Account.amount + vaultMint1BalBefore).toString(),
+        );
+        expect((await getAccount(connection, treasuryMint2Account.address)).amount.toString()).to.be.eq(
+            vaultMint2BalBefore.toString(),
+        );
+        expect(await connection.getBalance(adminSettings)).to.be.eq(rentExempt);
+        expect(await connection.getBalance(feeRecepient.publicKey)).to.be.eq(
+            feeRecepientSOLBalBefore + vaultSOLBalBefore,
+        );
     });
 
     it("refund SPL A Token <-> SPL B Token + SOL", async () => {
@@ -832,6 +3495,8 @@ describe("OBridge", () => {
 
         let lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -874,7 +3539,7 @@ describe("OBridge", () => {
         // user initate a swap by sending transfer out
         console.log(`========== transfer out ==========`);
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -883,7 +3548,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint1Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -918,7 +3585,7 @@ describe("OBridge", () => {
 
         // lp response to the swap initiated by user (transfer in)
         const tx2 = await program.methods
-            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo)
+            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo, { deducted: {} }, null)
             .accounts({
                 payer: lp.publicKey,
                 from: lp.publicKey,
@@ -927,7 +3594,9 @@ describe("OBridge", () => {
                 escrow: escrow2,
                 escrowAta: escrow2AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint2Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -947,6 +3616,7 @@ describe("OBridge", () => {
                     source: userAtaTokenMint1Account.address,
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
+                    mint: mint1,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
                 })
@@ -979,12 +3649,17 @@ describe("OBridge", () => {
                 source: userAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                mint: mint1,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
-            .rpc();
+            .rpc({ commitment: "confirmed" });
 
         console.log(`user refund transfer out tx: ${tx5}`);
+        let refunded = (await getCpiEvents(connection, program, tx5)).find((e) => e.name === "Refunded");
+        expect(refunded).not.to.be.undefined;
+        expect(refunded!.data.feeMode).to.deep.eq({ deducted: {} });
+        expect(refunded!.data.referrer.toBase58()).to.be.eq(web3.PublicKey.default.toBase58());
 
         console.log(`========== refund transfer in ==========`);
         // lp refund the swap (transfer in)
@@ -995,6 +3670,7 @@ describe("OBridge", () => {
                 source: lpAtaTokenMint2Account.address,
                 escrow: escrow2,
                 escrowAta: escrow2AtaTokenAccount,
+                mint: mint2,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
@@ -1028,6 +3704,8 @@ describe("OBridge", () => {
 
         let lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -1066,7 +3744,7 @@ describe("OBridge", () => {
         // user initate a swap by sending transfer out
         console.log(`========== transfer out ==========`);
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, solAmount1, tokenAmount1, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -1075,7 +3753,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: null,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -1106,7 +3786,7 @@ describe("OBridge", () => {
 
         // lp response to the swap initiated by user (transfer in)
         const tx2 = await program.methods
-            .prepare(uuid2, user.publicKey, solAmount1, tokenAmount1, lock, isIn, memo)
+            .prepare(uuid2, user.publicKey, solAmount1, tokenAmount1, lock, isIn, memo, { deducted: {} }, null)
             .accounts({
                 payer: lp.publicKey,
                 from: lp.publicKey,
@@ -1115,7 +3795,9 @@ describe("OBridge", () => {
                 escrow: escrow2,
                 escrowAta: null,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: solSettings,
+                solSettings: null,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -1135,6 +3817,7 @@ describe("OBridge", () => {
                     source: null,
                     escrow: escrow1,
                     escrowAta: null,
+                    mint: null,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: null,
                 })
@@ -1167,6 +3850,7 @@ describe("OBridge", () => {
                 source: null,
                 escrow: escrow1,
                 escrowAta: null,
+                mint: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
             })
@@ -1183,6 +3867,7 @@ describe("OBridge", () => {
                 source: null,
                 escrow: escrow2,
                 escrowAta: null,
+                mint: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
             })
@@ -1215,6 +3900,8 @@ describe("OBridge", () => {
 
         let lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -1257,7 +3944,7 @@ describe("OBridge", () => {
         tx = await program.methods
             .setFeeRate(0)
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
             })
             .signers([newAdmin])
@@ -1267,7 +3954,7 @@ describe("OBridge", () => {
 
         // user initiate the swap (transfer out)
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, new BN(0), tokenAmount, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, new BN(0), tokenAmount, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -1276,7 +3963,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint1Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -1290,13 +3979,8 @@ describe("OBridge", () => {
         // lp token mint1 ata address
         let lpAtaTokenMint1Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, lp.publicKey);
 
-        // fee recepient mint1 ata address
-        let feeMin1Destination = await getOrCreateAssociatedTokenAccount(
-            connection,
-            payer,
-            mint1,
-            feeRecepient.publicKey,
-        );
+        // fee vault mint1 ata address
+        let feeMint1Vault = getAssociatedTokenAddressSync(mint1, adminSettings, true);
 
         // user confirm the swap (transfer out)
         const tx3 = await program.methods
@@ -1308,9 +3992,10 @@ describe("OBridge", () => {
                 destination: lpAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                mint: mint1,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                feeDestination: feeMin1Destination.address,
+                feeVault: feeMint1Vault,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
@@ -1336,6 +4021,8 @@ describe("OBridge", () => {
 
         let lock = {
             hash: hashlock,
+            hashAlgorithm: { keccak256: {} },
+            maxPreimageLen: 0,
             agreementReachedTime: new BN(agreementReachedTime),
             expectedSingleStepTime: new BN(expectedSingleStepTime),
             tolerantSingleStepTime: new BN(tolerantSingleStepTime),
@@ -1372,7 +4059,7 @@ describe("OBridge", () => {
 
         // transfer out
         tx = await program.methods
-            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo)
+            .prepare(uuid1, lp.publicKey, solAmount, tokenAmount, lock, isOut, memo, { deducted: {} }, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -1381,7 +4068,9 @@ describe("OBridge", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint1Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -1416,7 +4105,7 @@ describe("OBridge", () => {
 
         // lp response to the swap initiated by user (transfer in)
         const tx2 = await program.methods
-            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo)
+            .prepare(uuid2, user.publicKey, solAmountBack, tokenAmountBack, lock, isIn, memo, { deducted: {} }, null)
             .accounts({
                 payer: lp.publicKey,
                 from: lp.publicKey,
@@ -1425,7 +4114,9 @@ describe("OBridge", () => {
                 escrow: escrow2,
                 escrowAta: escrow2AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: null,
+                tokenSettings: mint2Settings,
+                solSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -1440,13 +4131,8 @@ describe("OBridge", () => {
         // lp token mint1 ata address
         let lpAtaTokenMint1Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, lp.publicKey);
 
-        // fee recepient mint1 ata address
-        let feeMin1Destination = await getOrCreateAssociatedTokenAccount(
-            connection,
-            payer,
-            mint1,
-            feeRecepient.publicKey,
-        );
+        // fee vault mint1 ata address
+        let feeMint1Vault = getAssociatedTokenAddressSync(mint1, adminSettings, true);
         // user cannot confirm the swap by using confirm in function
         try {
             const tx3 = await program.methods
@@ -1458,9 +4144,10 @@ describe("OBridge", () => {
                     destination: lpAtaTokenMint1Account.address,
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
+                    mint: mint1,
                     adminSettings: adminSettings,
-                    feeRecepient: feeRecepient.publicKey,
-                    feeDestination: feeMin1Destination.address,
+                    feeVault: feeMint1Vault,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
                 })
@@ -2,12 +2,15 @@ import crypto from "crypto";
 import * as anchor from "@coral-xyz/anchor";
 import { Program, web3, AnchorError } from "@coral-xyz/anchor";
 import { ObridgeSwap } from "../target/types/obridge_swap";
+import { TransferHook } from "../target/types/transfer_hook";
 import {
     getAccount,
     TOKEN_PROGRAM_ID,
+    TOKEN_2022_PROGRAM_ID,
     ASSOCIATED_TOKEN_PROGRAM_ID,
     getAssociatedTokenAddressSync,
     getOrCreateAssociatedTokenAccount,
+    mintTo,
     Account,
 } from "@solana/spl-token";
 import BN, { min } from "bn.js";
@@ -15,10 +18,12 @@ import {
     createAccountOnChain,
     airdropSOL,
     createSPLTokenAndMintToUser,
+    createTransferHookTokenAndMintToUser,
     transferSOL,
     sleep,
     splTokensBalance,
     generateUuidSwap,
+    getCpiEvents,
 } from "./helper";
 import { expect } from "chai";
 
@@ -36,6 +41,7 @@ describe("OBridge Swap", () => {
     console.log(`network connected: ${connection.rpcEndpoint}`);
     const program = anchor.workspace.ObridgeSwap as Program<ObridgeSwap>;
     console.log(`obridge_swap program: ${program.programId}`);
+    const hookProgram = anchor.workspace.TransferHook as Program<TransferHook>;
 
     // create user and airdrop SOL
     const payer = web3.Keypair.generate();
@@ -47,6 +53,8 @@ describe("OBridge Swap", () => {
     const newAdmin = web3.Keypair.generate();
     console.log(`new admin: ${newAdmin.publicKey}`);
 
+    const feeRateCapBp = 5000;
+
     let feeRecepient = web3.Keypair.generate();
     console.log(`feeRecepient: ${feeRecepient.publicKey}`);
 
@@ -63,14 +71,45 @@ describe("OBridge Swap", () => {
     let lpAtaTokenMint2Account: Account;
 
     let adminSettings: web3.PublicKey;
+    let solSettings: web3.PublicKey;
 
     let userAtaTokenMint2Account: Account;
     let lpAtaTokenMint1Account: Account;
-    let feeMint1Destination: Account;
-    let feeMint2Destination: Account;
+    let feeMint1Vault: Account;
+    let feeMint2Vault: Account;
 
     let tx: string;
 
+    function tokenSettingsOf(mint: web3.PublicKey) {
+        return web3.PublicKey.findProgramAddressSync([Buffer.from("token"), mint.toBytes()], program.programId)[0];
+    }
+
+    async function registerToken(mint: web3.PublicKey) {
+        return await program.methods
+            .registerToken(mint)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: tokenSettingsOf(mint),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+    }
+
+    async function deregisterToken(mint: web3.PublicKey) {
+        return await program.methods
+            .deregisterToken(mint)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: tokenSettingsOf(mint),
+            })
+            .signers([newAdmin])
+            .rpc();
+    }
+
     beforeEach(async () => {
         console.log(`========== setup up ==========`);
         await airdropSOL(connection, payer, 1000 * 10 ** 9);
@@ -124,6 +163,15 @@ describe("OBridge Swap", () => {
         [adminSettings] = web3.PublicKey.findProgramAddressSync([Buffer.from("settings")], program.programId);
         console.log(`offchain adminSettings: ${adminSettings.toBase58()}`);
 
+        solSettings = tokenSettingsOf(web3.PublicKey.default);
+
+        // submitSwap only accepts registered tokens, register them once the program is initialized
+        if (await connection.getAccountInfo(adminSettings)) {
+            await registerToken(mint1);
+            await registerToken(mint2);
+            await registerToken(web3.PublicKey.default);
+        }
+
         // user token mint2 ata address
         userAtaTokenMint2Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint2, user.publicKey);
         console.log(`user mint2 ata ${userAtaTokenMint2Account.address}`);
@@ -132,13 +180,13 @@ describe("OBridge Swap", () => {
         lpAtaTokenMint1Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, lp.publicKey);
         console.log(`lp mint1 ata ${lpAtaTokenMint1Account.address}`);
 
-        // fee recepient mint2 ata address
-        feeMint1Destination = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, feeRecepient.publicKey);
-        console.log(`fee mint1 ata ${feeMint1Destination.address}`);
+        // fee vault mint1 ata address
+        feeMint1Vault = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, adminSettings, true);
+        console.log(`fee vault mint1 ata ${feeMint1Vault.address}`);
 
-        // fee recepient mint2 ata address
-        feeMint2Destination = await getOrCreateAssociatedTokenAccount(connection, payer, mint2, feeRecepient.publicKey);
-        console.log(`fee mint2 ata ${feeMint2Destination.address}`);
+        // fee vault mint2 ata address
+        feeMint2Vault = await getOrCreateAssociatedTokenAccount(connection, payer, mint2, adminSettings, true);
+        console.log(`fee vault mint2 ata ${feeMint2Vault.address}`);
     });
 
     it("swap SPL A Token <-> SPL B Token", async () => {
@@ -150,9 +198,9 @@ describe("OBridge Swap", () => {
         let lpMint2BalBefore = new BN(lpAtaTokenMint2Account.amount.toString());
         let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
 
-        let feeRecepientMint1BalBefore = new BN(0);
-        let feeRecepientMint2BalBefore = new BN(0);
-        let feeRecepientSOLBalBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultMint1BalBefore = new BN(0);
+        let feeVaultMint2BalBefore = new BN(0);
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -195,7 +243,7 @@ describe("OBridge Swap", () => {
         // got error before initialize program
         try {
             await program.methods
-                .submitSwap(uuid1, amount, amountBack, lock, memo)
+                .submitSwap(uuid1, amount, amountBack, lock, memo, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -206,8 +254,9 @@ describe("OBridge Swap", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    srcTokenSettings: null,
-                    dstTokenSettings: null,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -223,7 +272,7 @@ describe("OBridge Swap", () => {
 
         // initialize program
         tx = await program.methods
-            .initialize(admin.publicKey)
+            .initialize(admin.publicKey, feeRateCapBp)
             .accounts({
                 payer: payer.publicKey,
                 adminSettings: adminSettings,
@@ -237,7 +286,7 @@ describe("OBridge Swap", () => {
         // cannot initialize program again
         try {
             await program.methods
-                .initialize(newAdmin.publicKey)
+                .initialize(newAdmin.publicKey, feeRateCapBp)
                 .accounts({
                     payer: payer.publicKey,
                     adminSettings: adminSettings,
@@ -269,11 +318,11 @@ describe("OBridge Swap", () => {
         tx = await program.methods
             .setFeeRecepient()
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 feeRecepient: feeRecepient.publicKey,
                 adminSettings: adminSettings,
             })
-            .signers([newAdmin, feeRecepient])
+            .signers([newAdmin])
             .rpc();
 
         console.log(`set fee recepient tx: ${tx}`);
@@ -282,7 +331,7 @@ describe("OBridge Swap", () => {
         tx = await program.methods
             .setFeeRate(1000)
             .accounts({
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
             })
             .signers([newAdmin])
@@ -290,12 +339,16 @@ describe("OBridge Swap", () => {
 
         console.log(`set fee rate tx: ${tx}`);
 
+        await registerToken(mint1);
+        await registerToken(mint2);
+        await registerToken(web3.PublicKey.default);
+
         let feeMint1 = amount.mul(new BN(1000)).div(new BN(10000));
         let feeMint2 = amountBack.mul(new BN(1000)).div(new BN(10000));
 
         // transfer out
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -306,8 +359,9 @@ describe("OBridge Swap", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
-                dstTokenSettings: null,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -320,7 +374,7 @@ describe("OBridge Swap", () => {
         // try to use same uuid for wrong test
         try {
             await program.methods
-                .submitSwap(uuid1, amount, amountBack, lock, memo)
+                .submitSwap(uuid1, amount, amountBack, lock, memo, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -331,8 +385,9 @@ describe("OBridge Swap", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    srcTokenSettings: null,
-                    dstTokenSettings: null,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -360,12 +415,15 @@ describe("OBridge Swap", () => {
                 toDestination: lpAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                srcToken: mint1,
+                dstToken: mint2,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: feeMint1Destination.address,
-                dstFeeDestination: feeMint2Destination.address,
+                srcFeeVault: feeMint1Vault.address,
+                dstFeeVault: feeMint2Vault.address,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
+                dstTokenProgram: TOKEN_PROGRAM_ID,
             })
             .signers([lp])
             .rpc();
@@ -390,16 +448,12 @@ describe("OBridge Swap", () => {
         expect(lpMint2BalBefore.sub(lpMint2BalAfter).toString()).to.be.eq(amountBack.toString());
         expect(lpSOLBalAfter.toString()).to.be.eq(lpSOLBalBefore.toString());
 
-        let feeRecepientMint1BalAfter = new BN(
-            (await getAccount(connection, feeMint1Destination.address)).amount.toString(),
-        );
-        let feeRecepientMint2BalAfter = new BN(
-            (await getAccount(connection, feeMint2Destination.address)).amount.toString(),
-        );
-        let feeRecepientSOLBalAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
-        expect(feeRecepientMint1BalAfter.sub(feeRecepientMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
-        expect(feeRecepientMint2BalAfter.sub(feeRecepientMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
-        expect(feeRecepientSOLBalAfter.toString()).to.be.eq(feeRecepientSOLBalBefore.toString());
+        let feeVaultMint1BalAfter = new BN((await getAccount(connection, feeMint1Vault.address)).amount.toString());
+        let feeVaultMint2BalAfter = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(feeVaultMint1BalAfter.sub(feeVaultMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
+        expect(feeVaultMint2BalAfter.sub(feeVaultMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
+        expect(feeVaultSOLBalAfter.toString()).to.be.eq(feeVaultSOLBalBefore.toString());
     });
 
     it("swap SOL <-> SPL B Token", async () => {
@@ -411,9 +465,9 @@ describe("OBridge Swap", () => {
         let lpMint2BalBefore = new BN(lpAtaTokenMint2Account.amount.toString());
         let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
 
-        let feeRecepientMint1BalBefore = new BN(0);
-        let feeRecepientMint2BalBefore = new BN(0);
-        let feeRecepientSOLBalBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultMint1BalBefore = new BN(0);
+        let feeVaultMint2BalBefore = new BN(0);
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -453,7 +507,7 @@ describe("OBridge Swap", () => {
         let memo = Buffer.from([1, 2, 3, 4, 5]);
 
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -464,8 +518,9 @@ describe("OBridge Swap", () => {
                 escrow: escrow1,
                 escrowAta: null,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
-                dstTokenSettings: null,
+                srcTokenSettings: solSettings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -489,12 +544,15 @@ describe("OBridge Swap", () => {
                 toDestination: null,
                 escrow: escrow1,
                 escrowAta: null,
+                srcToken: null,
+                dstToken: mint2,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: null,
-                dstFeeDestination: feeMint2Destination.address,
+                srcFeeVault: null,
+                dstFeeVault: feeMint2Vault.address,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
-                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenProgram: null,
+                dstTokenProgram: TOKEN_PROGRAM_ID,
             })
             .signers([lp])
             .rpc();
@@ -522,16 +580,12 @@ describe("OBridge Swap", () => {
         expect(lpMint2BalBefore.sub(lpMint2BalAfter).toString()).to.be.eq(amountBack.toString());
         expect(lpSOLBalAfter.sub(lpSOLBalBefore).toString()).to.be.eq(amount.sub(feeMint1).toString());
 
-        let feeRecepientMint1BalAfter = new BN(
-            (await getAccount(connection, feeMint1Destination.address)).amount.toString(),
-        );
-        let feeRecepientMint2BalAfter = new BN(
-            (await getAccount(connection, feeMint2Destination.address)).amount.toString(),
-        );
-        let feeRecepientSOLBalAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
-        expect(feeRecepientMint1BalAfter.toString()).to.be.equal(feeRecepientMint1BalBefore.toString());
-        expect(feeRecepientMint2BalAfter.sub(feeRecepientMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
-        expect(feeRecepientSOLBalAfter.sub(feeRecepientSOLBalBefore).toString()).to.be.eq(feeMint1.toString());
+        let feeVaultMint1BalAfter = new BN((await getAccount(connection, feeMint1Vault.address)).amount.toString());
+        let feeVaultMint2BalAfter = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(feeVaultMint1BalAfter.toString()).to.be.equal(feeVaultMint1BalBefore.toString());
+        expect(feeVaultMint2BalAfter.sub(feeVaultMint2BalBefore).toString()).to.be.eq(feeMint2.toString());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(feeMint1.toString());
     });
 
     it("swap SPL A Token <-> SOL", async () => {
@@ -543,9 +597,9 @@ describe("OBridge Swap", () => {
         let lpMint2BalBefore = new BN(lpAtaTokenMint2Account.amount.toString());
         let lpSOLBalBefore = new BN(await connection.getBalance(lp.publicKey));
 
-        let feeRecepientMint1BalBefore = new BN(0);
-        let feeRecepientMint2BalBefore = new BN(0);
-        let feeRecepientSOLBalBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultMint1BalBefore = new BN(0);
+        let feeVaultMint2BalBefore = new BN(0);
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -590,7 +644,7 @@ describe("OBridge Swap", () => {
 
         // got error before initialize program
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -601,8 +655,9 @@ describe("OBridge Swap", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
-                dstTokenSettings: null,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -624,20 +679,22 @@ describe("OBridge Swap", () => {
         // lp token mint1 ata address
         let lpAtaTokenMint1Account = await getOrCreateAssociatedTokenAccount(connection, payer, mint1, lp.publicKey);
 
-        // fee recepient mint2 ata address
-        let feeMint1Destination = await getOrCreateAssociatedTokenAccount(
+        // fee vault mint1 ata address
+        let feeMint1Vault = await getOrCreateAssociatedTokenAccount(
             connection,
             payer,
             mint1,
-            feeRecepient.publicKey,
+            adminSettings,
+            true,
         );
 
-        // fee recepient mint2 ata address
-        let feeMint2Destination = await getOrCreateAssociatedTokenAccount(
+        // fee vault mint2 ata address
+        let feeMint2Vault = await getOrCreateAssociatedTokenAccount(
             connection,
             payer,
             mint2,
-            feeRecepient.publicKey,
+            adminSettings,
+            true,
         );
 
         // lp confirmSwap the swap initiated by user (submitSwap)
@@ -652,12 +709,15 @@ describe("OBridge Swap", () => {
                 toDestination: lpAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                srcToken: mint1,
+                dstToken: null,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: feeMint1Destination.address,
-                dstFeeDestination: null,
+                srcFeeVault: feeMint1Vault.address,
+                dstFeeVault: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
+                dstTokenProgram: null,
             })
             .signers([lp])
             .rpc();
@@ -685,196 +745,659 @@ describe("OBridge Swap", () => {
         expect(lpMint2BalBefore.toString()).to.be.equal(lpMint2BalAfter.toString());
         expect(lpSOLBalBefore.sub(lpSOLBalAfter).toString()).to.be.eq(amountBack.toString());
 
-        let feeRecepientMint1BalAfter = new BN(
-            (await getAccount(connection, feeMint1Destination.address)).amount.toString(),
-        );
-        let feeRecepientMint2BalAfter = new BN(
-            (await getAccount(connection, feeMint2Destination.address)).amount.toString(),
-        );
-        let feeRecepientSOLBalAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
-        expect(feeRecepientMint1BalAfter.sub(feeRecepientMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
-        expect(feeRecepientMint2BalAfter.toString()).to.be.equal(feeRecepientMint2BalBefore.toString());
-        expect(feeRecepientSOLBalAfter.sub(feeRecepientSOLBalBefore).toString()).to.be.eq(feeMint2.toString());
+        let feeVaultMint1BalAfter = new BN((await getAccount(connection, feeMint1Vault.address)).amount.toString());
+        let feeVaultMint2BalAfter = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        expect(feeVaultMint1BalAfter.sub(feeVaultMint1BalBefore).toString()).to.be.eq(feeMint1.toString());
+        expect(feeVaultMint2BalAfter.toString()).to.be.equal(feeVaultMint2BalBefore.toString());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(feeMint2.toString());
     });
 
-    it("refund SPL A Token <-> SPL B Token", async () => {
+    it("share fees with referrer", async () => {
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
         if (!agreementReachedTime) {
             throw new Error("agreementReachedTime is null");
         }
 
-        let stepTime = 1;
-
         let lock: Lock = {
             agreementReachedTime: new BN(agreementReachedTime),
-            stepTime: new BN(stepTime),
+            stepTime: new BN(5),
         };
-        console.log(`lock: ${JSON.stringify(lock)}`);
 
-        console.log(`========== submitSwap ==========`);
+        const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
         let uuid1 = generateUuidSwap(
             user.publicKey,
             lp.publicKey,
-            mint1,
+            zeroPublicKey,
             amount,
             mint2,
             amountBack,
             lock.agreementReachedTime,
             lock.stepTime,
         );
-        console.log(`generate uuid1: ${uuid1}`);
-
-        // calculate escrow account address offchain without create it
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
-        console.log(`offchain escrow1: ${escrow1}`);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
 
-        // calculate escrowAtaTokenAccount account address offchain without create it
-        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
-        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+        let referrer = await createAccountOnChain(connection, payer);
+        let [referralVault] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("referral"), referrer.publicKey.toBytes()],
+            program.programId,
+        );
+        let referralVaultAta = getAssociatedTokenAddressSync(mint2, referralVault, true);
+        let submitSwap = (shareBp: number, referrerKey: web3.PublicKey = referrer.publicKey) =>
+            program.methods
+                .submitSwap(uuid1, amount, amountBack, lock, memo, { referrer: referrerKey, shareBp })
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    srcToken: null,
+                    source: null,
+                    dstToken: mint2,
+                    escrow: escrow1,
+                    escrowAta: null,
+                    adminSettings: adminSettings,
+                    srcTokenSettings: solSettings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
+                    referrer: referrerKey,
+                    associatedTokenProgram: null,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: null,
+                })
+                .signers([user])
+                .rpc();
 
-        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        let maxReferralShareBp = 3000;
+        tx = await program.methods
+            .setMaxReferralShare(maxReferralShareBp)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set max referral share tx: ${tx}`);
+
+        let shareBp = 2000;
+
+        // shares are claimed later, the referrer has to be a key that can sign for them
+        for (let referrerKey of [web3.PublicKey.default, program.programId]) {
+            try {
+                await submitSwap(shareBp, referrerKey);
+                expect.fail("submitSwap with a referrer that cannot claim should fail");
+            } catch (err: any) {
+                expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidReferrer");
+            }
+        }
+
+        tx = await submitSwap(shareBp);
+        console.log(`submitSwap tx: ${tx}`);
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+
+        let feeVaultSOLBalBefore = new BN(await connection.getBalance(adminSettings));
+        let feeVaultMint2BalBefore = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
 
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .confirmSwap(uuid1)
             .accounts({
-                payer: user.publicKey,
+                payer: lp.publicKey,
                 from: user.publicKey,
+                fromDestination: userAtaTokenMint2Account.address,
                 to: lp.publicKey,
-                srcToken: mint1,
-                source: userAtaTokenMint1Account.address,
-                dstToken: mint2,
+                toSource: lpAtaTokenMint2Account.address,
+                toDestination: null,
                 escrow: escrow1,
-                escrowAta: escrow1AtaTokenAccount,
+                escrowAta: null,
+                srcToken: null,
+                dstToken: mint2,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
-                dstTokenSettings: null,
+                srcFeeVault: null,
+                dstFeeVault: feeMint2Vault.address,
+                referralVault: referralVault,
+                srcReferralVaultAta: null,
+                dstReferralVaultAta: referralVaultAta,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+                dstTokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([lp])
+            .rpc({ commitment: "confirmed" });
+        console.log(`confirmSwap tx: ${tx}`);
+
+        // the referral shares accrue in the referral vault
+        let srcReferralFee = escrow1Data.srcTokenFee.mul(new BN(shareBp)).div(new BN(10000));
+        let dstReferralFee = escrow1Data.dstTokenFee.mul(new BN(shareBp)).div(new BN(10000));
+        let confirmed = (await getCpiEvents(connection, program, tx)).find((e) => e.name === "SwapConfirmed");
+        expect(confirmed).not.to.be.undefined;
+        expect(confirmed!.data.referrer.toBase58()).to.be.eq(referrer.publicKey.toBase58());
+        expect(confirmed!.data.srcReferralFee.toString()).to.be.eq(srcReferralFee.toString());
+        expect(confirmed!.data.dstReferralFee.toString()).to.be.eq(dstReferralFee.toString());
+        let referralVaultRent = new BN(await connection.getMinimumBalanceForRentExemption(8 + 32));
+        expect(await connection.getBalance(referralVault)).to.be.eq(referralVaultRent.add(srcReferralFee).toNumber());
+        expect((await getAccount(connection, referralVaultAta)).amount.toString()).to.be.eq(dstReferralFee.toString());
+
+        let feeVaultSOLBalAfter = new BN(await connection.getBalance(adminSettings));
+        let feeVaultMint2BalAfter = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+        expect(feeVaultSOLBalAfter.sub(feeVaultSOLBalBefore).toString()).to.be.eq(
+            escrow1Data.srcTokenFee.sub(srcReferralFee).toString(),
+        );
+        expect(feeVaultMint2BalAfter.sub(feeVaultMint2BalBefore).toString()).to.be.eq(
+            escrow1Data.dstTokenFee.sub(dstReferralFee).toString(),
+        );
+
+        // the referrer claims both shares to a token account of its choice
+        let referrerMint2Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint2,
+            referrer.publicKey,
+        );
+        let referrerSOLBalBefore = new BN(await connection.getBalance(referrer.publicKey));
+        tx = await program.methods
+            .claimReferralFees()
+            .accounts({
+                referrer: referrer.publicKey,
+                referralVault: referralVault,
+                token: mint2,
+                referralVaultAta: referralVaultAta,
+                destination: referrerMint2Account.address,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
-            .signers([user])
+            .signers([referrer])
             .rpc();
-        console.log(`transfer out tx: ${tx}`);
+        console.log(`claim referral fees tx: ${tx}`);
 
-        console.log(`========== refundSwap ==========`);
+        let referrerSOLBalAfter = new BN(await connection.getBalance(referrer.publicKey));
+        expect(referrerSOLBalAfter.sub(referrerSOLBalBefore).toString()).to.be.eq(srcReferralFee.toString());
+        expect((await getAccount(connection, referrerMint2Account.address)).amount.toString()).to.be.eq(
+            dstReferralFee.toString(),
+        );
+        expect((await getAccount(connection, referralVaultAta)).amount.toString()).to.be.eq("0");
+        expect(await connection.getBalance(referralVault)).to.be.eq(referralVaultRent.toNumber());
+    });
+
+    it("withdraw collected fees", async () => {
+        // fees collected by earlier confirmations
+        await mintTo(connection, payer, mint1, feeMint1Vault.address, payer, 1000);
+        await mintTo(connection, payer, mint2, feeMint2Vault.address, payer, 2000);
+        await transferSOL(connection, payer, adminSettings, 3000);
+
+        let feeRecepientMint1Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint1,
+            feeRecepient.publicKey,
+        );
+        // the approving admins may pick any destination, not only accounts of the fee recepient
+        let treasury = web3.Keypair.generate();
+        let treasuryMint2Account = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            mint2,
+            treasury.publicKey,
+        );
+        let adminSettingsInfo = await connection.getAccountInfo(adminSettings);
+        let rentExempt = await connection.getMinimumBalanceForRentExemption(adminSettingsInfo.data.length);
+        let vaultSOLBalBefore = adminSettingsInfo.lamports - rentExempt;
+        await transferSOL(connection, payer, feeRecepient.publicKey, web3.LAMPORTS_PER_SOL);
+        let feeRecepientSOLBalBefore = await connection.getBalance(feeRecepient.publicKey);
 
+        // each mint is withdrawn with the token program that owns it
         try {
             await program.methods
-                .refundSwap(uuid1)
+                .withdrawFees(0, Buffer.from([0]))
                 .accounts({
-                    from: user.publicKey,
-                    source: userAtaTokenMint1Account.address,
-                    escrow: escrow1,
-                    escrowAta: escrow1AtaTokenAccount,
-                    systemProgram: web3.SystemProgram.programId,
-                    tokenProgram: TOKEN_PROGRAM_ID,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    feeRecepient: feeRecepient.publicKey,
                 })
+                .remainingAccounts([
+                    { pubkey: mint1, isSigner: false, isWritable: false },
+                    { pubkey: feeMint1Vault.address, isSigner: false, isWritable: true },
+                    { pubkey: feeRecepientMint1Account.address, isSigner: false, isWritable: true },
+                    { pubkey: TOKEN_2022_PROGRAM_ID, isSigner: false, isWritable: false },
+                ])
+                .signers([newAdmin])
                 .rpc();
+            expect.fail("withdrawFees with another token program should fail");
         } catch (err: any) {
-            console.log(`cannot refund before the refund window`);
-            console.log(`========== error ==========`);
-            console.log((err as AnchorError).logs);
-            expect((err as AnchorError).logs).not.to.be.empty;
-        }
-
-        let refundTime = lock.agreementReachedTime.add(lock.stepTime.mul(new BN(2))).add(new BN(1));
-        console.log(`wait until the refund window: ${refundTime.toNumber()}`);
-        while (true) {
-            let slot = await connection.getSlot();
-            let currentTime = await connection.getBlockTime(slot);
-            if (!currentTime) {
-                throw new Error("currentTime is null");
-            }
-            console.log(`currentTime: ${currentTime}`);
-            if (currentTime >= refundTime.toNumber()) {
-                break;
-            }
-            await sleep(1000);
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
         }
 
-        const tx2 = await program.methods
-            .refundSwap(uuid1)
+        let tx = await program.methods
+            .withdrawFees(0, Buffer.from([0, 0]))
             .accounts({
-                from: user.publicKey,
-                source: userAtaTokenMint1Account.address,
-                escrow: escrow1,
-                escrowAta: escrow1AtaTokenAccount,
-                systemProgram: web3.SystemProgram.programId,
-                tokenProgram: TOKEN_PROGRAM_ID,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeRecepient: feeRecepient.publicKey,
             })
+            .remainingAccounts([
+                { pubkey: mint1, isSigner: false, isWritable: false },
+                { pubkey: feeMint1Vault.address, isSigner: false, isWritable: true },
+                { pubkey: feeRecepientMint1Account.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+                { pubkey: mint2, isSigner: false, isWritable: false },
+                { pubkey: feeMint2Vault.address, isSigner: false, isWritable: true },
+                { pubkey: treasuryMint2Account.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+            ])
+            .signers([newAdmin])
             .rpc();
-
-        console.log(`refundSwap tx: ${tx2}`);
+        console.log(`withdraw fees tx: ${tx}`);
+
+        expect((await getAccount(connection, feeMint1Vault.address)).amount.toString()).to.be.eq("0");
+        expect((await getAccount(connection, feeMint2Vault.address)).amount.toString()).to.be.eq("0");
+        expect((await getAccount(connection, feeRecepientMint1Account.address)).amount.toString()).to.be.eq("1000");
+        expect((await getAccount(connection, treasuryMint2Account.address)).amount.toString()).to.be.eq("2000");
+        expect(await connection.getBalance(adminSettings)).to.be.eq(rentExempt);
+        expect(await connection.getBalance(feeRecepient.publicKey)).to.be.eq(
+            feeRecepientSOLBalBefore + vaultSOLBalBefore,
+        );
     });
 
-    it("refund SOL <-> SPL B Token", async () => {
+    it("swap Token-2022 token with transfer hook", async () => {
+        let ret = await createTransferHookTokenAndMintToUser(
+            connection,
+            payer,
+            user,
+            amount.toNumber() * 2,
+            hookProgram,
+        );
+        let hookMint = ret.mint;
+        let hookAccounts = ret.hookAccounts;
+        let transferCount = async () => (await hookProgram.account.counter.fetch(ret.counter)).count.toNumber();
+        await registerToken(hookMint);
+        let userAtaHookMintAccount = ret.ataTokenAccount;
+        console.log(`create Token-2022 mint ${hookMint} with transfer hook ${hookProgram.programId}`);
+
+        // lp pays the SOL leg of the swap
+        await transferSOL(connection, payer, lp.publicKey, amountBack.toNumber());
+
+        let lpAtaHookMintAccount = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            hookMint,
+            lp.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        let feeHookMintVault = getAssociatedTokenAddressSync(hookMint, adminSettings, true, TOKEN_2022_PROGRAM_ID);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        // zero public key to indicate native token SOL
+        const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
+
+        let submitSwap = (uuid: number[], escrow: web3.PublicKey, escrowAta: web3.PublicKey, lock: Lock) =>
+            program.methods
+                .submitSwap(uuid, amount, amountBack, lock, memo, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    srcToken: hookMint,
+                    source: userAtaHookMintAccount.address,
+                    dstToken: null,
+                    escrow: escrow,
+                    escrowAta: escrowAta,
+                    adminSettings: adminSettings,
+                    srcTokenSettings: tokenSettingsOf(hookMint),
+                    dstTokenSettings: solSettings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_2022_PROGRAM_ID,
+                })
+                .remainingAccounts(hookAccounts)
+                .signers([user])
+                .rpc();
+
+        console.log(`========== submitSwap and confirmSwap ==========`);
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
         if (!agreementReachedTime) {
             throw new Error("agreementReachedTime is null");
         }
 
-        let stepTime = 1;
-
         let lock: Lock = {
             agreementReachedTime: new BN(agreementReachedTime),
-            stepTime: new BN(stepTime),
+            stepTime: new BN(5),
         };
-        console.log(`lock: ${JSON.stringify(lock)}`);
-
-        // zero public key to indicate native token SOL
-        const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
-        console.log(`zero public key: ${zeroPublicKey.toBase58()}`);
-
-        console.log(`========== submitSwap ==========`);
         let uuid1 = generateUuidSwap(
             user.publicKey,
             lp.publicKey,
-            mint1,
+            hookMint,
             amount,
             zeroPublicKey,
             amountBack,
             lock.agreementReachedTime,
             lock.stepTime,
         );
-        console.log(`generate uuid1: ${uuid1}`);
-
-        // calculate escrow account address offchain without create it
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
-        console.log(`offchain escrow1: ${escrow1}`);
-
-        // calculate escrowAtaTokenAccount account address offchain without create it
-        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
-        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(hookMint, escrow1, true, TOKEN_2022_PROGRAM_ID);
 
-        let memo = Buffer.from([1, 2, 3, 4, 5]);
+        tx = await submitSwap(uuid1, escrow1, escrow1AtaTokenAccount, lock);
+        console.log(`transfer out tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(1);
 
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .confirmSwap(uuid1)
             .accounts({
-                payer: user.publicKey,
+                payer: lp.publicKey,
                 from: user.publicKey,
+                fromDestination: null,
                 to: lp.publicKey,
-                srcToken: null,
-                source: null,
-                dstToken: mint2,
+                toSource: null,
+                toDestination: lpAtaHookMintAccount.address,
                 escrow: escrow1,
-                escrowAta: null,
+                escrowAta: escrow1AtaTokenAccount,
+                srcToken: hookMint,
+                dstToken: null,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
-                dstTokenSettings: null,
-                associatedTokenProgram: null,
+                srcFeeVault: feeHookMintVault,
+                dstFeeVault: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
-                tokenProgram: null,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+                dstTokenProgram: null,
             })
-            .signers([user])
+            .remainingAccounts(hookAccounts)
+            .signers([lp])
             .rpc();
-        console.log(`transfer out tx: ${tx}`);
+        console.log(`confirmSwap tx: ${tx}`);
+
+        // the fee and the payout both went through the hook
+        expect(await transferCount()).to.be.eq(3);
+        let feeVaultHookMintBal = (
+            await getAccount(connection, feeHookMintVault, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        expect(feeVaultHookMintBal > BigInt(0)).to.be.true;
+
+        console.log(`========== submitSwap and refundSwap ==========`);
+        slot = await connection.getSlot();
+        agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
 
-        console.log(`========== refundSwap ==========`);
+        let lock2: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(1),
+        };
+        let uuid2 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            hookMint,
+            amount,
+            zeroPublicKey,
+            amountBack,
+            lock2.agreementReachedTime,
+            lock2.stepTime,
+        );
+        let [escrow2] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid2)], program.programId);
+        let escrow2AtaTokenAccount = getAssociatedTokenAddressSync(hookMint, escrow2, true, TOKEN_2022_PROGRAM_ID);
 
-        let refundTime = lock.agreementReachedTime.add(lock.stepTime.mul(new BN(2))).add(new BN(1));
+        tx = await submitSwap(uuid2, escrow2, escrow2AtaTokenAccount, lock2);
+        console.log(`transfer out tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(4);
+
+        let refundTime = lock2.agreementReachedTime.add(lock2.stepTime.mul(new BN(2))).add(new BN(1));
+        console.log(`wait until the refund window: ${refundTime.toNumber()}`);
+        while (true) {
+            let slot = await connection.getSlot();
+            let currentTime = await connection.getBlockTime(slot);
+            if (!currentTime) {
+                throw new Error("currentTime is null");
+            }
+            if (currentTime >= refundTime.toNumber()) {
+                break;
+            }
+            await sleep(1000);
+        }
+
+        tx = await program.methods
+            .refundSwap(uuid2)
+            .accounts({
+                from: user.publicKey,
+                source: userAtaHookMintAccount.address,
+                escrow: escrow2,
+                escrowAta: escrow2AtaTokenAccount,
+                srcToken: hookMint,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_2022_PROGRAM_ID,
+            })
+            .remainingAccounts(hookAccounts)
+            .rpc();
+        console.log(`refundSwap tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(5);
+
+        console.log(`========== withdraw fees ==========`);
+        let feeRecepientHookMintAccount = await getOrCreateAssociatedTokenAccount(
+            connection,
+            payer,
+            hookMint,
+            feeRecepient.publicKey,
+            false,
+            undefined,
+            undefined,
+            TOKEN_2022_PROGRAM_ID,
+        );
+        tx = await program.methods
+            .withdrawFees(0, Buffer.from([hookAccounts.length]))
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeRecepient: feeRecepient.publicKey,
+            })
+            .remainingAccounts([
+                { pubkey: hookMint, isSigner: false, isWritable: false },
+                { pubkey: feeHookMintVault, isSigner: false, isWritable: true },
+                { pubkey: feeRecepientHookMintAccount.address, isSigner: false, isWritable: true },
+                { pubkey: TOKEN_2022_PROGRAM_ID, isSigner: false, isWritable: false },
+                ...hookAccounts,
+            ])
+            .signers([newAdmin])
+            .rpc();
+        console.log(`withdraw fees tx: ${tx}`);
+        expect(await transferCount()).to.be.eq(6);
+        let feeRecepientHookMintBal = (
+            await getAccount(connection, feeRecepientHookMintAccount.address, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount;
+        expect(feeRecepientHookMintBal.toString()).to.be.eq(feeVaultHookMintBal.toString());
+    });
+
+    it("refund SPL A Token <-> SPL B Token", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 1;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        console.log(`========== submitSwap ==========`);
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        console.log(`generate uuid1: ${uuid1}`);
+
+        // calculate escrow account address offchain without create it
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        console.log(`offchain escrow1: ${escrow1}`);
+
+        // calculate escrowAtaTokenAccount account address offchain without create it
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                srcToken: mint1,
+                source: userAtaTokenMint1Account.address,
+                dstToken: mint2,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                adminSettings: adminSettings,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        console.log(`========== refundSwap ==========`);
+
+        try {
+            await program.methods
+                .refundSwap(uuid1)
+                .accounts({
+                    from: user.publicKey,
+                    source: userAtaTokenMint1Account.address,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    srcToken: mint1,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .rpc();
+        } catch (err: any) {
+            console.log(`cannot refund before the refund window`);
+            console.log(`========== error ==========`);
+            console.log((err as AnchorError).logs);
+            expect((err as AnchorError).logs).not.to.be.empty;
+        }
+
+        let refundTime = lock.agreementReachedTime.add(lock.stepTime.mul(new BN(2))).add(new BN(1));
+        console.log(`wait until the refund window: ${refundTime.toNumber()}`);
+        while (true) {
+            let slot = await connection.getSlot();
+            let currentTime = await connection.getBlockTime(slot);
+            if (!currentTime) {
+                throw new Error("currentTime is null");
+            }
+            console.log(`currentTime: ${currentTime}`);
+            if (currentTime >= refundTime.toNumber()) {
+                break;
+            }
+            await sleep(1000);
+        }
+
+        const tx2 = await program.methods
+            .refundSwap(uuid1)
+            .accounts({
+                from: user.publicKey,
+                source: userAtaTokenMint1Account.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                srcToken: mint1,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .rpc({ commitment: "confirmed" });
+
+        console.log(`refundSwap tx: ${tx2}`);
+
+        // the escrow is closed, the refund outcome is only observable through the event
+        let refunded = (await getCpiEvents(connection, program, tx2)).find((e) => e.name === "SwapRefunded");
+        expect(refunded).not.to.be.undefined;
+        expect(Array.from(refunded!.data.uuid as number[])).to.deep.eq(uuid1);
+        expect(refunded!.data.srcToken.toBase58()).to.be.eq(mint1.toBase58());
+        expect(refunded!.data.srcAmount.toString()).to.be.eq(amount.toString());
+
+    it("refund SOL <-> SPL B Token", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 1;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        // zero public key to indicate native token SOL
+        const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
+        console.log(`zero public key: ${zeroPublicKey.toBase58()}`);
+
+        console.log(`========== submitSwap ==========`);
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            zeroPublicKey,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        console.log(`generate uuid1: ${uuid1}`);
+
+        // calculate escrow account address offchain without create it
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        console.log(`offchain escrow1: ${escrow1}`);
+
+        // calculate escrowAtaTokenAccount account address offchain without create it
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                srcToken: null,
+                source: null,
+                dstToken: mint2,
+                escrow: escrow1,
+                escrowAta: null,
+                adminSettings: adminSettings,
+                srcTokenSettings: solSettings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
+                associatedTokenProgram: null,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: null,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        console.log(`========== refundSwap ==========`);
+
+        let refundTime = lock.agreementReachedTime.add(lock.stepTime.mul(new BN(2))).add(new BN(1));
         console.log(`wait until the refund window: ${refundTime.toNumber()}`);
         while (true) {
             let slot = await connection.getSlot();
@@ -896,50 +1419,909 @@ describe("OBridge Swap", () => {
                 source: null,
                 escrow: escrow1,
                 escrowAta: null,
+                srcToken: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
             })
             .rpc();
 
-        console.log(`refundSwap tx: ${tx2}`);
+        console.log(`refundSwap tx: ${tx2}`);
+    });
+
+    it("set token max fee", async () => {
+        let feeVaultMint1Before = new BN((await getAccount(connection, feeMint1Vault.address)).amount.toString());
+
+        let feeVaultMint2Before = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+
+        let maxFeeForMint1 = new BN(50);
+        let tx = await program.methods
+            .setMaxFeeForToken(mint1, maxFeeForMint1)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: mint1Settings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`set src token max fee tx: ${tx}`);
+
+        let maxFeeForMint2 = new BN(30);
+        tx = await program.methods
+            .setMaxFeeForToken(mint2, maxFeeForMint2)
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: mint2Settings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`set dst token max fee tx: ${tx}`);
+
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 5;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+        console.log(`lock: ${JSON.stringify(lock)}`);
+
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        console.log(`generate uuid1: ${uuid1}`);
+
+        // calculate escrow account address offchain without create it
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        console.log(`offchain escrow1: ${escrow1}`);
+
+        // calculate escrowAtaTokenAccount account address offchain without create it
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
+
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        // transfer out
+        tx = await program.methods
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                srcToken: mint1,
+                source: userAtaTokenMint1Account.address,
+                dstToken: mint2,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                adminSettings: adminSettings,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+
+        console.log(`transfer out tx: ${tx}`);
+
+        const tx2 = await program.methods
+            .confirmSwap(uuid1)
+            .accounts({
+                payer: lp.publicKey,
+                from: user.publicKey,
+                fromDestination: userAtaTokenMint2Account.address,
+                to: lp.publicKey,
+                toSource: lpAtaTokenMint2Account.address,
+                toDestination: lpAtaTokenMint1Account.address,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                srcToken: mint1,
+                dstToken: mint2,
+                adminSettings: adminSettings,
+                srcFeeVault: feeMint1Vault.address,
+                dstFeeVault: feeMint2Vault.address,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                dstTokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([lp])
+            .rpc();
+
+        console.log(`confirmSwap tx: ${tx2}`);
+
+        let feeVaultMint1After = new BN((await getAccount(connection, feeMint1Vault.address)).amount.toString());
+
+        expect(feeVaultMint1After.sub(feeVaultMint1Before).toString()).to.be.eq(maxFeeForMint1.toString());
+
+        let feeVaultMint2After = new BN((await getAccount(connection, feeMint2Vault.address)).amount.toString());
+
+        expect(feeVaultMint2After.sub(feeVaultMint2Before).toString()).to.be.eq(maxFeeForMint2.toString());
+    });
+
+    it("set token fee rate and min fee", async () => {
+        let setFeeRateForToken = (mint: web3.PublicKey, feeRateBp: number | null, minFee: BN) =>
+            program.methods
+                .setFeeRateForToken(mint, feeRateBp, minFee)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(mint),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+
+        try {
+            await setFeeRateForToken(mint1, 10000, new BN(0));
+            expect.fail("fee rate of 100% should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidFeeRate");
+        }
+
+        // mint1 overrides the global rate, mint2 only charges the minimum fee
+        let feeRateBpForMint1 = 50;
+        tx = await setFeeRateForToken(mint1, feeRateBpForMint1, new BN(0));
+        console.log(`set src token fee rate tx: ${tx}`);
+        let minFeeForMint2 = new BN(1000);
+        tx = await setFeeRateForToken(mint2, 0, minFeeForMint2);
+        console.log(`set dst token min fee tx: ${tx}`);
+
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 5;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        tx = await program.methods
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+            .accounts({
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                srcToken: mint1,
+                source: userAtaTokenMint1Account.address,
+                dstToken: mint2,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
+                adminSettings: adminSettings,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([user])
+            .rpc();
+        console.log(`transfer out tx: ${tx}`);
+
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Data.srcTokenFee.toString()).to.be.eq(
+            amount.mul(new BN(feeRateBpForMint1)).div(new BN(10000)).toString(),
+        );
+        expect(escrow1Data.dstTokenFee.toString()).to.be.eq(minFeeForMint2.toString());
+    });
+
+    it("require admin signer threshold", async () => {
+        let coSigner1 = web3.Keypair.generate();
+        let coSigner2 = web3.Keypair.generate();
+        let setFeeRate = (feeRateBp: number, coSigners: web3.Keypair[]) =>
+            program.methods
+                .setFeeRate(feeRateBp)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .remainingAccounts(
+                    coSigners.map((coSigner) => ({ pubkey: coSigner.publicKey, isSigner: true, isWritable: false })),
+                )
+                .signers([newAdmin, ...coSigners])
+                .rpc();
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+
+        // threshold above the number of signers is rejected
+        try {
+            await program.methods
+                .setAdminSigners([newAdmin.publicKey, coSigner1.publicKey], 3)
+                .accounts({
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("threshold above the signer count should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidAdminSigners");
+        }
+
+        tx = await program.methods
+            .setAdminSigners([newAdmin.publicKey, coSigner1.publicKey, coSigner2.publicKey], 2)
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set admin signers tx: ${tx}`);
+
+        try {
+            await setFeeRate(feeRateBp + 1, []);
+            expect.fail("setFeeRate by a single admin key should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+
+        // signers outside the set do not count
+        try {
+            await setFeeRate(feeRateBp + 1, [user]);
+            expect.fail("setFeeRate with an unknown co-signer should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+
+        tx = await setFeeRate(feeRateBp + 1, [coSigner2]);
+        console.log(`set fee rate with co-signer tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp + 1);
+
+        // the admin pauses alone, lifting the pause needs the co-signers
+        let setPause = (paused: boolean, coSigners: web3.Keypair[]) =>
+            program.methods
+                .setPause(paused)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .remainingAccounts(
+                    coSigners.map((coSigner) => ({ pubkey: coSigner.publicKey, isSigner: true, isWritable: false })),
+                )
+                .signers([newAdmin, ...coSigners])
+                .rpc();
+        tx = await setPause(true, []);
+        console.log(`pause by a single admin key tx: ${tx}`);
+        try {
+            await setPause(false, []);
+            expect.fail("unpausing by a single admin key should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("NotEnoughAdminSigners");
+        }
+        tx = await setPause(false, [coSigner2]);
+        console.log(`unpause with co-signer tx: ${tx}`);
+
+        await setFeeRate(feeRateBp, [coSigner1]);
+        tx = await program.methods
+            .setAdminSigners([], 0)
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .remainingAccounts([{ pubkey: coSigner1.publicKey, isSigner: true, isWritable: false }])
+            .signers([newAdmin, coSigner1])
+            .rpc();
+        console.log(`reset admin signers tx: ${tx}`);
+    });
+
+    it("separate admin roles", async () => {
+        let pauser = web3.Keypair.generate();
+        let feeManager = web3.Keypair.generate();
+        let setRole = (role: object, key: web3.PublicKey) =>
+            program.methods
+                .setRole(role, key)
+                .accounts({
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+        let setPause = (authority: web3.Keypair, paused: boolean) =>
+            program.methods
+                .setPause(paused)
+                .accounts({
+                    authority: authority.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([authority])
+                .rpc();
+        let setFeeRate = (authority: web3.Keypair, feeRateBp: number) =>
+            program.methods
+                .setFeeRate(feeRateBp)
+                .accounts({
+                    authority: authority.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([authority])
+                .rpc();
+
+        // only the super-admin assigns roles
+        try {
+            await program.methods
+                .setRole({ pauser: {} }, pauser.publicKey)
+                .accounts({
+                    admin: pauser.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([pauser])
+                .rpc();
+            expect.fail("setRole by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        tx = await setRole({ pauser: {} }, pauser.publicKey);
+        console.log(`set pauser tx: ${tx}`);
+        tx = await setRole({ feeManager: {} }, feeManager.publicKey);
+        console.log(`set fee manager tx: ${tx}`);
+
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+
+        // the pauser can pause but cannot touch fees
+        await setPause(pauser, true);
+        await setPause(pauser, false);
+        try {
+            await setFeeRate(pauser, feeRateBp);
+            expect.fail("setFeeRate by the pauser should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        // the fee manager can set fees but cannot pause
+        await setFeeRate(feeManager, feeRateBp);
+        try {
+            await setPause(feeManager, true);
+            expect.fail("setPause by the fee manager should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        // the super-admin keeps every role
+        await setPause(newAdmin, false);
+        await setFeeRate(newAdmin, feeRateBp);
+
+        await setRole({ pauser: {} }, web3.PublicKey.default);
+        await setRole({ feeManager: {} }, web3.PublicKey.default);
+    });
+
+    it("cap fee rates at the protocol cap", async () => {
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateCapBp).to.be.eq(feeRateCapBp);
+
+        try {
+            await program.methods
+                .setFeeRate(feeRateCapBp + 1)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("fee rate above the protocol cap should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("FeeRateAboveCap");
+        }
+
+        try {
+            await program.methods
+                .setFeeRateForToken(mint1, feeRateCapBp + 1, new BN(0))
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(mint1),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            expect.fail("fee rate above the protocol cap should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("FeeRateAboveCap");
+        }
+    });
+
+    it("queue timelocked fee changes", async () => {
+        const pendingChangeFor = (id: number) =>
+            web3.PublicKey.findProgramAddressSync(
+                [Buffer.from("pending"), new BN(id).toArrayLike(Buffer, "le", 8)],
+                program.programId,
+            )[0];
+        const blockTime = async () => {
+            let time = await connection.getBlockTime(await connection.getSlot());
+            if (!time) {
+                throw new Error("block time is null");
+            }
+            return time;
+        };
+        const feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        const delay = 2;
+
+        tx = await program.methods
+            .setTimelockDelay(new BN(delay))
+            .accounts({
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set timelock delay tx: ${tx}`);
+
+        try {
+            await program.methods
+                .setFeeRate(9999)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("setFeeRate should be queued while the timelock is set");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockRequired");
+        }
+
+        try {
+            await program.methods
+                .setFeeRecepient()
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    feeRecepient: user.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+            expect.fail("setFeeRecepient should be queued while the timelock is set");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockRequired");
+        }
+
+        try {
+            await program.methods
+                .queueAdminChange(new BN(1), { feeRate: { feeRateBp: feeRateBp + 10 } }, new BN(await blockTime()))
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    pendingChange: pendingChangeFor(1),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            expect.fail("queueAdminChange before the delay should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidEffectiveTime");
+        }
+
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(1),
+                { feeRate: { feeRateBp: feeRateBp + 10 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(1),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`queue fee rate tx: ${tx}`);
+
+        try {
+            await program.methods
+                .executeAdminChange(new BN(1))
+                .accounts({
+                    payer: payer.publicKey,
+                    adminSettings: adminSettings,
+                    pendingChange: pendingChangeFor(1),
+                    tokenSettings: null,
+                })
+                .rpc();
+            expect.fail("executeAdminChange before the effective time should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TimelockNotElapsed");
+        }
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp);
+
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(2),
+                { feeRate: { feeRateBp: feeRateBp + 20 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(2),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        tx = await program.methods
+            .cancelAdminChange(new BN(2))
+            .accounts({
+                authority: newAdmin.publicKey,
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(2),
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`cancel fee rate tx: ${tx}`);
+        expect(await connection.getAccountInfo(pendingChangeFor(2))).to.be.null;
+
+        // queueing a fee discount opens the discount account empty, execute sets it
+        let discountUser = web3.Keypair.generate().publicKey;
+        let [feeDiscount] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("discount"), discountUser.toBytes()],
+            program.programId,
+        );
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(4),
+                { feeDiscount: { user: discountUser, discountBp: 500 } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(4),
+                feeDiscount: feeDiscount,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`queue fee discount tx: ${tx}`);
+        expect((await program.account.feeDiscount.fetch(feeDiscount)).discountBp).to.be.eq(0);
+
+        await sleep((delay + 3) * 1000);
+        tx = await program.methods
+            .executeAdminChange(new BN(1))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(1),
+                tokenSettings: null,
+            })
+            .rpc();
+        console.log(`execute fee rate tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRateBp).to.be.eq(feeRateBp + 10);
+        expect(await connection.getAccountInfo(pendingChangeFor(1))).to.be.null;
+
+        tx = await program.methods
+            .executeAdminChange(new BN(4))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(4),
+                tokenSettings: null,
+                feeDiscount: feeDiscount,
+            })
+            .rpc();
+        console.log(`execute fee discount tx: ${tx}`);
+        expect((await program.account.feeDiscount.fetch(feeDiscount)).discountBp).to.be.eq(500);
+
+        // lift the timelock and restore the fee rate
+        tx = await program.methods
+            .queueAdminChange(
+                new BN(3),
+                { timelockDelay: { delay: new BN(0) } },
+                new BN((await blockTime()) + delay + 2),
+            )
+            .accounts({
+                payer: payer.publicKey,
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(3),
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        await sleep((delay + 3) * 1000);
+        tx = await program.methods
+            .executeAdminChange(new BN(3))
+            .accounts({
+                payer: payer.publicKey,
+                adminSettings: adminSettings,
+                pendingChange: pendingChangeFor(3),
+                tokenSettings: null,
+            })
+            .rpc();
+        expect((await program.account.adminSettings.fetch(adminSettings)).timelockDelay.toNumber()).to.be.eq(0);
+
+        tx = await program.methods
+            .setFeeRate(feeRateBp)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+
+        tx = await program.methods
+            .removeFeeDiscount(discountUser)
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                feeDiscount: feeDiscount,
+            })
+            .signers([newAdmin])
+            .rpc();
+    });
+
+    it("keep current accounts on the versioned layout", async () => {
+        expect((await program.account.adminSettings.fetch(adminSettings)).version).to.be.eq(1);
+
+        try {
+            await program.methods
+                .migrateAdminSettings(feeRateCapBp)
+                .accounts({
+                    payer: payer.publicKey,
+                    admin: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+            expect.fail("migrating an account on the current layout should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AlreadyMigrated");
+        }
+    });
+
+    it("migrate accounts written by the unversioned release", async () => {
+        // loaded from tests/fixtures, see Anchor.toml
+        let legacyToken = new web3.PublicKey(Buffer.alloc(32, 9));
+        let legacyTokenSettings = tokenSettingsOf(legacyToken);
+        let legacyUuid = Array.from(Buffer.alloc(32, 0x42));
+        let [legacyEscrow] = web3.PublicKey.findProgramAddressSync([Buffer.from(legacyUuid)], program.programId);
+        expect((await connection.getAccountInfo(legacyTokenSettings)).data.length).to.be.eq(16);
+        expect((await connection.getAccountInfo(legacyEscrow)).data.length).to.be.eq(184);
+
+        // escrows need no admin to migrate, any payer can
+        let migrateEscrow = () =>
+            program.methods
+                .migrateEscrow(legacyUuid)
+                .accounts({
+                    payer: user.publicKey,
+                    escrow: legacyEscrow,
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([user])
+                .rpc();
+
+        tx = await program.methods
+            .migrateTokenSettings(legacyToken)
+            .accounts({
+                payer: payer.publicKey,
+                admin: newAdmin.publicKey,
+                adminSettings: adminSettings,
+                tokenSettings: legacyTokenSettings,
+                systemProgram: web3.SystemProgram.programId,
+            })
+            .signers([payer, newAdmin])
+            .rpc();
+        console.log(`migrate token settings tx: ${tx}`);
+
+        let tokenSettings = await program.account.tokenSettings.fetch(legacyTokenSettings);
+        expect(tokenSettings.version).to.be.eq(1);
+        expect(tokenSettings.maxFee.toNumber()).to.be.eq(777);
+        expect(tokenSettings.feeRateBp).to.be.null;
+        expect(tokenSettings.enabled).to.be.true;
+
+        tx = await migrateEscrow();
+        console.log(`migrate escrow tx: ${tx}`);
+
+        // the unversioned release only moved classic SPL tokens
+        let escrow = await program.account.escrow.fetch(legacyEscrow);
+        expect(escrow.version).to.be.eq(1);
+        expect(escrow.from.toBuffer()).to.deep.eq(Buffer.alloc(32, 1));
+        expect(escrow.srcToken.toBase58()).to.be.eq(legacyToken.toBase58());
+        expect(escrow.dstAmount.toNumber()).to.be.eq(1000000);
+        expect(escrow.lock.stepTime.toNumber()).to.be.eq(5);
+        expect(escrow.srcTokenProgram.toBase58()).to.be.eq(TOKEN_PROGRAM_ID.toBase58());
+        expect(escrow.dstTokenProgram.toBase58()).to.be.eq(web3.PublicKey.default.toBase58());
+        expect(escrow.referrer.toBase58()).to.be.eq(web3.PublicKey.default.toBase58());
+
+        // the migrated account now carries its version
+        try {
+            await migrateEscrow();
+            expect.fail("migrating an escrow twice should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AlreadyMigrated");
+        }
+    });
+
+    it("set fee rounding", async () => {
+        try {
+            await program.methods
+                .setFeeRounding({ ceil: {} })
+                .accounts({
+                    authority: user.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([user])
+                .rpc();
+            expect.fail("setFeeRounding by non admin should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AccountMismatch");
+        }
+
+        tx = await program.methods
+            .setFeeRounding({ ceil: {} })
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        console.log(`set fee rounding tx: ${tx}`);
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRounding).to.be.deep.eq({ ceil: {} });
+
+        tx = await program.methods
+            .setFeeRounding({ floor: {} })
+            .accounts({
+                authority: newAdmin.publicKey,
+                adminSettings: adminSettings,
+            })
+            .signers([newAdmin])
+            .rpc();
+        expect((await program.account.adminSettings.fetch(adminSettings)).feeRounding).to.be.deep.eq({ floor: {} });
     });
 
-    it("set token max fee", async () => {
-        let feeRecepientMint1Before = new BN(
-            (await getAccount(connection, feeMint1Destination.address)).amount.toString(),
-        );
+    it("set token fee tiers", async () => {
+        let setFeeTiersForToken = (mint: web3.PublicKey, feeTiers: { minAmount: BN; feeRateBp: number }[]) =>
+            program.methods
+                .setFeeTiersForToken(mint, feeTiers)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(mint),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+
+        try {
+            await setFeeTiersForToken(mint1, [
+                { minAmount: new BN(10), feeRateBp: 30 },
+                { minAmount: new BN(10), feeRateBp: 10 },
+            ]);
+            expect.fail("fee tiers without ascending min amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidFeeTiers");
+        }
+
+        // src amount reaches the second mint1 tier, dst amount is below every mint2 tier
+        let feeRateBpForMint1 = 10;
+        tx = await setFeeTiersForToken(mint1, [
+            { minAmount: new BN(0), feeRateBp: 30 },
+            { minAmount: amount, feeRateBp: feeRateBpForMint1 },
+        ]);
+        console.log(`set src token fee tiers tx: ${tx}`);
+        tx = await setFeeTiersForToken(mint2, [{ minAmount: amountBack.addn(1), feeRateBp: 5 }]);
+        console.log(`set dst token fee tiers tx: ${tx}`);
+
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 5;
 
-        let feeRecepientMint2Before = new BN(
-            (await getAccount(connection, feeMint2Destination.address)).amount.toString(),
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
         );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
 
-        let maxFeeForMint1 = new BN(50);
-        let tx = await program.methods
-            .setMaxFeeForToken(mint1, maxFeeForMint1)
+        tx = await program.methods
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
-                payer: payer.publicKey,
-                admin: newAdmin.publicKey,
+                payer: user.publicKey,
+                from: user.publicKey,
+                to: lp.publicKey,
+                srcToken: mint1,
+                source: userAtaTokenMint1Account.address,
+                dstToken: mint2,
+                escrow: escrow1,
+                escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                tokenSettings: mint1Settings,
+                srcTokenSettings: mint1Settings,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
+                tokenProgram: TOKEN_PROGRAM_ID,
             })
-            .signers([payer, newAdmin])
+            .signers([user])
             .rpc();
-        console.log(`set src token max fee tx: ${tx}`);
+        console.log(`transfer out tx: ${tx}`);
 
-        let maxFeeForMint2 = new BN(30);
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Data.srcTokenFee.toString()).to.be.eq(
+            amount.mul(new BN(feeRateBpForMint1)).div(new BN(10000)).toString(),
+        );
+        expect(escrow1Data.dstTokenFee.toString()).to.be.eq(
+            amountBack.mul(new BN(feeRateBp)).div(new BN(10000)).toString(),
+        );
+    });
+
+    it("apply user fee discount", async () => {
+        let [feeDiscount] = web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("discount"), user.publicKey.toBytes()],
+            program.programId,
+        );
+        let discountBp = 5000;
         tx = await program.methods
-            .setMaxFeeForToken(mint2, maxFeeForMint2)
+            .setFeeDiscount(user.publicKey, discountBp)
             .accounts({
                 payer: payer.publicKey,
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
-                tokenSettings: mint2Settings,
+                feeDiscount: feeDiscount,
                 systemProgram: web3.SystemProgram.programId,
             })
             .signers([payer, newAdmin])
             .rpc();
-        console.log(`set dst token max fee tx: ${tx}`);
+        console.log(`set fee discount tx: ${tx}`);
 
         let slot = await connection.getSlot();
         let agreementReachedTime = await connection.getBlockTime(slot);
@@ -953,7 +2335,6 @@ describe("OBridge Swap", () => {
             agreementReachedTime: new BN(agreementReachedTime),
             stepTime: new BN(stepTime),
         };
-        console.log(`lock: ${JSON.stringify(lock)}`);
 
         let uuid1 = generateUuidSwap(
             user.publicKey,
@@ -965,21 +2346,12 @@ describe("OBridge Swap", () => {
             lock.agreementReachedTime,
             lock.stepTime,
         );
-        console.log(`generate uuid1: ${uuid1}`);
-
-        // calculate escrow account address offchain without create it
         let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
-        console.log(`offchain escrow1: ${escrow1}`);
-
-        // calculate escrowAtaTokenAccount account address offchain without create it
         let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
-        console.log(`offchain escrow1 ata ${escrow1AtaTokenAccount}`);
-
         let memo = Buffer.from([1, 2, 3, 4, 5]);
 
-        // transfer out
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -992,53 +2364,41 @@ describe("OBridge Swap", () => {
                 adminSettings: adminSettings,
                 srcTokenSettings: mint1Settings,
                 dstTokenSettings: mint2Settings,
+                feeDiscount: feeDiscount,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
             })
             .signers([user])
             .rpc();
-
         console.log(`transfer out tx: ${tx}`);
 
-        const tx2 = await program.methods
-            .confirmSwap(uuid1)
+        let feeRateBp = (await program.account.adminSettings.fetch(adminSettings)).feeRateBp;
+        let srcFee = amount.mul(new BN(feeRateBp)).div(new BN(10000));
+        let dstFee = amountBack.mul(new BN(feeRateBp)).div(new BN(10000));
+        let escrow1Data = await program.account.escrow.fetch(escrow1);
+        expect(escrow1Data.srcTokenFee.toString()).to.be.eq(
+            srcFee.sub(srcFee.mul(new BN(discountBp)).div(new BN(10000))).toString(),
+        );
+        expect(escrow1Data.dstTokenFee.toString()).to.be.eq(
+            dstFee.sub(dstFee.mul(new BN(discountBp)).div(new BN(10000))).toString(),
+        );
+
+        tx = await program.methods
+            .removeFeeDiscount(user.publicKey)
             .accounts({
-                payer: lp.publicKey,
-                from: user.publicKey,
-                fromDestination: userAtaTokenMint2Account.address,
-                to: lp.publicKey,
-                toSource: lpAtaTokenMint2Account.address,
-                toDestination: lpAtaTokenMint1Account.address,
-                escrow: escrow1,
-                escrowAta: escrow1AtaTokenAccount,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: feeMint1Destination.address,
-                dstFeeDestination: feeMint2Destination.address,
-                systemProgram: web3.SystemProgram.programId,
-                tokenProgram: TOKEN_PROGRAM_ID,
+                feeDiscount: feeDiscount,
             })
-            .signers([lp])
+            .signers([newAdmin])
             .rpc();
-
-        console.log(`confirmSwap tx: ${tx2}`);
-
-        let feeRecepientMint1After = new BN(
-            (await getAccount(connection, feeMint1Destination.address)).amount.toString(),
-        );
-
-        expect(feeRecepientMint1After.sub(feeRecepientMint1Before).toString()).to.be.eq(maxFeeForMint1.toString());
-
-        let feeRecepientMint2After = new BN(
-            (await getAccount(connection, feeMint2Destination.address)).amount.toString(),
-        );
-
-        expect(feeRecepientMint2After.sub(feeRecepientMint2Before).toString()).to.be.eq(maxFeeForMint2.toString());
+        console.log(`remove fee discount tx: ${tx}`);
+        expect(await connection.getAccountInfo(feeDiscount)).to.be.null;
     });
 
     it("set src token SOL max fee", async () => {
-        let feeRecepientSOLBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultSOLBefore = new BN(await connection.getBalance(adminSettings));
 
         const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
         console.log(`zero public key: ${zeroPublicKey.toBase58()}`);
@@ -1053,7 +2413,7 @@ describe("OBridge Swap", () => {
             .setMaxFeeForToken(zeroPublicKey, maxFeeForMint1)
             .accounts({
                 payer: payer.publicKey,
-                admin: newAdmin.publicKey,
+                authority: newAdmin.publicKey,
                 adminSettings: adminSettings,
                 tokenSettings: solSettings,
                 systemProgram: web3.SystemProgram.programId,
@@ -1096,7 +2456,7 @@ describe("OBridge Swap", () => {
 
         // transfer out
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -1108,7 +2468,8 @@ describe("OBridge Swap", () => {
                 escrowAta: null,
                 adminSettings: adminSettings,
                 srcTokenSettings: solSettings,
-                dstTokenSettings: null,
+                dstTokenSettings: mint2Settings,
+                feeDiscount: null,
                 associatedTokenProgram: null,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: null,
@@ -1129,25 +2490,28 @@ describe("OBridge Swap", () => {
                 toDestination: null,
                 escrow: escrow1,
                 escrowAta: null,
+                srcToken: null,
+                dstToken: mint2,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: null,
-                dstFeeDestination: feeMint2Destination.address,
+                srcFeeVault: null,
+                dstFeeVault: feeMint2Vault.address,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
-                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenProgram: null,
+                dstTokenProgram: TOKEN_PROGRAM_ID,
             })
             .signers([lp])
             .rpc();
 
         console.log(`confirmSwap tx: ${tx2}`);
 
-        let feeRecepientSOLAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultSOLAfter = new BN(await connection.getBalance(adminSettings));
 
-        expect(feeRecepientSOLAfter.sub(feeRecepientSOLBefore).toString()).to.be.eq(maxFeeForMint1.toString());
+        expect(feeVaultSOLAfter.sub(feeVaultSOLBefore).toString()).to.be.eq(maxFeeForMint1.toString());
     });
 
     it("set dst token SOL max fee", async () => {
-        let feeRecepientSOLBefore = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultSOLBefore = new BN(await connection.getBalance(adminSettings));
 
         const zeroPublicKey = new web3.PublicKey(new Uint8Array(32).fill(0));
         console.log(`zero public key: ${zeroPublicKey.toBase58()}`);
@@ -1196,7 +2560,7 @@ describe("OBridge Swap", () => {
 
         // transfer out
         tx = await program.methods
-            .submitSwap(uuid1, amount, amountBack, lock, memo)
+            .submitSwap(uuid1, amount, amountBack, lock, memo, null)
             .accounts({
                 payer: user.publicKey,
                 from: user.publicKey,
@@ -1207,8 +2571,9 @@ describe("OBridge Swap", () => {
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
                 adminSettings: adminSettings,
-                srcTokenSettings: null,
+                srcTokenSettings: mint1Settings,
                 dstTokenSettings: solSettings,
+                feeDiscount: null,
                 associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
@@ -1229,21 +2594,24 @@ describe("OBridge Swap", () => {
                 toDestination: lpAtaTokenMint1Account.address,
                 escrow: escrow1,
                 escrowAta: escrow1AtaTokenAccount,
+                srcToken: mint1,
+                dstToken: null,
                 adminSettings: adminSettings,
-                feeRecepient: feeRecepient.publicKey,
-                srcFeeDestination: feeMint1Destination.address,
-                dstFeeDestination: null,
+                srcFeeVault: feeMint1Vault.address,
+                dstFeeVault: null,
+                associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                 systemProgram: web3.SystemProgram.programId,
                 tokenProgram: TOKEN_PROGRAM_ID,
+                dstTokenProgram: null,
             })
             .signers([lp])
             .rpc();
 
         console.log(`confirmSwap tx: ${tx2}`);
 
-        let feeRecepientSOLAfter = new BN(await connection.getBalance(feeRecepient.publicKey));
+        let feeVaultSOLAfter = new BN(await connection.getBalance(adminSettings));
 
-        expect(feeRecepientSOLAfter.sub(feeRecepientSOLBefore).toString()).to.be.eq(maxFeeForMint2.toString());
+        expect(feeVaultSOLAfter.sub(feeVaultSOLBefore).toString()).to.be.eq(maxFeeForMint2.toString());
     });
 
     it("cannot call submitSwap after deadline", async () => {
@@ -1288,7 +2656,7 @@ describe("OBridge Swap", () => {
 
         try {
             tx = await program.methods
-                .submitSwap(uuid1, amount, amountBack, lock, memo)
+                .submitSwap(uuid1, amount, amountBack, lock, memo, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -1299,8 +2667,9 @@ describe("OBridge Swap", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    srcTokenSettings: null,
-                    dstTokenSettings: null,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -1355,7 +2724,7 @@ describe("OBridge Swap", () => {
         try {
             await program.methods;
             tx = await program.methods
-                .submitSwap(uuid1, new BN(0), new BN(0), lock, memo)
+                .submitSwap(uuid1, new BN(0), new BN(0), lock, memo, null)
                 .accounts({
                     payer: user.publicKey,
                     from: user.publicKey,
@@ -1366,8 +2735,9 @@ describe("OBridge Swap", () => {
                     escrow: escrow1,
                     escrowAta: escrow1AtaTokenAccount,
                     adminSettings: adminSettings,
-                    srcTokenSettings: null,
-                    dstTokenSettings: null,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
                     associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
                     systemProgram: web3.SystemProgram.programId,
                     tokenProgram: TOKEN_PROGRAM_ID,
@@ -1381,4 +2751,182 @@ describe("OBridge Swap", () => {
             expect((err as AnchorError).logs).not.to.be.empty;
         }
     });
+
+    it("cannot call submitSwap while paused or with unregistered token", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 5;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let submitSwap = () =>
+            program.methods
+                .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    srcToken: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    dstToken: mint2,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+        let setPause = (paused: boolean) =>
+            program.methods
+                .setPause(paused)
+                .accounts({
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                })
+                .signers([newAdmin])
+                .rpc();
+
+        await setPause(true);
+        try {
+            await submitSwap();
+            expect.fail("submitSwap while paused should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("Paused");
+        } finally {
+            await setPause(false);
+        }
+
+        // deregistering the dst token is enough to block the pair
+        await deregisterToken(mint2);
+        try {
+            await submitSwap();
+            expect.fail("submitSwap with deregistered token should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("TokenNotEnabled");
+        }
+
+        await registerToken(mint2);
+        tx = await submitSwap();
+        console.log(`transfer out after registering token again tx: ${tx}`);
+    });
+
+    it("cannot call submitSwap outside token amount limits", async () => {
+        let slot = await connection.getSlot();
+        let agreementReachedTime = await connection.getBlockTime(slot);
+        if (!agreementReachedTime) {
+            throw new Error("agreementReachedTime is null");
+        }
+
+        let stepTime = 5;
+
+        let lock: Lock = {
+            agreementReachedTime: new BN(agreementReachedTime),
+            stepTime: new BN(stepTime),
+        };
+
+        let uuid1 = generateUuidSwap(
+            user.publicKey,
+            lp.publicKey,
+            mint1,
+            amount,
+            mint2,
+            amountBack,
+            lock.agreementReachedTime,
+            lock.stepTime,
+        );
+        let [escrow1] = web3.PublicKey.findProgramAddressSync([Buffer.from(uuid1)], program.programId);
+        let escrow1AtaTokenAccount = getAssociatedTokenAddressSync(mint1, escrow1, true);
+        let memo = Buffer.from([1, 2, 3, 4, 5]);
+
+        let submitSwap = () =>
+            program.methods
+                .submitSwap(uuid1, amount, amountBack, lock, memo, null)
+                .accounts({
+                    payer: user.publicKey,
+                    from: user.publicKey,
+                    to: lp.publicKey,
+                    srcToken: mint1,
+                    source: userAtaTokenMint1Account.address,
+                    dstToken: mint2,
+                    escrow: escrow1,
+                    escrowAta: escrow1AtaTokenAccount,
+                    adminSettings: adminSettings,
+                    srcTokenSettings: mint1Settings,
+                    dstTokenSettings: mint2Settings,
+                    feeDiscount: null,
+                    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+                    systemProgram: web3.SystemProgram.programId,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([user])
+                .rpc();
+        let setAmountLimits = (mint: web3.PublicKey, minAmount: BN, maxAmount: BN) =>
+            program.methods
+                .setAmountLimitsForToken(mint, minAmount, maxAmount)
+                .accounts({
+                    payer: payer.publicKey,
+                    authority: newAdmin.publicKey,
+                    adminSettings: adminSettings,
+                    tokenSettings: tokenSettingsOf(mint),
+                    systemProgram: web3.SystemProgram.programId,
+                })
+                .signers([payer, newAdmin])
+                .rpc();
+
+        try {
+            await setAmountLimits(mint1, new BN(2), new BN(1));
+            expect.fail("min amount above max amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("InvalidAmountLimits");
+        }
+
+        // src amount below the src token minimum
+        await setAmountLimits(mint1, amount.addn(1), new BN(0));
+        try {
+            await submitSwap();
+            expect.fail("submitSwap below min amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AmountOutOfRange");
+        }
+        await setAmountLimits(mint1, new BN(0), new BN(0));
+
+        // dst amount above the dst token maximum
+        await setAmountLimits(mint2, new BN(0), amountBack.subn(1));
+        try {
+            await submitSwap();
+            expect.fail("submitSwap above max amount should fail");
+        } catch (err: any) {
+            expect((err as AnchorError).error.errorCode.code).to.be.eq("AmountOutOfRange");
+        }
+
+        await setAmountLimits(mint2, amountBack, amountBack);
+        tx = await submitSwap();
+        console.log(`transfer out within amount limits tx: ${tx}`);
+    });
 });